@@ -0,0 +1,275 @@
+// Wrappers around the R2 bucket and KV namespace bindings.
+
+import { BudgetExhausted, DavError } from './error';
+import { logger } from './log';
+
+// A view of an R2 object with the prefix removed from its key. Methods of the runtime's objects
+// only work when called on the original, so they are bound to it.
+function strip_key<T extends { key: string }>(value: T, prefix: string): T {
+	return new Proxy(value, {
+		get(target, property) {
+			if (property === 'key') {
+				return target.key.slice(prefix.length);
+			}
+			let member = Reflect.get(target, property, target);
+			return typeof member === 'function' ? member.bind(target) : member;
+		},
+	});
+}
+
+function strip_upload(upload: R2MultipartUpload, prefix: string): R2MultipartUpload {
+	return {
+		key: upload.key.slice(prefix.length),
+		uploadId: upload.uploadId,
+		uploadPart: upload.uploadPart.bind(upload),
+		abort: upload.abort.bind(upload),
+		complete: async (parts: R2UploadedPart[]) => strip_key(await upload.complete(parts), prefix),
+	};
+}
+
+// The part of a bucket under prefix, as if it were a whole bucket: the prefix is added to every key
+// passed in and removed from every key handed back
+export function prefix_bucket(bucket: R2Bucket, prefix: string): R2Bucket {
+	if (prefix === '') {
+		return bucket;
+	}
+	const strip = <T extends R2Object | null>(object: T): T => (object === null ? object : strip_key(object, prefix));
+
+	let prefixed = {
+		head: async (key: string) => strip(await bucket.head(prefix + key)),
+		get: async (key: string, options?: R2GetOptions) => strip(await bucket.get(prefix + key, options)),
+		put: async (key: string, value: Parameters<R2Bucket['put']>[1], options?: R2PutOptions) =>
+			strip(await bucket.put(prefix + key, value, options)),
+		delete: (keys: string | string[]) =>
+			bucket.delete(Array.isArray(keys) ? keys.map((key) => prefix + key) : prefix + keys),
+		list: async (options?: R2ListOptions) => {
+			let result = await bucket.list({
+				...options,
+				prefix: prefix + (options?.prefix ?? ''),
+				startAfter: options?.startAfter === undefined ? undefined : prefix + options.startAfter,
+			});
+			return {
+				...result,
+				objects: result.objects.map(strip),
+				delimitedPrefixes: result.delimitedPrefixes.map((delimited) => delimited.slice(prefix.length)),
+			};
+		},
+		createMultipartUpload: async (key: string, options?: R2MultipartOptions) =>
+			strip_upload(await bucket.createMultipartUpload(prefix + key, options), prefix),
+		resumeMultipartUpload: (key: string, uploadId: string) =>
+			strip_upload(bucket.resumeMultipartUpload(prefix + key, uploadId), prefix),
+	};
+	// The overloads of get and put can't be spelled out on an object literal
+	return prefixed as unknown as R2Bucket;
+}
+
+// R2 error codes worth another try: internal error, service unavailable, reduced capacity and rate limiting.
+// Failed conditions aren't errors, R2 answers them with null or an object without a body.
+const RETRYABLE_CODES = [10001, 10043, 10054, 10058];
+const RETRY_BASE_DELAY = 100;
+
+function is_retryable(error: unknown): boolean {
+	let code = String(error).match(/\((\d+)\)/)?.[1];
+	return code !== undefined && RETRYABLE_CODES.includes(Number(code));
+}
+
+async function with_retries<T>(operation: () => Promise<T>, max_retries: number): Promise<T> {
+	for (let attempt = 0; ; attempt++) {
+		try {
+			return await operation();
+		} catch (error) {
+			if (attempt >= max_retries || !is_retryable(error)) {
+				throw error;
+			}
+			// Exponential backoff with jitter, so concurrent requests don't retry in lockstep
+			let delay = RETRY_BASE_DELAY * 2 ** attempt * (0.5 + Math.random() / 2);
+			logger.warn('Retrying an R2 operation', { attempt: attempt + 1, delay, error });
+			await new Promise((resolve) => setTimeout(resolve, delay));
+		}
+	}
+}
+
+// The bucket with its operations retried up to max_retries times on transient errors. Puts of streams
+// and multipart uploads aren't retried, a stream can only be read once. Neither are conditional puts:
+// when the first attempt went through after all, the retry would fail its own condition.
+export function retry_bucket(bucket: R2Bucket, max_retries: number): R2Bucket {
+	if (max_retries <= 0) {
+		return bucket;
+	}
+	let retrying = {
+		head: (key: string) => with_retries(() => bucket.head(key), max_retries),
+		get: (key: string, options?: R2GetOptions) => with_retries(() => bucket.get(key, options), max_retries),
+		put: (key: string, value: Parameters<R2Bucket['put']>[1], options?: R2PutOptions) =>
+			value instanceof ReadableStream || options?.onlyIf !== undefined
+				? bucket.put(key, value, options)
+				: with_retries(() => bucket.put(key, value, options), max_retries),
+		delete: (keys: string | string[]) => with_retries(() => bucket.delete(keys), max_retries),
+		list: (options?: R2ListOptions) => with_retries(() => bucket.list(options), max_retries),
+		createMultipartUpload: bucket.createMultipartUpload.bind(bucket),
+		resumeMultipartUpload: bucket.resumeMultipartUpload.bind(bucket),
+	};
+	return retrying as unknown as R2Bucket;
+}
+
+// Puts that only rewrite what's known about an object, like PROPPATCH, mark their options with this.
+// notify_bucket leaves them out, receivers are told about the files written.
+const metadata_puts = new WeakSet<R2PutOptions>();
+
+export function metadata_only(options: R2PutOptions = {}): R2PutOptions {
+	metadata_puts.add(options);
+	return options;
+}
+
+// The bucket with every object written or deleted through it passed to notify once R2 has done it,
+// null for a delete. Writes whose conditions failed and metadata_only puts aren't.
+export function notify_bucket(bucket: R2Bucket, notify: (key: string, object: R2Object | null) => void): R2Bucket {
+	const notify_upload = (upload: R2MultipartUpload): R2MultipartUpload => ({
+		key: upload.key,
+		uploadId: upload.uploadId,
+		uploadPart: upload.uploadPart.bind(upload),
+		abort: upload.abort.bind(upload),
+		complete: async (parts: R2UploadedPart[]) => {
+			let object = await upload.complete(parts);
+			notify(object.key, object);
+			return object;
+		},
+	});
+
+	let notifying = {
+		head: bucket.head.bind(bucket),
+		get: bucket.get.bind(bucket),
+		put: async (key: string, value: Parameters<R2Bucket['put']>[1], options?: R2PutOptions) => {
+			let object = await bucket.put(key, value, options);
+			if (object !== null && !(options !== undefined && metadata_puts.has(options))) {
+				notify(key, object);
+			}
+			return object;
+		},
+		delete: async (keys: string | string[]) => {
+			await bucket.delete(keys);
+			for (let key of Array.isArray(keys) ? keys : [keys]) {
+				notify(key, null);
+			}
+		},
+		list: bucket.list.bind(bucket),
+		createMultipartUpload: async (key: string, options?: R2MultipartOptions) =>
+			notify_upload(await bucket.createMultipartUpload(key, options)),
+		resumeMultipartUpload: (key: string, uploadId: string) =>
+			notify_upload(bucket.resumeMultipartUpload(key, uploadId)),
+	};
+	return notifying as unknown as R2Bucket;
+}
+
+// Subrequests a request can still make, shared by the buckets it uses
+export type Budget = { remaining: number };
+
+// The bucket with every call to R2 counted against budget. Once it's spent calls throw BudgetExhausted.
+export function budget_bucket(bucket: R2Bucket, budget: Budget): R2Bucket {
+	const spend = <T>(operation: () => Promise<T>): Promise<T> => {
+		if (budget.remaining <= 0) {
+			return Promise.reject(new BudgetExhausted());
+		}
+		budget.remaining--;
+		return operation();
+	};
+	const count_upload = (upload: R2MultipartUpload): R2MultipartUpload => ({
+		key: upload.key,
+		uploadId: upload.uploadId,
+		uploadPart: (...args: Parameters<R2MultipartUpload['uploadPart']>) => spend(() => upload.uploadPart(...args)),
+		abort: () => spend(() => upload.abort()),
+		complete: (parts: R2UploadedPart[]) => spend(() => upload.complete(parts)),
+	});
+
+	let counting = {
+		head: (key: string) => spend(() => bucket.head(key)),
+		get: (key: string, options?: R2GetOptions) => spend(() => bucket.get(key, options)),
+		put: (key: string, value: Parameters<R2Bucket['put']>[1], options?: R2PutOptions) =>
+			spend(() => bucket.put(key, value, options)),
+		delete: (keys: string | string[]) => spend(() => bucket.delete(keys)),
+		list: (options?: R2ListOptions) => spend(() => bucket.list(options)),
+		createMultipartUpload: async (key: string, options?: R2MultipartOptions) =>
+			count_upload(await spend(() => bucket.createMultipartUpload(key, options))),
+		resumeMultipartUpload: (key: string, uploadId: string) =>
+			count_upload(bucket.resumeMultipartUpload(key, uploadId)),
+	};
+	return counting as unknown as R2Bucket;
+}
+
+// Request header with a customer provided key (SSE-C) for the objects the request reads and writes.
+// Keys are 256 bit, written as 64 hex digits.
+export const SSEC_HEADER = 'X-SSEC-Key';
+
+export function is_ssec_key(key: string): boolean {
+	return /^[0-9a-f]{64}$/i.test(key);
+}
+
+// R2 refuses reads of an object encrypted with another key, or with none, the key itself is never in the error
+function is_key_error(error: unknown): boolean {
+	return /ssec|encryption key/i.test(String(error));
+}
+
+// The bucket with every object written and read with the customer provided key. Objects stay encrypted
+// at rest with it, R2 doesn't keep it, so anything stored by this bucket can't be read without it.
+// Without a key only the reads of encrypted objects are refused, with a 403 rather than a 500.
+export function ssec_bucket(bucket: R2Bucket, key: string | undefined): R2Bucket {
+	let ssec = key === undefined ? {} : { ssecKey: key };
+	const with_key = async <T>(operation: () => Promise<T>): Promise<T> => {
+		try {
+			return await operation();
+		} catch (error) {
+			throw is_key_error(error) ? new DavError(403, 'Forbidden') : error;
+		}
+	};
+	const encrypt_upload = (upload: R2MultipartUpload): R2MultipartUpload => ({
+		key: upload.key,
+		uploadId: upload.uploadId,
+		uploadPart: (part: number, value: Parameters<R2MultipartUpload['uploadPart']>[1]) =>
+			upload.uploadPart(part, value, ssec),
+		abort: upload.abort.bind(upload),
+		complete: upload.complete.bind(upload),
+	});
+
+	let encrypting = {
+		head: (name: string) => with_key(() => bucket.head(name, ssec)),
+		get: (name: string, options?: R2GetOptions) => with_key(() => bucket.get(name, { ...options, ...ssec })),
+		put: (name: string, value: Parameters<R2Bucket['put']>[1], options?: R2PutOptions) =>
+			bucket.put(name, value, { ...options, ...ssec }),
+		delete: bucket.delete.bind(bucket),
+		list: bucket.list.bind(bucket),
+		createMultipartUpload: async (name: string, options?: R2MultipartOptions) =>
+			encrypt_upload(await bucket.createMultipartUpload(name, { ...options, ...ssec })),
+		resumeMultipartUpload: (name: string, uploadId: string) =>
+			encrypt_upload(bucket.resumeMultipartUpload(name, uploadId)),
+	};
+	return encrypting as unknown as R2Bucket;
+}
+
+// The entries of a KV namespace, keyed "<kind>:<path>" like "lock:<path>", with the paths relative to the
+// folder prefix of the bucket. An object has the same entries whoever reaches it from where, so a lock
+// taken by an admin holds for the user confined to the folder as well.
+export function prefix_kv(kv: KVNamespace, prefix: string): KVNamespace {
+	if (prefix === '') {
+		return kv;
+	}
+	// The folder itself is the prefix without its slash, like the key of a collection marker
+	let to_key = (key: string, listing: boolean = false) => {
+		let kind = key.slice(0, key.indexOf(':') + 1);
+		let path = key.slice(kind.length);
+		return kind + (path === '' && !listing ? prefix.slice(0, -1) : prefix + path);
+	};
+	let prefixed = {
+		get: (key: string, type?: 'text' | 'json') => (type === 'json' ? kv.get(to_key(key), 'json') : kv.get(to_key(key))),
+		put: (key: string, value: string, options?: KVNamespacePutOptions) => kv.put(to_key(key), value, options),
+		delete: (key: string) => kv.delete(to_key(key)),
+		list: async (options?: KVNamespaceListOptions) => {
+			let result = await kv.list({ ...options, prefix: to_key(options?.prefix ?? '', true) });
+			let keys = result.keys.map((key) => {
+				let kind = key.name.slice(0, key.name.indexOf(':') + 1);
+				return { ...key, name: kind + key.name.slice(kind.length + prefix.length) };
+			});
+			return { ...result, keys };
+		},
+	};
+	// Like for the bucket, the overloads of get can't be spelled out. Text and JSON are all we read.
+	return prefixed as unknown as KVNamespace;
+}
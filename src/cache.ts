@@ -0,0 +1,78 @@
+// Object downloads kept in the Workers Cache API, so repeated GETs don't read R2 again.
+//
+// Entries are keyed by the ETag of the object as well as its path. A request only reads the current ETag
+// from R2, so an overwritten object is never served from the cache, in whatever location it was written.
+// The entries of old versions are deleted where the write happened, elsewhere they just expire.
+
+import { SSEC_HEADER } from './bucket';
+
+// Used for objects stored without a Cache-Control
+const DEFAULT_MAX_AGE = 60;
+// Partial and conditional requests always go to R2, as do those with their own key, to be decrypted with it
+const BYPASS_HEADERS = [
+	'Range',
+	'If-Match',
+	'If-None-Match',
+	'If-Modified-Since',
+	'If-Unmodified-Since',
+	'If-Range',
+	SSEC_HEADER,
+];
+
+// One key per version of a resource, however its URL was encoded
+function cache_key(origin: string, href: string, etag: string): Request {
+	return new Request(`${origin}${href}?etag=${encodeURIComponent(etag)}`, { method: 'GET' });
+}
+
+export function is_cacheable(request: Request): boolean {
+	return (
+		(request.method === 'GET' || request.method === 'HEAD') &&
+		!new URL(request.url).pathname.endsWith('/') &&
+		new URL(request.url).search === '' &&
+		!BYPASS_HEADERS.some((name) => request.headers.has(name))
+	);
+}
+
+// etag is the one of the object as it is now in R2
+export async function match_cache(request: Request, href: string, etag: string): Promise<Response | undefined> {
+	if (!is_cacheable(request)) {
+		return undefined;
+	}
+	let response = await caches.default.match(cache_key(new URL(request.url).origin, href, etag));
+	if (response === undefined) {
+		return undefined;
+	}
+	// Responses from the cache have immutable headers
+	return new Response(request.method === 'HEAD' ? null : response.body, {
+		status: response.status,
+		headers: new Headers(response.headers),
+	});
+}
+
+// Keeps a copy of a full download, unless the object asks not to be stored. It's keyed by the ETag
+// it was sent with, the object may have changed since the request looked it up.
+export function store_in_cache(request: Request, href: string, response: Response, ctx: ExecutionContext) {
+	let cache_control = response.headers.get('Cache-Control') ?? '';
+	let etag = response.headers.get('ETag')?.replace(/^W\//, '').replace(/^"(.*)"$/, '$1');
+	if (!is_cacheable(request) || request.method !== 'GET' || response.status !== 200 || !etag) {
+		return;
+	} else if (/no-store|private/i.test(cache_control) || response.headers.has('Vary')) {
+		// Responses that vary, like compressed ones, would be served to every client alike
+		return;
+	}
+	let copy = response.clone();
+	let headers = new Headers(copy.headers);
+	if (cache_control === '') {
+		headers.set('Cache-Control', `max-age=${DEFAULT_MAX_AGE}`);
+	}
+	let key = cache_key(new URL(request.url).origin, href, etag);
+	ctx.waitUntil(caches.default.put(key, new Response(copy.body, { status: copy.status, headers })));
+}
+
+export type CachedVersion = { href: string; etag: string };
+
+// Deletes the entries of versions a write replaced or removed
+export function invalidate_cache(request: Request, versions: CachedVersion[], ctx: ExecutionContext) {
+	let origin = new URL(request.url).origin;
+	ctx.waitUntil(Promise.all(versions.map(({ href, etag }) => caches.default.delete(cache_key(origin, href, etag)))));
+}
@@ -0,0 +1,34 @@
+// Compression of downloads for clients that accept it, limited to content types worth compressing.
+
+const DEFAULT_COMPRESSIBLE_TYPES = 'text/*, application/json, application/javascript, application/xml, image/svg+xml';
+
+// Whether content_type is in the comma separated allowlist, which may contain wildcards like "text/*"
+export function is_compressible(content_type: string | undefined, allowlist: string = DEFAULT_COMPRESSIBLE_TYPES) {
+	let type = (content_type ?? '').split(';')[0].trim().toLowerCase();
+	return allowlist
+		.split(',')
+		.map((pattern) => pattern.trim().toLowerCase())
+		.some((pattern) => pattern === type || (pattern.endsWith('/*') && type.startsWith(pattern.slice(0, -1))));
+}
+
+// The q-value Accept-Encoding gives an encoding, 0 when it isn't acceptable
+function accepted_quality(accept_encoding: string, encoding: string): number {
+	let qualities = new Map<string, number>();
+	for (let entry of accept_encoding.split(',')) {
+		let [name, ...parameters] = entry.split(';').map((part) => part.trim().toLowerCase());
+		let q = parameters.find((parameter) => parameter.startsWith('q='));
+		qualities.set(name, q === undefined ? 1 : Number(q.slice(2)) || 0);
+	}
+	return qualities.get(encoding) ?? qualities.get('*') ?? 0;
+}
+
+// The encoding to compress a response with, or null to send it as it is. Brotli compresses better,
+// it's taken unless the client prefers gzip.
+export function negotiate_encoding(accept_encoding: string | null): 'br' | 'gzip' | null {
+	let br = accepted_quality(accept_encoding ?? '', 'br');
+	let gzip = accepted_quality(accept_encoding ?? '', 'gzip');
+	if (br > 0 && br >= gzip) {
+		return 'br';
+	}
+	return gzip > 0 ? 'gzip' : null;
+}
@@ -0,0 +1,24 @@
+// Errors that end a request with a given status. They can be thrown from anywhere below a handler,
+// dispatch_handler turns them into the response.
+//
+// With a condition, the response body is a DAV:error element naming the precondition or postcondition
+// that failed (RFC 4918 section 16), like "propfind-finite-depth".
+export class DavError extends Error {
+	constructor(
+		readonly status: number,
+		message: string,
+		readonly condition?: string,
+	) {
+		super(message);
+		this.name = 'DavError';
+	}
+}
+
+// Thrown once a request has made all the subrequests it may, before the runtime's own limit fails it
+// without a word. Done work stays done, so repeating the request goes on where it stopped.
+export class BudgetExhausted extends DavError {
+	constructor() {
+		super(507, 'Subrequest budget exhausted, repeat the request to continue');
+		this.name = 'BudgetExhausted';
+	}
+}
@@ -0,0 +1,18 @@
+// Files macOS leaves on network shares: AppleDouble "._name" files holding extended attributes,
+// and the .DS_Store files with the Finder settings of a folder.
+
+const DEFAULT_HIDDEN_PATTERNS = '._*, .DS_Store';
+
+function pattern_to_regexp(pattern: string): RegExp {
+	let escaped = pattern.replace(/[.+?^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*');
+	return new RegExp(`^${escaped}$`);
+}
+
+// Whether the name of the file at path matches one of the comma separated patterns, "*" matches anything
+export function is_hidden_file(path: string, patterns: string = DEFAULT_HIDDEN_PATTERNS): boolean {
+	let name = path.replace(/\/$/, '').split('/').pop() ?? '';
+	return patterns
+		.split(',')
+		.map((pattern) => pattern.trim())
+		.some((pattern) => pattern !== '' && pattern_to_regexp(pattern).test(name));
+}
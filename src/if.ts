@@ -0,0 +1,122 @@
+// The If request header (RFC 4918 section 10.4), like `<http://host/a> (<token> ["etag"]) (Not <DAV:no-lock>)`.
+//
+// It holds when any of its lists does, and a list when all of its conditions do. Lists without a
+// resource tag apply to the request URL. A state token matches a lock covering the resource, an
+// entity tag its current ETag.
+
+export type IfCondition = {
+	not: boolean;
+	type: 'token' | 'etag';
+	value: string;
+};
+
+export type IfList = {
+	// The tagged URL, null for the request URL
+	resource: string | null;
+	conditions: IfCondition[];
+};
+
+export type ResourceState = {
+	etag: string | undefined;
+	tokens: string[];
+};
+
+// Parses the header, null when it's malformed
+export function parse_if_header(header: string): IfList[] | null {
+	let lists: IfList[] = [];
+	let resource: string | null = null;
+	let tagged: boolean | undefined = undefined;
+	let position = 0;
+
+	// The text up to end, position being past the opening delimiter
+	const read_until = (end: string): string | null => {
+		let index = header.indexOf(end, position);
+		if (index < 0) {
+			return null;
+		}
+		let value = header.slice(position, index);
+		position = index + 1;
+		return value;
+	};
+	const skip_whitespace = () => {
+		while (/\s/.test(header[position] ?? '')) {
+			position++;
+		}
+	};
+
+	while (true) {
+		skip_whitespace();
+		if (position >= header.length) {
+			break;
+		}
+		let char = header[position++];
+		if (char === '<') {
+			// Tagged and untagged lists can't be mixed
+			if (tagged === false) {
+				return null;
+			}
+			tagged = true;
+			resource = read_until('>');
+			if (resource === null) {
+				return null;
+			}
+		} else if (char === '(') {
+			if (tagged === undefined) {
+				tagged = false;
+			}
+			let conditions: IfCondition[] = [];
+			while (true) {
+				skip_whitespace();
+				if (header[position] === ')') {
+					position++;
+					break;
+				}
+				let not = header.slice(position, position + 3).toLowerCase() === 'not';
+				if (not) {
+					position += 3;
+					skip_whitespace();
+				}
+				let open = header[position++];
+				let value = open === '<' ? read_until('>') : open === '[' ? read_until(']') : null;
+				if (value === null) {
+					return null;
+				}
+				conditions.push({ not, type: open === '<' ? 'token' : 'etag', value });
+			}
+			if (conditions.length === 0) {
+				return null;
+			}
+			lists.push({ resource, conditions });
+		} else {
+			return null;
+		}
+	}
+	return lists.length > 0 ? lists : null;
+}
+
+// The lock tokens the client submits, whether the conditions hold or not
+export function submitted_tokens(lists: IfList[]): string[] {
+	return lists.flatMap((list) =>
+		list.conditions.filter((condition) => condition.type === 'token' && !condition.not).map(({ value }) => value),
+	);
+}
+
+export async function evaluate_if(
+	lists: IfList[],
+	state: (resource: string | null) => Promise<ResourceState>,
+): Promise<boolean> {
+	for (let list of lists) {
+		let { etag, tokens } = await state(list.resource);
+		let holds = list.conditions.every((condition) => {
+			let matches =
+				condition.type === 'token'
+					? tokens.includes(condition.value)
+					: condition.value.replace(/^W\//, '').replace(/^"(.*)"$/, '$1') === etag;
+			return matches !== condition.not;
+		});
+		if (holds) {
+			return true;
+		}
+	}
+	return false;
+}
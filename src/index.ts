@@ -8,26 +8,181 @@
  * Learn more at https://developers.cloudflare.com/workers/
  */
 
+import {
+	Budget,
+	SSEC_HEADER,
+	budget_bucket,
+	is_ssec_key,
+	metadata_only,
+	notify_bucket,
+	prefix_bucket,
+	prefix_kv,
+	retry_bucket,
+	ssec_bucket,
+} from './bucket';
+import { CachedVersion, invalidate_cache, is_cacheable, match_cache, store_in_cache } from './cache';
+import { is_compressible, negotiate_encoding } from './compress';
+import { BudgetExhausted, DavError } from './error';
+import { is_hidden_file } from './hidden';
+import { IfList, ResourceState, evaluate_if, parse_if_header, submitted_tokens } from './if';
+import { negotiate_language, variant_language } from './language';
+import { logger, set_log_level } from './log';
+import {
+	Lock,
+	SUPPORTED_LOCKS,
+	create_lock,
+	find_conflicts,
+	find_locks,
+	find_member_locks,
+	generate_activelock,
+	is_unlocked,
+	locks_covering,
+	parse_timeout,
+	refresh_lock,
+	remove_lock,
+} from './lock';
+import { guess_content_type, is_generic_content_type } from './mime';
+import { DEFAULT_LINK_LIFETIME, MAX_LINK_LIFETIME, sign_path, verify_signature } from './presign';
+import {
+	Property,
+	PropfindEntry,
+	PropfindRequest,
+	minimal_propfind,
+	parse_propfind,
+	stream_multistatus,
+} from './propfind';
+import { count_request, peek_requests, request_kind } from './ratelimit';
+import {
+	MAX_PART_SIZE,
+	MIN_PART_SIZE,
+	UPLOAD_TTL,
+	delete_upload,
+	find_stale_uploads,
+	get_upload,
+	parse_content_range,
+	put_upload,
+} from './upload';
+import { WebhookEvent, object_event, send_webhook } from './webhook';
+import { XmlElement, child_elements, escape_xml, find_child, parse_xml, serialize_xml, text_content } from './xml';
+
 export interface Env {
 	// Example binding to R2. Learn more at https://developers.cloudflare.com/workers/runtime-apis/r2/
 	bucket: R2Bucket;
 
+	// Optional KV namespace for locks, LOCK is only supported when it's bound
+	kv?: KVNamespace;
+
+	// Optional second bucket, COPY and MOVE put Destinations under SECONDARY_PREFIX there
+	secondary_bucket?: R2Bucket;
+
 	// Variables defined in the "Environment Variables" section of the Wrangler CLI or dashboard
-	USERNAME: string;
-	PASSWORD: string;
+	USERNAME?: string;
+	PASSWORD?: string;
+	// More accounts, user:password pairs separated by commas or newlines
+	USERS?: string;
+	// "basic" by default, "bearer" accepts only "Authorization: Bearer <BEARER_TOKEN>" instead
+	AUTH_MODE?: string;
+	BEARER_TOKEN?: string;
+	// Key of the temporary download links made with ?presign=, they're disabled without it
+	SIGNING_SECRET?: string;
+	// Customer provided key (SSE-C) every object is encrypted with, 64 hex digits, see SSEC_HEADER
+	SSEC_KEY?: string;
+	// Comma separated origins allowed to call from browsers, like "https://app.example.com", all by default
+	CORS_ORIGINS?: string;
+	// Parts of a multi-range download read from R2 at the same time, 3 by default
+	RANGE_CONCURRENCY?: string;
+	// Size in bytes of the parts big uploads are split into, 10 MiB by default, from 5 MiB to 5 GiB
+	MULTIPART_PART_SIZE?: string;
+	// Largest file a PUT may upload, in bytes, unlimited by default
+	MAX_UPLOAD_BYTES?: string;
+	// Sends nosniff and CONTENT_SECURITY_POLICY with downloads, and FRAME_OPTIONS as X-Frame-Options if set
+	SECURITY_HEADERS?: string | boolean;
+	CONTENT_SECURITY_POLICY?: string;
+	FRAME_OPTIONS?: string;
+	// Standard (the default) or InfrequentAccess, uploads can choose with an X-Storage-Class header
+	STORAGE_CLASS?: string;
+	// XML namespace of the properties the custom metadata of objects is shown as, urn:r2-webdav:metadata by default
+	METADATA_NAMESPACE?: string;
+	// Comma separated folder markers of other tools recognized besides ours: slash (the default), bzEmpty
+	// and $folder$
+	FOLDER_MARKERS?: string;
+	// Cache-Control sent with objects stored without one, like "public, max-age=3600"
+	CACHE_CONTROL?: string;
+	// Sends every ETag as weak, W/"...", for content that may change in ways clients shouldn't rely on
+	WEAK_ETAGS?: string | boolean;
+	// Gives every user the folder users/<name>/ as their root, except the comma separated ADMIN_USERS
+	USER_ISOLATION?: string | boolean;
+	ADMIN_USERS?: string;
+
+	// Set by serve_request, the authenticated user or the one a signed link was made by
+	user?: string | null;
+	// Set by serve_request, the subrequests left to the buckets of the request
+	budget?: Budget;
+	// Serves GETs of missing files with their language variants, like page.fr.html for page.html,
+	// picked with Accept-Language or else DEFAULT_LANGUAGE
+	LANGUAGE_NEGOTIATION?: string | boolean;
+	DEFAULT_LANGUAGE?: string;
+	// File, like index.html, served for the GET of a folder holding one instead of its listing
+	INDEX_DOCUMENT?: string;
+	// Only allows reading, every method that writes is refused with a 405
+	READ_ONLY?: string | boolean;
+	// Serves only the keys under this prefix, as if they were the whole bucket
+	ROOT_PREFIX?: string;
+	// error, warn, info (the default) or debug
+	LOG_LEVEL?: string;
+	// Keeps downloads in the Cache API of each location
+	CACHE_ENABLED?: string | boolean;
+	// Largest object whose dead properties can be changed, 100 MiB by default
+	PROPPATCH_MAX_BYTES?: string;
+	// Compresses downloads of the types in COMPRESSIBLE_TYPES, a comma separated list like "text/*, application/json"
+	COMPRESSION_ENABLED?: string | boolean;
+	COMPRESSIBLE_TYPES?: string;
+
+	// Maximum number of resources a Depth: infinity PROPFIND may return, unlimited by default
+	PROPFIND_INFINITY_LIMIT?: string;
+	// Storage quota in bytes, enables the quota-used-bytes and quota-available-bytes properties
+	QUOTA_BYTES?: string;
+	// Drops uploads of the macOS files matching HIDDEN_FILE_PATTERNS and leaves them out of listings
+	HIDE_APPLE_FILES?: string | boolean;
+	HIDDEN_FILE_PATTERNS?: string;
+	// Folder, like "production", whose path leads into secondary_bucket as a Destination
+	SECONDARY_PREFIX?: string;
+	// Retries of R2 operations failing with a transient error, 2 by default
+	R2_MAX_RETRIES?: string;
+	// Set to false to answer the DELETE of a missing resource with 204 instead of 404
+	DELETE_MISSING_IS_404?: string | boolean;
+	// Seconds after its last chunk a resumable upload is aborted by the scheduled handler, a day by default.
+	// Uploads last a week at most anyway, longer ages are cut to that.
+	UPLOAD_MAX_AGE?: string;
+	// Makes the scheduled handler check the SHA-256 of some objects on every run, logging mismatches
+	INTEGRITY_AUDIT?: string | boolean;
+	// Requests a user, or a client IP without one, may make per RATE_LIMIT_WINDOW seconds, a minute by
+	// default. Reads are GET, HEAD, OPTIONS and PROPFIND, the rest are writes. Counted in the KV namespace.
+	// Requests with the wrong credentials count against their IP, and are refused with it before they're checked.
+	RATE_LIMIT_READS?: string;
+	RATE_LIMIT_WRITES?: string;
+	RATE_LIMIT_WINDOW?: string;
+	// URL the events of the files a request wrote or deleted are POSTed to together, signed with WEBHOOK_SECRET
+	WEBHOOK_URL?: string;
+	WEBHOOK_SECRET?: string;
+	// Calls to R2 a request may make, recursive operations stop with a 507 there. 950 by default, the runtime
+	// allows 1000 subrequests and KV and the cache need some.
+	SUBREQUEST_LIMIT?: string;
 }
 
-async function* listAll(bucket: R2Bucket, prefix: string, isRecursive: boolean = false) {
+async function* listAll(bucket: R2Bucket, prefix: string, isRecursive: boolean = false, start_after?: string) {
 	let cursor: string | undefined = undefined;
 	do {
 		var r2_objects = await bucket.list({
 			prefix: prefix,
 			delimiter: isRecursive ? undefined : '/',
 			cursor: cursor,
+			startAfter: cursor === undefined ? start_after : undefined,
 			// @ts-ignore https://developers.cloudflare.com/r2/api/workers/workers-api-reference/#r2listoptions
 			include: ['httpMetadata', 'customMetadata'],
 		});
 
+		logger.debug('Listed objects', { prefix, count: r2_objects.objects.length, truncated: r2_objects.truncated });
 		for (let object of r2_objects.objects) {
 			yield object;
 		}
@@ -38,6 +193,138 @@ async function* listAll(bucket: R2Bucket, prefix: string, isRecursive: boolean =
 	} while (r2_objects.truncated);
 }
 
+type ListPage = {
+	entries: (R2Object | string)[];
+	// Continues the listing, undefined on the last page
+	cursor: string | undefined;
+};
+
+// One page of the direct members of a directory, in the form list_dir yields them. R2 counts
+// objects and prefixes alike against the limit, and may return less when metadata is included.
+async function list_page(
+	bucket: R2Bucket,
+	env: Env,
+	prefix: string,
+	options: { start_after?: string; limit?: number; cursor?: string } = {},
+): Promise<ListPage> {
+	let r2_objects = await bucket.list({
+		prefix: prefix,
+		delimiter: '/',
+		startAfter: options.start_after,
+		limit: options.limit,
+		cursor: options.cursor,
+		// @ts-ignore https://developers.cloudflare.com/r2/api/workers/workers-api-reference/#r2listoptions
+		include: ['httpMetadata', 'customMetadata'],
+	});
+	let markers = new Set(
+		r2_objects.objects
+			.filter((object) => is_collection(object))
+			.map((object) => object.key + '/'),
+	);
+	// The markers of other tools aren't members, those of member folders stand for them
+	let objects: R2Object[] = [];
+	let prefixes = new Set(r2_objects.delimitedPrefixes.filter((prefix) => !markers.has(prefix)));
+	for (let object of r2_objects.objects) {
+		let folder = foreign_marker_folder(env, object.key);
+		if (folder === null) {
+			objects.push(object);
+		} else if (folder !== prefix.replace(/\/$/, '') && !markers.has(folder + '/')) {
+			prefixes.add(folder + '/');
+		}
+	}
+	return {
+		entries: [...objects, ...prefixes],
+		cursor: r2_objects.truncated ? r2_objects.cursor : undefined,
+	};
+}
+
+// Lists the direct members of a directory. Subdirectories created by other tools may have no
+// marker object, those are yielded as their delimited prefix, like "photos/2024/".
+async function* list_dir(bucket: R2Bucket, env: Env, prefix: string): AsyncGenerator<R2Object | string> {
+	let markers = new Set<string>();
+	let cursor: string | undefined = undefined;
+	do {
+		let page: ListPage = await list_page(bucket, env, prefix, { cursor });
+		for (let entry of page.entries) {
+			if (typeof entry !== 'string') {
+				if (is_collection(entry)) {
+					markers.add(entry.key + '/');
+				}
+				yield entry;
+			} else if (!markers.has(entry)) {
+				// A marker always sorts before its prefix, so it has been seen by now, even on an earlier page.
+				// A Hadoop marker sorts after it, the folder is only yielded once.
+				markers.add(entry);
+				yield entry;
+			}
+		}
+		cursor = page.cursor;
+	} while (cursor !== undefined);
+}
+
+// Collections are kept as empty marker objects carrying this in their custom metadata
+const COLLECTION_RESOURCETYPE = '<collection />';
+
+function is_collection(object: R2Object | null): boolean {
+	return object?.customMetadata?.resourcetype === COLLECTION_RESOURCETYPE;
+}
+
+// The markers other tools keep their folders with, recognized when listed in FOLDER_MARKERS: "slash" for
+// an empty object named like the folder with a slash (the AWS console), "bzEmpty" for a .bzEmpty file in
+// it (Backblaze) and "$folder$" for an object named like the folder with _$folder$ after it (Hadoop).
+// Collections created here always have a marker of our own.
+const DEFAULT_FOLDER_MARKERS = 'slash';
+const BZEMPTY_MARKER = '.bzEmpty';
+const HADOOP_MARKER_SUFFIX = '_$folder$';
+
+function folder_markers(env: Env): string[] {
+	return (env.FOLDER_MARKERS ?? DEFAULT_FOLDER_MARKERS).split(',').map((marker) => marker.trim());
+}
+
+// The folder a marker of another tool stands for, like "photos" for "photos/" or "photos_$folder$".
+// Null for any other key.
+function foreign_marker_folder(env: Env, key: string): string | null {
+	let markers = folder_markers(env);
+	let name = key.split('/').pop()!;
+	let hadoop_marker = name.endsWith(HADOOP_MARKER_SUFFIX) && name !== HADOOP_MARKER_SUFFIX;
+	if (markers.includes('slash') && key.endsWith('/')) {
+		return key.slice(0, -1);
+	} else if (markers.includes('bzEmpty') && name === BZEMPTY_MARKER) {
+		return key.slice(0, -BZEMPTY_MARKER.length).replace(/\/$/, '');
+	} else if (markers.includes('$folder$') && hadoop_marker) {
+		return key.slice(0, -HADOOP_MARKER_SUFFIX.length);
+	}
+	return null;
+}
+
+// Whether there are keys below path, which makes it a collection even without a marker. The markers
+// of the AWS console and Backblaze are below it, only a Hadoop one has to be looked up.
+async function has_members(bucket: R2Bucket, env: Env, path: string): Promise<boolean> {
+	let listing = await bucket.list({ prefix: path === '' ? '' : path + '/', limit: 1 });
+	if (listing.objects.length > 0) {
+		return true;
+	}
+	let hadoop = path !== '' && folder_markers(env).includes('$folder$');
+	return hadoop && (await bucket.head(path + HADOOP_MARKER_SUFFIX)) !== null;
+}
+
+// The keys that may mark the collection at path besides those below it: ours, and a Hadoop one.
+// Deleting a collection deletes them once its members are gone.
+function marker_keys(env: Env, path: string): string[] {
+	return folder_markers(env).includes('$folder$') ? [path, path + HADOOP_MARKER_SUFFIX] : [path];
+}
+
+// R2 is flat, a collection is a zero-byte marker object flagged by its custom metadata
+async function put_collection_marker(bucket: R2Bucket, path: string): Promise<R2Object> {
+	return await bucket.put(path, new Uint8Array(), { customMetadata: { resourcetype: COLLECTION_RESOURCETYPE } });
+}
+
+// Whether path is a collection, with a marker or implied by the keys below it. A file can't be written
+// in its place, the folder and the file would share a name. object is what's stored at path.
+async function is_collection_path(bucket: R2Bucket, env: Env, path: string, object: R2Object | null): Promise<boolean> {
+	return object === null ? await has_members(bucket, env, path) : is_collection(object);
+}
+
 type DavProperties = {
 	creationdate: string | undefined;
 	displayname: string | undefined;
@@ -49,40 +336,149 @@ type DavProperties = {
 	resourcetype: string;
 };
 
-function fromR2Object(object: R2Object | null | undefined): DavProperties {
+// creationdate is an ISO 8601 date (RFC 4918 section 15.1), getlastmodified an HTTP-date.
+// Collections without a marker have no timestamps in R2, they get the current time.
+// The values are XML, text values are escaped here.
+function fromR2Object(env: Env, object: R2Object | null | undefined, path: string = object?.key ?? ''): DavProperties {
+	let displayname = path === '' ? undefined : escape_xml(path.split('/').pop()!);
 	if (object === null || object === undefined) {
 		return {
-			creationdate: new Date().toUTCString(),
-			displayname: undefined,
+			creationdate: new Date().toISOString(),
+			displayname: displayname,
 			getcontentlanguage: undefined,
-			getcontentlength: '0',
+			getcontentlength: undefined,
 			getcontenttype: undefined,
 			getetag: undefined,
 			getlastmodified: new Date().toUTCString(),
-			resourcetype: '<collection />',
+			resourcetype: COLLECTION_RESOURCETYPE,
 		};
 	}
 
+	let is_dir = is_collection(object);
+	let content_language = object.httpMetadata?.contentLanguage;
+	// Files stored without a type get the one of their extension, collections have none
+	let content_type = is_dir ? undefined : object.httpMetadata?.contentType ?? guess_content_type(path);
 	return {
-		creationdate: object.uploaded.toUTCString(),
-		displayname: object.httpMetadata?.contentDisposition,
-		getcontentlanguage: object.httpMetadata?.contentLanguage,
-		getcontentlength: object.size.toString(),
-		getcontenttype: object.httpMetadata?.contentType,
-		getetag: object.etag,
+		creationdate: object.uploaded.toISOString(),
+		displayname: displayname,
+		getcontentlanguage: content_language === undefined ? undefined : escape_xml(content_language),
+		getcontentlength: is_dir ? undefined : object.size.toString(),
+		getcontenttype: content_type === undefined ? undefined : escape_xml(content_type),
+		getetag: make_etag(env, object),
 		getlastmodified: object.uploaded.toUTCString(),
-		resourcetype: object.customMetadata?.resourcetype ?? '',
+		resourcetype: is_dir ? COLLECTION_RESOURCETYPE : '',
 	};
 }
 
+// The bucket key of a URL path. Segments are percent-decoded, empty and "." ones are dropped and ".." ones
+// resolved. Null when the path climbs above the root, or when a segment decodes to a separator or NUL.
+function normalize_path(pathname: string): string | null {
+	let segments: string[] = [];
+	for (let segment of pathname.split(/[\/\\]/)) {
+		let decoded: string;
+		try {
+			decoded = decodeURIComponent(segment);
+		} catch {
+			return null;
+		}
+		if (/[\/\\\0]/.test(decoded)) {
+			return null;
+		} else if (decoded === '..') {
+			if (segments.length === 0) {
+				return null;
+			}
+			segments.pop();
+		} else if (decoded !== '' && decoded !== '.') {
+			segments.push(decoded);
+		}
+	}
+	return segments.join('/');
+}
+
 function make_resource_path(request: Request): string {
-	let path = new URL(request.url).pathname.slice(1);
-	path = path.endsWith('/') ? path.slice(0, -1) : path;
-	return path;
+	// dispatch_handler refuses the requests whose path doesn't normalize
+	return normalize_path(new URL(request.url).pathname) ?? '';
+}
+
+// Paths ending in a slash name collections, the query string doesn't count
+function has_trailing_slash(request: Request): boolean {
+	return new URL(request.url).pathname.endsWith('/');
+}
+
+// The href of a bucket key or prefix, percent-encoded segment by segment
+function encode_href(path: string): string {
+	return '/' + path.split('/').map(encodeURIComponent).join('/');
+}
+
+// One of the macOS files HIDE_APPLE_FILES keeps out of the bucket, given as a path, object or listed prefix
+function is_hidden(env: Env, entry: R2Object | string): boolean {
+	let path = typeof entry === 'string' ? entry : entry.key;
+	return is_flag_set(env.HIDE_APPLE_FILES) && is_hidden_file(path, env.HIDDEN_FILE_PATTERNS);
+}
+
+// The ETag of an object as clients see it, in headers and getetag alike. If-Match compares strongly,
+// weak ETags never satisfy it.
+function make_etag(env: Env, object: R2Object): string {
+	return is_flag_set(env.WEAK_ETAGS) ? `W/${object.httpEtag}` : object.httpEtag;
+}
+
+function make_object_headers(env: Env, object: R2Object): Headers {
+	let headers = new Headers({
+		'Content-Type': object.httpMetadata?.contentType ?? 'application/octet-stream',
+		'Content-Length': object.size.toString(),
+		'Last-Modified': object.uploaded.toUTCString(),
+		ETag: make_etag(env, object),
+		'Accept-Ranges': 'bytes',
+	});
+	if (object.httpMetadata?.contentDisposition) {
+		headers.set('Content-Disposition', object.httpMetadata.contentDisposition);
+	}
+	if (object.httpMetadata?.contentEncoding) {
+		headers.set('Content-Encoding', object.httpMetadata.contentEncoding);
+	}
+	if (object.httpMetadata?.contentLanguage) {
+		headers.set('Content-Language', object.httpMetadata.contentLanguage);
+	}
+	if (object.httpMetadata?.cacheControl) {
+		headers.set('Cache-Control', object.httpMetadata.cacheControl);
+	} else if (env.CACHE_CONTROL) {
+		headers.set('Cache-Control', env.CACHE_CONTROL);
+	}
+	if (object.httpMetadata?.cacheExpiry) {
+		headers.set('Expires', object.httpMetadata.cacheExpiry.toUTCString());
+	}
+	return headers;
+}
+
+// The runtime compresses bodies in the Content-Encoding of their response. Objects uploaded with one
+// are stored that way, they must not be compressed again on the way out.
+function encode_body(object: R2Object): ResponseInit['encodeBody'] {
+	return object.httpMetadata?.contentEncoding ? 'manual' : 'automatic';
+}
+
+// Compresses a whole object download when COMPRESSION_ENABLED is set and the client accepts it, in brotli
+// or gzip. Empty objects are sent as they are, with "Content-Length: 0", compressing only makes them bigger.
+function compress_body(request: Request, env: Env, object: R2ObjectBody, headers: Headers) {
+	if (
+		!is_flag_set(env.COMPRESSION_ENABLED) ||
+		object.size === 0 ||
+		object.httpMetadata?.contentEncoding ||
+		!is_compressible(object.httpMetadata?.contentType, env.COMPRESSIBLE_TYPES)
+	) {
+		return;
+	}
+	headers.append('Vary', 'Accept-Encoding');
+	let encoding = negotiate_encoding(request.headers.get('Accept-Encoding'));
+	if (encoding === null) {
+		return;
+	}
+	// The compressed bytes differ from the stored ones, and their length isn't known yet
+	headers.set('Content-Encoding', encoding);
+	headers.set('ETag', `W/${object.httpEtag}`);
+	headers.delete('Content-Length');
 }
 
-async function handle_head(request: Request, bucket: R2Bucket): Promise<Response> {
-	let response = await handle_get(request, bucket);
+function without_body(response: Response): Response {
 	return new Response(null, {
 		status: response.status,
 		statusText: response.statusText,
@@ -90,708 +486,2730 @@ async function handle_head(request: Request, bucket: R2Bucket): Promise<Response
 	});
 }
 
-async function handle_get(request: Request, bucket: R2Bucket): Promise<Response> {
+async function handle_head(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	// Only the metadata is needed, don't fetch the body. Listings are only made by GET though, their
+	// HEAD is the GET without the body, so both always agree.
 	let resource_path = make_resource_path(request);
-
-	if (request.url.endsWith('/')) {
-		let page = '',
-			prefix = resource_path;
-		if (resource_path !== '') {
-			page += `<a href="../">..</a><br>`;
-			prefix = `${resource_path}/`;
+	let object = has_trailing_slash(request) ? null : await bucket.head(resource_path);
+	if (has_trailing_slash(request) || (prefers_html(request) && is_collection(object))) {
+		return without_body(await handle_get(request, bucket, env));
+	} else if (
+		object === null &&
+		resource_path !== '' &&
+		(await is_collection_path(bucket, env, resource_path, object))
+	) {
+		// A folder without a marker has no object to describe, it gets the headers of its listing
+		let listing = new Request(new URL(encode_href(resource_path) + '/', request.url), request);
+		return without_body(await handle_get(listing, bucket, env));
+	} else if (object === null) {
+		return await get_language_variant(request, bucket, env, resource_path, handle_head);
+	}
+	let unmet = check_head_conditions(request, env, object);
+	if (unmet !== null) {
+		return unmet;
+	}
+	let headers = make_object_headers(env, object);
+
+	// A ranged HEAD gets the headers of the GET, so download managers learn ranges are supported.
+	// The multipart body of several ranges is only worked out by GET, their HEAD describes the whole object.
+	let requested_ranges = parse_ranges(request.headers.get('Range'));
+	if (requested_ranges !== null && requested_ranges.length <= MAX_RANGES && if_range_matches(request, env, object)) {
+		let size = object.size;
+		let ranges = requested_ranges
+			.map((requested) => resolve_range(requested, size))
+			.filter(({ rangeOffset, rangeEnd }) => rangeOffset <= rangeEnd);
+		if (ranges.length === 0) {
+			return new Response(null, { status: 416, headers: { 'Content-Range': `bytes */${size}` } });
+		} else if (ranges.length === 1) {
+			let { rangeOffset, rangeEnd } = ranges[0];
+			headers.set('Content-Length', (rangeEnd - rangeOffset + 1).toString());
+			headers.set('Content-Range', `bytes ${rangeOffset}-${rangeEnd}/${size}`);
+			return new Response(null, { status: 206, headers });
 		}
+	}
+	return new Response(null, {
+		status: 200,
+		headers: headers,
+	});
+}
 
-		for await (const object of listAll(bucket, prefix)) {
-			if (object.key === resource_path) {
-				continue;
-			}
-			let href = `/${object.key + (object.customMetadata?.resourcetype === '<collection />' ? '/' : '')}`;
-			page += `<a href="${href}">${object.httpMetadata?.contentDisposition ?? object.key.slice(prefix.length)}</a><br>`;
-		}
-		// 定义模板
-		var pageSource = `<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8"><meta name="viewport" content="width=device-width,initial-scale=1.0"><title>R2Storage</title><style>*{box-sizing:border-box;}body{padding:10px;font-family:'Segoe UI','Circular','Roboto','Lato','Helvetica Neue','Arial Rounded MT Bold','sans-serif';}a{display:inline-block;width:100%;color:#000;text-decoration:none;padding:5px 10px;cursor:pointer;border-radius:5px;}a:hover{background-color:#60C590;color:white;}a[href="../"]{background-color:#cbd5e1;}</style></head><body><h1>R2 Storage</h1><div>${page}</div></body></html>`;
+async function handle_get(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	let resource_path = make_resource_path(request);
 
-		return new Response(pageSource, {
-			status: 200,
-			headers: { 'Content-Type': 'text/html; charset=utf-8' },
-		});
+	if (has_trailing_slash(request) && new URL(request.url).searchParams.get('format') === 'json') {
+		return await generate_json_listing(bucket, env, resource_path);
+	} else if (has_trailing_slash(request) && is_untranslated(request)) {
+		// A collection has no content of its own to send, only its listing is made up
+		return method_not_allowed(env, ['GET', 'HEAD']);
+	} else if (has_trailing_slash(request)) {
+		// With INDEX_DOCUMENT, a folder holding one is served like a static site
+		let index = env.INDEX_DOCUMENT && (resource_path === '' ? '' : resource_path + '/') + env.INDEX_DOCUMENT;
+		if (index && (await bucket.head(index)) !== null) {
+			return await handle_get(new Request(new URL(encode_href(index), request.url), request), bucket, env);
+		}
+		return await generate_index(request, bucket, env, resource_path);
+	} else if (new URL(request.url).searchParams.has('presign')) {
+		return await generate_signed_link(request, bucket, env, resource_path);
+	} else if (prefers_html(request) && is_collection(await bucket.head(resource_path))) {
+		// A browser opening a collection without the trailing slash. The marker itself is served at the same
+		// URL to everyone else, so the response mustn't be cached for them.
+		let response = await generate_index(request, bucket, env, resource_path);
+		response.headers.set('Vary', 'Accept');
+		return response;
 	} else {
-		let object = await bucket.get(resource_path, {
-			onlyIf: request.headers,
-			range: request.headers,
-		});
-
-		let isR2ObjectBody = (object: R2Object | R2ObjectBody): object is R2ObjectBody => {
-			return 'body' in object;
-		};
+		let requested_ranges = parse_ranges(request.headers.get('Range'));
+		// Too many ranges are ignored and the whole object is served instead
+		if (requested_ranges !== null && requested_ranges.length > 1 && requested_ranges.length <= MAX_RANGES) {
+			return handle_ranges_get(request, bucket, env, resource_path, requested_ranges);
+		}
+		let range = requested_ranges?.length === 1 ? requested_ranges[0] : undefined;
+
+		let object: R2Object | null;
+		try {
+			object = await bucket.get(resource_path, { onlyIf: make_etag_conditions(request), range });
+		} catch (error) {
+			// R2 refuses to read a range starting past the end, which is a 416 once the conditions are met
+			if (range === undefined) {
+				throw error;
+			}
+			return handle_ranges_get(request, bucket, env, resource_path, [range], error);
+		}
 
 		if (object === null) {
-			return new Response('Not Found', { status: 404 });
+			return await get_language_variant(request, bucket, env, resource_path);
 		} else if (!isR2ObjectBody(object)) {
-			return new Response('Precondition Failed', { status: 412 });
-		} else {
-			const { rangeOffset, rangeEnd } = calcContentRange(object);
-			const contentLength = rangeEnd - rangeOffset + 1;
+			return failed_condition_response(request, env, object);
+		}
+		let unmet = await check_read_conditions(request, env, object);
+		if (unmet !== null) {
+			return unmet;
+		} else if (range === undefined) {
+			let headers = make_object_headers(env, object);
+			compress_body(request, env, object, headers);
 			return new Response(object.body, {
-				status: object.range && contentLength !== object.size ? 206 : 200,
-				headers: {
-					'Content-Type': object.httpMetadata?.contentType ?? 'application/octet-stream',
-					'Content-Length': contentLength.toString(),
-					...{ 'Content-Range': `bytes ${rangeOffset}-${rangeEnd}/${object.size}` },
-					...(object.httpMetadata?.contentDisposition
-						? {
-							'Content-Disposition': object.httpMetadata.contentDisposition,
-						}
-						: {}),
-					...(object.httpMetadata?.contentEncoding
-						? {
-							'Content-Encoding': object.httpMetadata.contentEncoding,
-						}
-						: {}),
-					...(object.httpMetadata?.contentLanguage
-						? {
-							'Content-Language': object.httpMetadata.contentLanguage,
-						}
-						: {}),
-					...(object.httpMetadata?.cacheControl
-						? {
-							'Cache-Control': object.httpMetadata.cacheControl,
-						}
-						: {}),
-					...(object.httpMetadata?.cacheExpiry
-						? {
-							'Cache-Expiry': object.httpMetadata.cacheExpiry.toISOString(),
-						}
-						: {}),
-				},
+				status: 200,
+				headers: headers,
+				encodeBody: encode_body(object),
 			});
+		} else if (!if_range_matches(request, env, object)) {
+			// The part the client has is outdated, it gets the whole object instead
+			await object.body.cancel();
+			return await handle_get(without_range(request), bucket, env);
 		}
-	}
-}
 
-function calcContentRange(object: R2ObjectBody) {
-	let rangeOffset = 0;
-	let rangeEnd = object.size - 1;
-	if (object.range) {
-		if ('suffix' in object.range) {
-			// Case 3: {suffix: number}
-			rangeOffset = object.size - object.range.suffix;
-		} else {
-			// Case 1: {offset: number, length?: number}
-			// Case 2: {offset?: number, length: number}
-			rangeOffset = object.range.offset ?? 0;
-			let length = object.range.length ?? object.size - rangeOffset;
-			rangeEnd = Math.min(rangeOffset + length - 1, object.size - 1);
+		// Content-Range describes the bytes actually served, which R2 may have clamped to the object size
+		const { rangeOffset, rangeEnd } = calcContentRange(object);
+		if (rangeEnd < rangeOffset) {
+			await object.body.cancel();
+			return range_not_satisfiable(object.size);
 		}
+		const contentLength = rangeEnd - rangeOffset + 1;
+		let headers = make_object_headers(env, object);
+		headers.set('Content-Length', contentLength.toString());
+		headers.set('Content-Range', `bytes ${rangeOffset}-${rangeEnd}/${object.size}`);
+		return new Response(object.body, {
+			status: 206,
+			headers: headers,
+			encodeBody: encode_body(object),
+		});
 	}
-	return { rangeOffset, rangeEnd };
 }
 
-async function handle_put(request: Request, bucket: R2Bucket): Promise<Response> {
-	if (request.url.endsWith('/')) {
-		return new Response('Method Not Allowed', { status: 405 });
-	}
+// The request without its Range header, for serving the whole object
+function without_range(request: Request): Request {
+	let headers = new Headers(request.headers);
+	headers.delete('Range');
+	return new Request(request, { headers });
+}
 
-	let resource_path = make_resource_path(request);
+function range_not_satisfiable(size: number): Response {
+	return new Response('Range Not Satisfiable', {
+		status: 416,
+		headers: { 'Content-Range': `bytes */${size}` },
+	});
+}
 
-	// Check if the parent directory exists
-	let dirpath = resource_path.split('/').slice(0, -1).join('/');
-	if (dirpath !== '') {
-		let dir = await bucket.head(dirpath);
-		if (!(dir && dir.customMetadata?.resourcetype === '<collection />')) {
-			return new Response('Conflict', { status: 409 });
+// Ranges that need the size of the object up front: several of them, each read on its own, or one R2
+// refused to read. The conditions are answered first, only then is it a 416 when nothing can be served.
+async function handle_ranges_get(
+	request: Request,
+	bucket: R2Bucket,
+	env: Env,
+	resource_path: string,
+	requested_ranges: R2Range[],
+	read_error?: unknown,
+): Promise<Response> {
+	let head = await bucket.head(resource_path);
+	if (head === null) {
+		return await get_language_variant(request, bucket, env, resource_path);
+	} else if (read_condition_status(request, head) !== null) {
+		return failed_condition_response(request, env, head);
+	} else if (!if_range_matches(request, env, head)) {
+		return await handle_get(without_range(request), bucket, env);
+	}
+	let size = head.size;
+	let ranges = requested_ranges
+		.map((requested) => resolve_range(requested, size))
+		.filter(({ rangeOffset, rangeEnd }) => rangeOffset <= rangeEnd);
+	if (ranges.length === 0) {
+		return range_not_satisfiable(size);
+	} else if (read_error !== undefined) {
+		throw read_error;
+	} else if (ranges.length === 1) {
+		// Only one of the ranges can be served, it's sent like a single range
+		let headers = new Headers(request.headers);
+		headers.set('Range', `bytes=${ranges[0].rangeOffset}-${ranges[0].rangeEnd}`);
+		return await handle_get(new Request(request, { headers }), bucket, env);
+	}
+	// R2 reads a single range at a time, so every part is a read of its own
+	return handle_multirange_get(request, bucket, env, resource_path, ranges);
+}
+
+// With LANGUAGE_NEGOTIATION, a missing file is served as the language variant the client prefers,
+// like page.fr.html for page.html, or the DEFAULT_LANGUAGE one when it accepts none of them.
+// The variant is served by handler, a HEAD gets the headers of the GET.
+async function get_language_variant(
+	request: Request,
+	bucket: R2Bucket,
+	env: Env,
+	resource_path: string,
+	handler: Handler = handle_get,
+): Promise<Response> {
+	if (!is_flag_set(env.LANGUAGE_NEGOTIATION) || resource_path === '') {
+		throw new DavError(404, 'Not Found');
+	}
+	let segments = resource_path.split('/');
+	let file = segments.pop()!;
+	let prefix = segments.map((segment) => segment + '/').join('');
+	let variants = new Map<string, string>();
+	for await (let entry of list_dir(bucket, env, prefix + file.split('.')[0] + '.')) {
+		if (typeof entry === 'string' || is_collection(entry)) {
+			continue;
 		}
+		let language = variant_language(file, entry.key.slice(prefix.length));
+		if (language !== null) {
+			variants.set(language, entry.key);
+		}
+	}
+	let languages = [...variants.keys()];
+	let language = negotiate_language(request.headers.get('Accept-Language'), languages);
+	let default_language = env.DEFAULT_LANGUAGE?.toLowerCase();
+	language ??= languages.find((available) => available.toLowerCase() === default_language) ?? null;
+	if (language === null) {
+		throw new DavError(404, 'Not Found');
 	}
 
-	let body = await request.arrayBuffer();
-	await bucket.put(resource_path, body, {
-		onlyIf: request.headers,
-		httpMetadata: request.headers,
-	});
-	return new Response('', { status: 201 });
+	let variant = new Request(new URL(encode_href(variants.get(language)!), request.url), request);
+	let response = await handler(variant, bucket, env);
+	if (!response.headers.has('Content-Language')) {
+		response.headers.set('Content-Language', language);
+	}
+	response.headers.append('Vary', 'Accept-Language');
+	return response;
 }
 
-async function handle_delete(request: Request, bucket: R2Bucket): Promise<Response> {
-	let resource_path = make_resource_path(request);
+// The Windows Mini-Redirector sends "Translate: f" to get a resource itself rather than a page made of it
+function is_untranslated(request: Request): boolean {
+	return request.headers.get('Translate')?.trim().toLowerCase() === 'f';
+}
 
-	if (resource_path === '') {
-		let r2_objects,
-			cursor: string | undefined = undefined;
-		do {
-			r2_objects = await bucket.list({ cursor: cursor });
-			let keys = r2_objects.objects.map((object) => object.key);
-			if (keys.length > 0) {
-				await bucket.delete(keys);
-			}
+// Browsers ask for HTML first, WebDAV clients don't ask for it at all. The Mini-Redirector may, but
+// it doesn't want a listing then.
+function prefers_html(request: Request): boolean {
+	if (is_untranslated(request)) {
+		return false;
+	}
+	return (request.headers.get('Accept') ?? '').split(',').some((range) => {
+		let [type, ...parameters] = range.split(';').map((part) => part.trim().toLowerCase());
+		return type === 'text/html' && !parameters.some((parameter) => /^q=0(\.0*)?$/.test(parameter));
+	});
+}
 
-			if (r2_objects.truncated) {
-				cursor = r2_objects.cursor;
-			}
-		} while (r2_objects.truncated);
+function format_size(size: number): string {
+	let units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+	let unit = 0;
+	while (size >= 1024 && unit < units.length - 1) {
+		size /= 1024;
+		unit++;
+	}
+	return unit === 0 ? `${size} B` : `${size.toFixed(1)} ${units[unit]}`;
+}
 
-		return new Response(null, { status: 204 });
+// The HTML listing of the collection at resource_path
+async function generate_index(request: Request, bucket: R2Bucket, env: Env, resource_path: string): Promise<Response> {
+	let page = '',
+		next = '',
+		prefix = resource_path;
+	if (resource_path !== '') {
+		let parent = resource_path.split('/').slice(0, -1).join('/');
+		let parent_href = parent === '' ? '/' : encode_href(parent) + '/';
+		page += `<tr class="parent"><td><a href="${parent_href}">..</a></td><td></td><td></td></tr>`;
+		prefix = `${resource_path}/`;
 	}
 
-	let resource = await bucket.head(resource_path);
-	if (resource === null) {
-		return new Response('Not Found', { status: 404 });
+	// Big directories are shown in pages, ?limit= sets their size and ?cursor= continues the listing
+	let url = new URL(request.url);
+	let listing = await list_page(bucket, env, prefix, {
+		limit: Number(url.searchParams.get('limit')) || undefined,
+		cursor: url.searchParams.get('cursor') ?? undefined,
+	});
+	for (const entry of listing.entries.filter((entry) => !is_hidden(env, entry))) {
+		let href = typeof entry === 'string' ? encode_href(entry) : make_href(entry);
+		let name =
+			typeof entry === 'string'
+				? entry.slice(prefix.length)
+				: entry.httpMetadata?.contentDisposition ?? entry.key.slice(prefix.length);
+		// Prefixes without a marker have neither a size nor a date
+		let size = typeof entry === 'string' || is_collection(entry) ? '' : format_size(entry.size);
+		let modified = typeof entry === 'string' ? '' : entry.uploaded.toUTCString();
+		page += `<tr><td><a href="${href}">${escape_xml(name)}</a></td><td>${size}</td><td>${modified}</td></tr>`;
 	}
-	await bucket.delete(resource_path);
-	if (resource.customMetadata?.resourcetype !== '<collection />') {
-		return new Response(null, { status: 204 });
+	if (listing.cursor !== undefined) {
+		url.searchParams.set('cursor', listing.cursor);
+		next = `<p><a href="${escape_xml(url.pathname + url.search)}">Next page</a></p>`;
 	}
+	// 定义模板
+	var pageSource = `<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8"><meta name="viewport" content="width=device-width,initial-scale=1.0"><title>R2Storage</title><style>*{box-sizing:border-box;}body{padding:10px;font-family:'Segoe UI','Circular','Roboto','Lato','Helvetica Neue','Arial Rounded MT Bold','sans-serif';}table{width:100%;border-collapse:collapse;}td{padding:5px 10px;white-space:nowrap;}td:first-child{width:100%;}tr:hover{background-color:#60C590;}tr:hover a{color:white;}a{color:#000;text-decoration:none;}tr.parent{background-color:#cbd5e1;}</style></head><body><h1>R2 Storage</h1><table>${page}</table>${next}</body></html>`;
 
-	let r2_objects,
-		cursor: string | undefined = undefined;
-	do {
-		r2_objects = await bucket.list({
-			prefix: resource_path + '/',
-			cursor: cursor,
-		});
-		let keys = r2_objects.objects.map((object) => object.key);
-		if (keys.length > 0) {
-			await bucket.delete(keys);
-		}
+	return new Response(pageSource, {
+		status: 200,
+		headers: { 'Content-Type': 'text/html; charset=utf-8' },
+	});
+}
 
-		if (r2_objects.truncated) {
-			cursor = r2_objects.cursor;
-		}
-	} while (r2_objects.truncated);
+type JsonEntry = {
+	name: string;
+	size: number | null;
+	modified: string | null;
+	etag: string | null;
+	is_dir: boolean;
+};
 
-	return new Response(null, { status: 204 });
+// The members of the collection at resource_path as JSON, for clients that don't speak WebDAV.
+// Collections have no size, prefixes without a marker have no date and etag either.
+async function generate_json_listing(bucket: R2Bucket, env: Env, resource_path: string): Promise<Response> {
+	let prefix = resource_path === '' ? '' : resource_path + '/';
+	let entries: JsonEntry[] = [];
+	for await (let entry of list_dir(bucket, env, prefix)) {
+		if (is_hidden(env, entry)) {
+			continue;
+		} else if (typeof entry === 'string') {
+			entries.push({ name: entry.slice(prefix.length, -1), size: null, modified: null, etag: null, is_dir: true });
+		} else {
+			let is_dir = is_collection(entry);
+			entries.push({
+				name: entry.key.slice(prefix.length),
+				size: is_dir ? null : entry.size,
+				modified: entry.uploaded.toISOString(),
+				etag: make_etag(env, entry),
+				is_dir,
+			});
+		}
+	}
+	return new Response(JSON.stringify(entries), {
+		status: 200,
+		headers: { 'Content-Type': 'application/json' },
+	});
 }
 
-async function handle_mkcol(request: Request, bucket: R2Bucket): Promise<Response> {
-	// Stupid Windows Explorer carries the body, we have to support it.
-	// So dont check for request.body.
-	// if (request.body) {
-	// 	return new Response('Unsupported Media Type', { status: 415 });
-	// }
-
-	let resource_path = make_resource_path(request);
+// If-Range holds a strong etag or the Last-Modified date of the version the client has parts of,
+// the range is only served from that version. Weak etags and malformed dates never match, except the
+// ETag sent with WEAK_ETAGS: it only marks R2's strong one weak, and ranges are served from the stored bytes.
+function if_range_matches(request: Request, env: Env, object: R2Object): boolean {
+	let if_range = request.headers.get('If-Range')?.trim();
+	if (if_range === undefined) {
+		return true;
+	} else if (if_range.startsWith('"') || if_range.startsWith('W/')) {
+		return if_range === object.httpEtag || if_range === make_etag(env, object);
+	}
+	return Math.floor(Date.parse(if_range) / 1000) === Math.floor(object.uploaded.getTime() / 1000);
+}
 
-	// Check if the resource already exists
-	let resource = await bucket.head(resource_path);
-	if (resource !== null) {
-		return new Response('Method Not Allowed', { status: 405 });
+// "?presign=<seconds>" answers with a link that downloads the file without credentials for that long
+async function generate_signed_link(
+	request: Request,
+	bucket: R2Bucket,
+	env: Env,
+	resource_path: string,
+): Promise<Response> {
+	if (env.SIGNING_SECRET === undefined) {
+		throw new DavError(501, 'Not Implemented');
+	}
+	let lifetime = Number(new URL(request.url).searchParams.get('presign') || DEFAULT_LINK_LIFETIME);
+	if (!Number.isInteger(lifetime) || lifetime <= 0 || lifetime > MAX_LINK_LIFETIME) {
+		throw new DavError(400, 'Bad Request');
+	}
+	let object = await bucket.head(resource_path);
+	if (object === null || is_collection(object)) {
+		throw new DavError(404, 'Not Found');
 	}
+	let expires = Math.floor(Date.now() / 1000) + lifetime;
+	let link = new URL(encode_href(resource_path), request.url);
+	let scope = user_prefix(env, env.user ?? null);
+	if (scope !== '') {
+		link.searchParams.set('user', env.user!);
+	}
+	link.searchParams.set('expires', expires.toString());
+	link.searchParams.set('signature', await sign_path(env.SIGNING_SECRET, scope + resource_path, expires));
+	return new Response(link.toString(), {
+		status: 200,
+		headers: { 'Content-Type': 'text/plain; charset=utf-8' },
+	});
+}
 
-	// Check if the parent directory exists
-	let parent_dir = resource_path.split('/').slice(0, -1).join('/');
+function isR2ObjectBody(object: R2Object | R2ObjectBody): object is R2ObjectBody {
+	return 'body' in object;
+}
+
+// R2 skipped the body. Only when it's If-None-Match that failed does the client have the current version.
+function failed_condition_response(request: Request, env: Env, object: R2Object): Response {
+	return read_condition_status(request, object) === 304
+		? not_modified(env, object)
+		: new Response('Precondition Failed', { status: 412 });
+}
 
-	if (parent_dir !== '' && !(await bucket.head(parent_dir))) {
-		return new Response('Conflict', { status: 409 });
+// The etag conditions are also left to R2, which settles concurrent writes, see date_condition_status
+// for the others. If-Match compares strongly, its weak tags can never match and are left out. The W/
+// of those of If-None-Match is dropped, R2 compares them strongly but they are the same tags.
+function make_etag_conditions(request: Request): Headers {
+	let headers = new Headers();
+	let if_match = request.headers.get('If-Match');
+	let strong = (if_match ?? '')
+		.split(',')
+		.map((tag) => tag.trim())
+		.filter((tag) => tag !== '' && !tag.startsWith('W/'));
+	if (strong.length > 0) {
+		headers.set('If-Match', strong.join(', '));
+	}
+	let if_none_match = request.headers.get('If-None-Match');
+	if (if_none_match !== null) {
+		headers.set('If-None-Match', if_none_match.split(',').map((tag) => tag.trim().replace(/^W\//, '')).join(', '));
 	}
+	return headers;
+}
 
-	await bucket.put(resource_path, new Uint8Array(), {
-		httpMetadata: request.headers,
-		customMetadata: { resourcetype: '<collection />' },
-	});
-	return new Response('', { status: 201 });
+// HTTP-dates are precise to the second while R2 compares them with the upload time in milliseconds,
+// so If-Unmodified-Since and If-Modified-Since are evaluated here. Malformed dates are ignored, and
+// each is skipped when its etag counterpart is present (RFC 9110 section 13.2.2).
+function date_condition_status(request: Request, object: R2Object): 304 | 412 | null {
+	let uploaded = Math.floor(object.uploaded.getTime() / 1000);
+	let parse_date = (name: string) => Math.floor(Date.parse(request.headers.get(name) ?? '') / 1000);
+	let unmodified_since = parse_date('If-Unmodified-Since');
+	if (!request.headers.has('If-Match') && uploaded > unmodified_since) {
+		return 412;
+	}
+	let modified_since = parse_date('If-Modified-Since');
+	let is_read = request.method === 'GET' || request.method === 'HEAD';
+	if (is_read && !request.headers.has('If-None-Match') && uploaded <= modified_since) {
+		return 304;
+	}
+	return null;
 }
 
-function generate_propfind_response(object: R2Object | null): string {
-	if (object === null) {
-		return `
-	<response>
-		<href>/</href>
-		<propstat>
-			<prop>
-			${Object.entries(fromR2Object(null))
-				.filter(([_, value]) => value !== undefined)
-				.map(([key, value]) => `<${key}>${value}</${key}>`)
-				.join('\n				')}
-			</prop>
-			<status>HTTP/1.1 200 OK</status>
-		</propstat>
-	</response>`;
+// The order of RFC 9110 section 13.2.2: If-Match, or else If-Unmodified-Since, is a 412 before
+// If-None-Match, or else If-Modified-Since, can make a read a 304
+function read_condition_status(request: Request, object: R2Object): 304 | 412 | null {
+	let if_match = request.headers.get('If-Match');
+	if (if_match !== null && !etag_matches_strongly(if_match, object.etag)) {
+		return 412;
+	}
+	let date_status = date_condition_status(request, object);
+	if (date_status === 412) {
+		return 412;
+	} else if (etag_matches(request.headers.get('If-None-Match') ?? '', object.etag)) {
+		return 304;
 	}
+	return date_status;
+}
 
-	let href = `/${object.key + (object.customMetadata?.resourcetype === '<collection />' ? '/' : '')}`;
-	return `
-	<response>
-		<href>${href}</href>
-		<propstat>
-			<prop>
-			${Object.entries(fromR2Object(object))
-			.filter(([_, value]) => value !== undefined)
-			.map(([key, value]) => `<${key}>${value}</${key}>`)
-			.join('\n				')}
-			</prop>
-			<status>HTTP/1.1 200 OK</status>
-		</propstat>
-	</response>`;
+// The conditions are evaluated again once R2 returned a body, for the date ones, the weak tags of If-Match
+// and the "*" wildcard of If-None-Match, which R2 doesn't evaluate for reads
+async function check_read_conditions(request: Request, env: Env, object: R2ObjectBody): Promise<Response | null> {
+	let status = read_condition_status(request, object);
+	if (status === null) {
+		return null;
+	}
+	await object.body.cancel();
+	return status === 304 ? not_modified(env, object) : new Response('Precondition Failed', { status: 412 });
 }
 
-async function handle_propfind(request: Request, bucket: R2Bucket): Promise<Response> {
-	let resource_path = make_resource_path(request);
+// A HEAD gets the same answers to its conditions as a GET would. R2 doesn't evaluate them for a head,
+// so all of them are checked here.
+function check_head_conditions(request: Request, env: Env, object: R2Object): Response | null {
+	let status = read_condition_status(request, object);
+	if (status === null) {
+		return null;
+	}
+	return status === 304 ? not_modified(env, object) : new Response(null, { status: 412 });
+}
 
-	let is_collection: boolean;
-	let page = `<?xml version="1.0" encoding="utf-8"?>
-<multistatus xmlns="DAV:">`;
+type ContentRange = { rangeOffset: number; rangeEnd: number };
+
+// Serves several ranges of one object as a multipart/byteranges body
+async function handle_multirange_get(
+	request: Request,
+	bucket: R2Bucket,
+	env: Env,
+	resource_path: string,
+	ranges: ContentRange[],
+): Promise<Response> {
+	let read_range = ({ rangeOffset, rangeEnd }: ContentRange, onlyIf: R2Conditional | Headers) =>
+		bucket.get(resource_path, { onlyIf, range: { offset: rangeOffset, length: rangeEnd - rangeOffset + 1 } });
+
+	let first = await read_range(ranges[0], make_etag_conditions(request));
+	if (first === null) {
+		throw new DavError(404, 'Not Found');
+	} else if (!isR2ObjectBody(first)) {
+		return failed_condition_response(request, env, first);
+	}
+	let unmet = await check_read_conditions(request, env, first);
+	if (unmet !== null) {
+		return unmet;
+	}
 
-	if (resource_path === '') {
-		page += generate_propfind_response(null);
-		is_collection = true;
-	} else {
-		let object = await bucket.head(resource_path);
-		if (object === null) {
-			return new Response('Not Found', { status: 404 });
+	let object: R2ObjectBody = first;
+	let boundary = crypto.randomUUID();
+	let content_type = object.httpMetadata?.contentType ?? 'application/octet-stream';
+	let encoder = new TextEncoder();
+	let part_headers = ranges.map(({ rangeOffset, rangeEnd }, index) =>
+		encoder.encode(
+			`${index === 0 ? '' : '\r\n'}--${boundary}\r\nContent-Type: ${content_type}\r\n` +
+				`Content-Range: bytes ${rangeOffset}-${rangeEnd}/${object.size}\r\n\r\n`,
+		),
+	);
+	let closing = encoder.encode(`\r\n--${boundary}--\r\n`);
+	let content_length =
+		part_headers.reduce((total, part) => total + part.byteLength, closing.byteLength) +
+		ranges.reduce((total, { rangeOffset, rangeEnd }) => total + rangeEnd - rangeOffset + 1, 0);
+
+	// The parts are requested ahead of the one being written, up to RANGE_CONCURRENCY at a time.
+	// The later ones must come from the same version of the object as the first one.
+	let concurrency = Math.max(Number(env.RANGE_CONCURRENCY ?? DEFAULT_RANGE_CONCURRENCY) || 1, 1);
+	let reads: Promise<R2Object | null>[] = [Promise.resolve(object)];
+	const read_ahead = (index: number) => {
+		while (reads.length < Math.min(index + concurrency, ranges.length)) {
+			let read = read_range(ranges[reads.length], { etagMatches: object.etag });
+			// Failures are handled when the part is awaited, or not at all once an earlier one failed
+			read.catch(() => {});
+			reads.push(read);
 		}
-		is_collection = object.customMetadata?.resourcetype === '<collection />';
-		page += generate_propfind_response(object);
-	}
+	};
 
-	if (is_collection) {
-		let depth = request.headers.get('Depth') ?? 'infinity';
-		switch (depth) {
-			case '0':
-				break;
-			case '1':
-				{
-					let prefix = resource_path === '' ? resource_path : resource_path + '/';
-					for await (let object of listAll(bucket, prefix)) {
-						page += generate_propfind_response(object);
-					}
+	let { readable, writable } = new FixedLengthStream(content_length);
+	let write_parts = async () => {
+		let writer = writable.getWriter();
+		try {
+			for (let index = 0; index < ranges.length; index++) {
+				read_ahead(index);
+				let part = await reads[index];
+				if (part === null || !isR2ObjectBody(part)) {
+					throw new Error(`/${resource_path} changed while its ranges were being read`);
 				}
-				break;
-			case 'infinity':
-				{
-					let prefix = resource_path === '' ? resource_path : resource_path + '/';
-					for await (let object of listAll(bucket, prefix, true)) {
-						page += generate_propfind_response(object);
+				await writer.write(part_headers[index]);
+				let reader = part.body.getReader();
+				while (true) {
+					let { done, value } = await reader.read();
+					if (done) {
+						break;
 					}
+					await writer.write(value);
 				}
-				break;
-			default: {
-				return new Response('Forbidden', { status: 403 });
 			}
+			await writer.write(closing);
+			await writer.close();
+		} catch (error) {
+			logger.error('Failed to serve ranges', { path: resource_path, error });
+			await writer.abort(error);
 		}
-	}
+	};
+	// The parts are written as the response is sent and nothing awaits them, an abort that fails is only logged
+	write_parts().catch((error) => logger.error('Failed to abort the ranges', { path: resource_path, error }));
 
-	page += '\n</multistatus>\n';
-	return new Response(page, {
-		status: 207,
+	let headers = make_object_headers(env, object);
+	headers.set('Content-Type', `multipart/byteranges; boundary=${boundary}`);
+	headers.set('Content-Length', content_length.toString());
+	return new Response(readable, { status: 206, headers, encodeBody: encode_body(object) });
+}
+
+// Weak comparison of an If-None-Match list against an R2 etag, "*" matches any etag
+function etag_matches(header: string, etag: string): boolean {
+	return header
+		.split(',')
+		.map((value) => value.trim())
+		.filter((value) => value !== '')
+		.some((value) => value === '*' || value.replace(/^W\//, '').replace(/^"(.*)"$/, '$1') === etag);
+}
+
+// Strong comparison, for If-Match: a weak tag never matches
+function etag_matches_strongly(header: string, etag: string): boolean {
+	return header
+		.split(',')
+		.map((value) => value.trim())
+		.some((value) => value === '*' || (!value.startsWith('W/') && value.replace(/^"(.*)"$/, '$1') === etag));
+}
+
+function not_modified(env: Env, object: R2Object): Response {
+	return new Response(null, {
+		status: 304,
 		headers: {
-			'Content-Type': 'text/xml',
+			ETag: make_etag(env, object),
+			'Last-Modified': object.uploaded.toUTCString(),
 		},
 	});
 }
 
-async function handle_proppatch(request: Request, bucket: R2Bucket): Promise<Response> {
-	const resource_path = make_resource_path(request);
+function calcContentRange(object: R2ObjectBody): ContentRange {
+	if (object.range) {
+		return resolve_range(object.range, object.size);
+	}
+	return { rangeOffset: 0, rangeEnd: object.size - 1 };
+}
 
-	// 检查资源是否存在
-	let object = await bucket.head(resource_path);
-	if (object === null) {
-		return new Response('Not Found', { status: 404 });
-	}
-
-	// 读取请求体
-	const body = await request.text();
-
-	// 使用 HTMLRewriter 解析 XML
-	const setProperties: { [key: string]: string } = {};
-	const removeProperties: string[] = [];
-	let currentAction: 'set' | 'remove' | null = null;
-	let currentPropName: string | null = null;
-	let currentPropValue: string = '';
-
-	class PropHandler {
-		element(element: Element) {
-			const tagName = element.tagName.toLowerCase();
-			if (tagName === 'set') {
-				currentAction = 'set';
-			} else if (tagName === 'remove') {
-				currentAction = 'remove';
-			} else if (tagName === 'prop') {
-				// 忽略 <prop> 标签
-			} else {
-				// 属性名称
-				currentPropName = tagName;
-				currentPropValue = '';
-			}
-		}
+// The first and last byte a range selects, rangeEnd is below rangeOffset when it selects nothing
+function resolve_range(range: R2Range, size: number): ContentRange {
+	let rangeOffset = 0;
+	let rangeEnd = size - 1;
+	if ('suffix' in range) {
+		// Case 3: {suffix: number}, "bytes=-500" means the last 500 bytes
+		// A suffix longer than the object selects the whole object
+		rangeOffset = Math.max(size - range.suffix, 0);
+	} else {
+		// Case 1: {offset: number, length?: number}
+		// Case 2: {offset?: number, length: number}
+		rangeOffset = range.offset ?? 0;
+		let length = range.length ?? size - rangeOffset;
+		rangeEnd = Math.min(rangeOffset + length - 1, size - 1);
+	}
+	return { rangeOffset, rangeEnd };
+}
 
-		text(textChunk: Text) {
-			if (currentPropName) {
-				currentPropValue += textChunk.text;
-			}
-		}
+// More ranges than this in one request are ignored
+const MAX_RANGES = 8;
+// Reads of those ranges in flight at once, see RANGE_CONCURRENCY
+const DEFAULT_RANGE_CONCURRENCY = 3;
 
-		end(element: Element) {
-			if (currentAction === 'set' && currentPropName) {
-				setProperties[currentPropName] = currentPropValue.trim();
-			} else if (currentAction === 'remove' && currentPropName) {
-				removeProperties.push(currentPropName);
-			}
-			currentPropName = null;
-			currentPropValue = '';
+// The ranges of a "bytes=" Range header, null when there is none or it's malformed
+function parse_ranges(header: string | null): R2Range[] | null {
+	let match = header?.match(/^\s*bytes\s*=(.*)$/i);
+	if (!match) {
+		return null;
+	}
+	let ranges: R2Range[] = [];
+	for (let spec of match[1].split(',').map((spec) => spec.trim())) {
+		let bounds = spec.match(/^(\d*)\s*-\s*(\d*)$/);
+		if (spec === '') {
+			continue;
+		} else if (bounds === null || (bounds[1] === '' && bounds[2] === '')) {
+			return null;
+		} else if (bounds[1] === '') {
+			ranges.push({ suffix: Number(bounds[2]) });
+		} else if (bounds[2] === '') {
+			ranges.push({ offset: Number(bounds[1]) });
+		} else if (Number(bounds[2]) < Number(bounds[1])) {
+			return null;
+		} else {
+			ranges.push({ offset: Number(bounds[1]), length: Number(bounds[2]) - Number(bounds[1]) + 1 });
 		}
 	}
+	return ranges.length > 0 ? ranges : null;
+}
 
-	// 使用 HTMLRewriter 解析请求体
-	await new HTMLRewriter().on('propertyupdate', new PropHandler()).transform(new Response(body)).arrayBuffer();
+// Uploads are unlimited without MAX_UPLOAD_BYTES. One that isn't a size refuses them all rather than none.
+function max_upload_bytes(env: Env): number {
+	let max_bytes = Number(env.MAX_UPLOAD_BYTES ?? Infinity);
+	if (Number.isNaN(max_bytes) || max_bytes < 0) {
+		logger.error('MAX_UPLOAD_BYTES must be a number of bytes');
+		throw new DavError(500, 'Internal Server Error');
+	}
+	return max_bytes;
+}
 
-	// 复制原有的自定义元数据
-	const customMetadata = object.customMetadata ? { ...object.customMetadata } : {};
+async function handle_put(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	if (has_trailing_slash(request)) {
+		return method_not_allowed(env, ['PUT']);
+	}
 
-	// 更新元数据
-	for (const propName in setProperties) {
-		customMetadata[propName] = setProperties[propName];
+	let resource_path = make_resource_path(request);
+	if (is_hidden(env, resource_path)) {
+		// The Finder gives up on a whole copy when one of these fails, so pretend they were stored
+		await request.body?.cancel();
+		return new Response('', { status: 201 });
 	}
 
-	for (const propName of removeProperties) {
-		delete customMetadata[propName];
+	// Check if the parent directory exists, a folder of another tool counts
+	let dirpath = resource_path.split('/').slice(0, -1).join('/');
+	if (dirpath !== '' && !(await is_collection_path(bucket, env, dirpath, await bucket.head(dirpath)))) {
+		throw new DavError(409, 'Conflict');
 	}
 
-	// 更新对象的元数据
-	const src = await bucket.get(object.key);
-	if (src === null) {
-		return new Response('Not Found', { status: 404 });
+	let content_range = request.headers.get('Content-Range');
+	if (content_range !== null) {
+		return handle_chunked_put(request, bucket, env, resource_path, content_range);
 	}
 
-	await bucket.put(object.key, src.body, {
-		httpMetadata: object.httpMetadata,
-		customMetadata: customMetadata,
-	});
+	let max_bytes = max_upload_bytes(env);
+	let content_length = Number(request.headers.get('Content-Length') ?? NaN);
+	if (content_length > max_bytes) {
+		throw new DavError(413, 'Payload Too Large');
+	}
 
-	// 构造响应
-	let responseXML = '<?xml version="1.0" encoding="utf-8"?>\n<multistatus xmlns="DAV:">\n';
-
-	for (const propName in setProperties) {
-		responseXML += `
-    <response>
-        <href>/${object.key}</href>
-        <propstat>
-            <prop>
-                <${propName} />
-            </prop>
-            <status>HTTP/1.1 200 OK</status>
-        </propstat>
-    </response>\n`;
-	}
-
-	for (const propName of removeProperties) {
-		responseXML += `
-    <response>
-        <href>/${object.key}</href>
-        <propstat>
-            <prop>
-                <${propName} />
-            </prop>
-            <status>HTTP/1.1 200 OK</status>
-        </propstat>
-    </response>\n`;
-	}
-
-	responseXML += '</multistatus>';
-
-	return new Response(responseXML, {
-		status: 207,
-		headers: {
-			'Content-Type': 'application/xml; charset="utf-8"',
-		},
-	});
-}
+	// Checked up front so a failing upload isn't read at all. The single put below still passes them
+	// to R2, which settles concurrent create-only puts, a multipart one checks them again at the end.
+	let existing = await bucket.head(resource_path);
+	if (await is_collection_path(bucket, env, resource_path, existing)) {
+		throw new DavError(409, 'Conflict');
+	} else if (write_precondition_failed(request, existing)) {
+		throw new DavError(412, 'Precondition Failed');
+	}
 
-async function handle_copy(request: Request, bucket: R2Bucket): Promise<Response> {
-	let resource_path = make_resource_path(request);
-	let dont_overwrite = request.headers.get('Overwrite') === 'F';
-	let destination_header = request.headers.get('Destination');
-	if (destination_header === null) {
-		return new Response('Bad Request', { status: 400 });
+	let storage_class = make_storage_class(request, env);
+	let content_md5 = request.headers.get('Content-MD5');
+	let md5 = content_md5 === null ? undefined : decode_md5(content_md5);
+	if (md5 === null) {
+		throw new DavError(400, 'Bad Request');
+	}
+	// Everything that can fail is checked before the body is read. A client sending "Expect: 100-continue"
+	// is only asked for the body once it's read, so it gets these errors without sending it.
+	let has_length = Number.isFinite(content_length);
+	if (has_length) {
+		await check_quota(bucket, env, content_length - (existing?.size ?? 0));
 	}
-	let destination = new URL(destination_header).pathname.slice(1);
-	destination = destination.endsWith('/') ? destination.slice(0, -1) : destination;
 
-	// Check if the parent directory exists
-	let destination_parent = destination
-		.split('/')
-		.slice(0, destination.endsWith('/') ? -2 : -1)
-		.join('/');
-	if (destination_parent !== '' && !(await bucket.head(destination_parent))) {
-		return new Response('Conflict', { status: 409 });
+	// A body without a Content-Length is only refused once too much of it has been read. Editors creating
+	// an empty file first may still send a body stream with "Content-Length: 0", there's nothing to read.
+	if (content_length === 0) {
+		await request.body?.cancel();
+	}
+	let stream = request.body === null || content_length === 0 ? null : limit_stream(request.body, max_bytes);
+	if (stream !== null && content_length > multipart_part_size(env)) {
+		let object = await multipart_put(
+			bucket,
+			resource_path,
+			stream,
+			{ httpMetadata: make_http_metadata(request, resource_path), storageClass: storage_class },
+			multipart_part_size(env),
+			(current) => write_precondition_failed(request, current),
+			md5,
+		);
+		return make_put_response(request, env, resource_path, existing, object);
 	}
 
-	// Check if the destination already exists
-	let destination_exists = await bucket.head(destination);
-	if (dont_overwrite && destination_exists) {
-		return new Response('Precondition Failed', { status: 412 });
+	let body = await new Response(stream).arrayBuffer();
+	if (!has_length) {
+		await check_quota(bucket, env, body.byteLength - (existing?.size ?? 0));
+	}
+	// R2 checks the digest as well, comparing it here first tells a corrupted body from other failures
+	if (md5 !== undefined && to_hex(await crypto.subtle.digest('MD5', body)) !== md5) {
+		throw new DavError(400, 'Bad Digest');
+	}
+	let object = await bucket.put(resource_path, body, {
+		onlyIf: make_etag_conditions(request),
+		httpMetadata: make_http_metadata(request, resource_path),
+		storageClass: storage_class,
+		md5,
+		sha256: await crypto.subtle.digest('SHA-256', body),
+	});
+	if (object === null) {
+		throw new DavError(412, 'Precondition Failed');
 	}
+	return make_put_response(request, env, resource_path, existing, object);
+}
 
-	let resource = await bucket.head(resource_path);
-	if (resource === null) {
-		return new Response('Not Found', { status: 404 });
+// 201 with the Location of a new resource, 204 when an existing one was replaced
+function make_put_response(
+	request: Request,
+	env: Env,
+	resource_path: string,
+	existing: R2Object | null,
+	object: R2Object,
+): Response {
+	if (existing !== null) {
+		return new Response(null, { status: 204, headers: { ETag: make_etag(env, object) } });
 	}
+	let location = new URL(encode_href(resource_path), request.url).toString();
+	return new Response('', { status: 201, headers: { ETag: make_etag(env, object), Location: location } });
+}
 
-	let is_dir = resource?.customMetadata?.resourcetype === '<collection />';
+// One chunk of a resumable upload, see upload.ts. Chunks have to arrive in order: one overlapping
+// what was already received is a 409, one leaving a gap a 416. The object appears with the last chunk.
+async function handle_chunked_put(
+	request: Request,
+	bucket: R2Bucket,
+	env: Env,
+	resource_path: string,
+	content_range: string,
+): Promise<Response> {
+	// Without KV there is nowhere to keep the upload, and a partial PUT must not replace the whole object
+	let kv = env.kv;
+	let range = parse_content_range(content_range);
+	let length = range === null ? 0 : range.end - range.start + 1;
+	if (kv === undefined || range === null || request.body === null) {
+		throw new DavError(400, 'Bad Request');
+	}
+	if (Number(request.headers.get('Content-Length')) !== length) {
+		throw new DavError(400, 'Bad Request');
+	} else if (range.total > max_upload_bytes(env)) {
+		throw new DavError(413, 'Payload Too Large');
+	}
 
-	if (is_dir) {
-		let depth = request.headers.get('Depth') ?? 'infinity';
-		switch (depth) {
-			case 'infinity': {
-				let prefix = resource_path + '/';
-				const copy = async (object: R2Object) => {
-					let target = destination + '/' + object.key.slice(prefix.length);
-					target = target.endsWith('/') ? target.slice(0, -1) : target;
-					let src = await bucket.get(object.key);
-					if (src !== null) {
-						await bucket.put(target, src.body, {
-							httpMetadata: object.httpMetadata,
-							customMetadata: object.customMetadata,
-						});
-					}
-				};
-				let promise_array = [copy(resource)];
-				for await (let object of listAll(bucket, prefix, true)) {
-					promise_array.push(copy(object));
-				}
-				await Promise.all(promise_array);
-				if (destination_exists) {
-					return new Response(null, { status: 204 });
-				} else {
-					return new Response('', { status: 201 });
-				}
-			}
-			case '0': {
-				let object = await bucket.get(resource.key);
-				if (object === null) {
-					return new Response('Not Found', { status: 404 });
-				}
-				await bucket.put(destination, object.body, {
-					httpMetadata: object.httpMetadata,
-					customMetadata: object.customMetadata,
-				});
-				if (destination_exists) {
-					return new Response(null, { status: 204 });
+	let upload = await get_upload(kv, resource_path);
+	if (range.start === 0) {
+		let storage_class = make_storage_class(request, env);
+		let existing = await bucket.head(resource_path);
+		if (await is_collection_path(bucket, env, resource_path, existing)) {
+			throw new DavError(409, 'Conflict');
+		} else if (write_precondition_failed(request, existing)) {
+			throw new DavError(412, 'Precondition Failed');
+		}
+		await check_quota(bucket, env, range.total - (existing?.size ?? 0));
+		if (upload !== null) {
+			await bucket
+				.resumeMultipartUpload(resource_path, upload.upload_id)
+				.abort()
+				.catch((error) => logger.warn('Failed to abort the previous upload', { path: resource_path, error }));
+		}
+		let multipart = await bucket.createMultipartUpload(resource_path, {
+			httpMetadata: make_http_metadata(request, resource_path),
+			storageClass: storage_class,
+		});
+		upload = { upload_id: multipart.uploadId, total: range.total, part_size: length, received: 0, parts: [] };
+	} else if (upload === null) {
+		throw new DavError(416, 'Range Not Satisfiable');
+	} else if (range.total !== upload.total) {
+		throw new DavError(400, 'Bad Request');
+	}
+
+	if (range.start < upload.received) {
+		throw new DavError(409, 'Conflict');
+	} else if (range.start > upload.received) {
+		throw new DavError(416, 'Range Not Satisfiable');
+	}
+	let is_last = range.end === range.total - 1;
+	if (is_last ? length > upload.part_size : length !== upload.part_size || length < MIN_PART_SIZE) {
+		throw new DavError(400, 'Bad Request');
+	}
+
+	let multipart = bucket.resumeMultipartUpload(resource_path, upload.upload_id);
+	upload.parts.push(await multipart.uploadPart(upload.parts.length + 1, request.body));
+	upload.received = range.end + 1;
+	if (!is_last) {
+		await put_upload(kv, resource_path, upload);
+		return new Response(null, { status: 202 });
+	}
+	let existing = await bucket.head(resource_path);
+	let object = await multipart.complete(upload.parts);
+	await delete_upload(kv, resource_path);
+	return make_put_response(request, env, resource_path, existing, object);
+}
+
+// The HTTP metadata stored with an upload. Without a meaningful Content-Type, it's guessed from the extension.
+function make_http_metadata(request: Request, resource_path: string): R2HTTPMetadata {
+	let content_type = request.headers.get('Content-Type');
+	if (is_generic_content_type(content_type)) {
+		content_type = guess_content_type(resource_path) ?? content_type;
+	}
+	let expires = Date.parse(request.headers.get('Expires') ?? '');
+	return {
+		contentType: content_type ?? undefined,
+		contentLanguage: request.headers.get('Content-Language') ?? undefined,
+		contentDisposition: request.headers.get('Content-Disposition') ?? undefined,
+		contentEncoding: request.headers.get('Content-Encoding') ?? undefined,
+		cacheControl: request.headers.get('Cache-Control') ?? undefined,
+		cacheExpiry: isNaN(expires) ? undefined : new Date(expires),
+	};
+}
+
+const STORAGE_CLASSES = ['Standard', 'InfrequentAccess'];
+
+// The storage class of an upload, from the X-Storage-Class header or STORAGE_CLASS, an unknown one is a 400
+function make_storage_class(request: Request, env: Env): string {
+	let storage_class = request.headers.get('X-Storage-Class') ?? env.STORAGE_CLASS ?? 'Standard';
+	if (!STORAGE_CLASSES.includes(storage_class)) {
+		throw new DavError(400, 'Bad Request');
+	}
+	return storage_class;
+}
+
+// Preconditions of a write. If-Match needs the resource to exist with one of the listed etags,
+// If-None-Match fails when it exists with one of them, so "If-None-Match: *" only creates.
+// If-Unmodified-Since fails when it was modified later.
+function write_precondition_failed(request: Request, resource: R2Object | null): boolean {
+	let if_match = request.headers.get('If-Match');
+	if (if_match !== null && (resource === null || !etag_matches_strongly(if_match, resource.etag))) {
+		return true;
+	}
+	if (resource !== null && date_condition_status(request, resource) === 412) {
+		return true;
+	}
+	let if_none_match = request.headers.get('If-None-Match');
+	return if_none_match !== null && resource !== null && etag_matches(if_none_match, resource.etag);
+}
+
+// Errors the stream with a 413 once more than max_bytes went through it
+function limit_stream(stream: ReadableStream<Uint8Array>, max_bytes: number): ReadableStream<Uint8Array> {
+	if (max_bytes === Infinity) {
+		return stream;
+	}
+	let received = 0;
+	return stream.pipeThrough(
+		new TransformStream<Uint8Array, Uint8Array>({
+			transform(chunk, controller) {
+				received += chunk.byteLength;
+				if (received > max_bytes) {
+					controller.error(new DavError(413, 'Payload Too Large'));
 				} else {
-					return new Response('', { status: 201 });
+					controller.enqueue(chunk);
 				}
+			},
+		}),
+	);
+}
+
+// Bodies larger than this are uploaded in parts, each part is buffered in memory.
+const DEFAULT_MULTIPART_PART_SIZE = 10 * 1024 * 1024;
+
+// The last part of an upload may still be smaller. A part size R2 refuses would only fail once an upload
+// is under way, uploads are refused before they start then.
+function multipart_part_size(env: Env): number {
+	let part_size = Number(env.MULTIPART_PART_SIZE ?? DEFAULT_MULTIPART_PART_SIZE);
+	if (!(part_size >= MIN_PART_SIZE && part_size <= MAX_PART_SIZE)) {
+		logger.error('MULTIPART_PART_SIZE must be between 5 MiB and 5 GiB', { part_size: env.MULTIPART_PART_SIZE });
+		throw new DavError(500, 'Internal Server Error');
+	}
+	return part_size;
+}
+
+async function* read_chunks(stream: ReadableStream<Uint8Array>, chunk_size: number): AsyncGenerator<Uint8Array> {
+	let reader = stream.getReader();
+	let buffer = new Uint8Array(chunk_size);
+	let filled = 0;
+	while (true) {
+		let { done, value } = await reader.read();
+		if (done) {
+			break;
+		}
+
+		let offset = 0;
+		while (offset < value.byteLength) {
+			let length = Math.min(chunk_size - filled, value.byteLength - offset);
+			buffer.set(value.subarray(offset, offset + length), filled);
+			filled += length;
+			offset += length;
+			if (filled === chunk_size) {
+				yield buffer;
+				buffer = new Uint8Array(chunk_size);
+				filled = 0;
 			}
-			default: {
-				return new Response('Bad Request', { status: 400 });
+		}
+	}
+	if (filled > 0) {
+		yield buffer.subarray(0, filled);
+	}
+}
+
+function to_hex(digest: ArrayBuffer | Uint8Array): string {
+	return [...new Uint8Array(digest)].map((byte) => byte.toString(16).padStart(2, '0')).join('');
+}
+
+// The hex digest of a Content-MD5 header, null when it isn't the base64 of 16 bytes
+function decode_md5(header: string): string | null {
+	try {
+		let digest = Uint8Array.from(atob(header.trim()), (char) => char.charCodeAt(0));
+		return digest.byteLength === 16 ? to_hex(digest) : null;
+	} catch {
+		return null;
+	}
+}
+
+// Multipart uploads can't be given an MD5 or onlyIf. The MD5 is computed while uploading and checked
+// before completing, as are the preconditions, against the object as it is by then.
+async function multipart_put(
+	bucket: R2Bucket,
+	key: string,
+	stream: ReadableStream<Uint8Array>,
+	options: R2MultipartOptions,
+	part_size: number,
+	precondition_failed: (existing: R2Object | null) => boolean,
+	md5?: string,
+): Promise<R2Object> {
+	let upload = await bucket.createMultipartUpload(key, options);
+	try {
+		let digest = new crypto.DigestStream('MD5');
+		let digest_writer = digest.getWriter();
+		let parts: R2UploadedPart[] = [];
+		for await (let chunk of read_chunks(stream, part_size)) {
+			if (md5 !== undefined) {
+				await digest_writer.write(chunk);
 			}
+			parts.push(await upload.uploadPart(parts.length + 1, chunk));
 		}
-	} else {
-		let src = await bucket.get(resource.key);
-		if (src === null) {
-			return new Response('Not Found', { status: 404 });
+		await digest_writer.close();
+		if (md5 !== undefined && to_hex(await digest.digest) !== md5) {
+			throw new DavError(400, 'Bad Digest');
+		} else if (precondition_failed(await bucket.head(key))) {
+			throw new DavError(412, 'Precondition Failed');
 		}
-		await bucket.put(destination, src.body, {
-			httpMetadata: src.httpMetadata,
-			customMetadata: src.customMetadata,
-		});
-		if (destination_exists) {
-			return new Response(null, { status: 204 });
-		} else {
-			return new Response('', { status: 201 });
+		return await upload.complete(parts);
+	} catch (error) {
+		// Don't leave an incomplete upload behind, but a failed abort mustn't hide why the upload failed
+		try {
+			await upload.abort();
+		} catch (abort_error) {
+			logger.warn('Failed to abort an upload', { key, error: abort_error });
+		}
+		throw error;
+	}
+}
+
+// R2 accepts up to 1000 keys per delete call
+const DELETE_BATCH_SIZE = 1000;
+
+// Deletes keys in as few calls as R2 allows and returns the ones that couldn't be deleted
+async function delete_many(bucket: R2Bucket, keys: string[]): Promise<string[]> {
+	let failed: string[] = [];
+	for (let start = 0; start < keys.length; start += DELETE_BATCH_SIZE) {
+		let batch = keys.slice(start, start + DELETE_BATCH_SIZE);
+		try {
+			await bucket.delete(batch);
+		} catch (error) {
+			if (error instanceof BudgetExhausted) {
+				throw error;
+			}
+			logger.error('Failed to delete objects', { count: batch.length, error });
+			failed.push(...batch);
+		}
+	}
+	return failed;
+}
+
+// Deletes every object under prefix and returns the keys that couldn't be deleted
+async function delete_prefix(bucket: R2Bucket, prefix: string): Promise<string[]> {
+	let failed: string[] = [];
+	let keys: string[] = [];
+	for await (let object of listAll(bucket, prefix, true)) {
+		keys.push(object.key);
+		if (keys.length === DELETE_BATCH_SIZE) {
+			failed.push(...(await delete_many(bucket, keys)));
+			keys = [];
 		}
 	}
+	failed.push(...(await delete_many(bucket, keys)));
+	return failed;
+}
+
+// The 207 of a DELETE or MOVE of a collection that went through except for the keys in failed
+function generate_failed_response(failed: string[]): Response {
+	let page = `<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:">`;
+	for (let key of failed) {
+		page += `
+	<response>
+		<href>${encode_href(key)}</href>
+		<status>HTTP/1.1 500 Internal Server Error</status>
+	</response>`;
+	}
+	page += '\n</multistatus>\n';
+	return new Response(page, {
+		status: 207,
+		headers: {
+			'Content-Type': 'text/xml',
+		},
+	});
 }
 
-async function handle_move(request: Request, bucket: R2Bucket): Promise<Response> {
+async function handle_delete(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
 	let resource_path = make_resource_path(request);
-	let overwrite = request.headers.get('Overwrite') === 'T';
-	let destination_header = request.headers.get('Destination');
-	if (destination_header === null) {
-		return new Response('Bad Request', { status: 400 });
+
+	if (resource_path === '') {
+		let failed = await delete_prefix(bucket, '');
+		return failed.length === 0 ? new Response(null, { status: 204 }) : generate_failed_response(failed);
+	}
+
+	let resource = await bucket.head(resource_path);
+	let is_dir = await is_collection_path(bucket, env, resource_path, resource);
+	if (resource === null && !is_dir && is_flag_set(env.DELETE_MISSING_IS_404, true)) {
+		throw new DavError(404, 'Not Found');
+	} else if (resource === null && !is_dir) {
+		// Sync tools treat deleting something already gone as done
+		return new Response(null, { status: 204 });
+	}
+	if (write_precondition_failed(request, resource)) {
+		throw new DavError(412, 'Precondition Failed');
+	}
+	if (!is_dir) {
+		await bucket.delete(resource_path);
+		return new Response(null, { status: 204 });
+	}
+
+	// Delete the members first, the collection itself stays if any of them is left
+	let failed = await delete_prefix(bucket, resource_path + '/');
+	if (failed.length > 0) {
+		return generate_failed_response(failed);
 	}
-	let destination = new URL(destination_header).pathname.slice(1);
-	destination = destination.endsWith('/') ? destination.slice(0, -1) : destination;
+	await bucket.delete(marker_keys(env, resource_path));
+	return new Response(null, { status: 204 });
+}
+
+// Reads no more of a body than it takes to tell whether it has any content, then lets go of it
+async function is_empty_stream(stream: ReadableStream<Uint8Array>): Promise<boolean> {
+	let reader = stream.getReader();
+	try {
+		while (true) {
+			let { done, value } = await reader.read();
+			if (done) {
+				return true;
+			} else if (value.byteLength > 0) {
+				return false;
+			}
+		}
+	} finally {
+		await reader.cancel();
+	}
+}
 
-	// Check if the parent directory exists
-	let destination_parent = destination
-		.split('/')
-		.slice(0, destination.endsWith('/') ? -2 : -1)
-		.join('/');
-	if (destination_parent !== '' && !(await bucket.head(destination_parent))) {
-		return new Response('Conflict', { status: 409 });
+async function handle_mkcol(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	// Windows Explorer sends a body stream, an empty one, so it's the length that counts.
+	// MKCOL bodies with content aren't defined by RFC 4918, none can be handled.
+	if (request.body !== null && !(await is_empty_stream(request.body))) {
+		throw new DavError(415, 'Unsupported Media Type');
 	}
 
-	// Check if the destination already exists
-	let destination_exists = await bucket.head(destination);
-	if (!overwrite && destination_exists) {
-		return new Response('Precondition Failed', { status: 412 });
+	let resource_path = make_resource_path(request);
+
+	// The root collection always exists
+	if (resource_path === '') {
+		return method_not_allowed(env, ['MKCOL']);
 	}
 
+	// Check if the resource already exists, a folder of another tool counts
 	let resource = await bucket.head(resource_path);
-	if (resource === null) {
-		return new Response('Not Found', { status: 404 });
+	if (resource !== null || (await has_members(bucket, env, resource_path))) {
+		return method_not_allowed(env, ['MKCOL']);
 	}
-	if (resource.key === destination) {
-		return new Response('Bad Request', { status: 400 });
+
+	// Check if the parent directory exists, intermediate collections are never created on the way.
+	// A file in its place doesn't count.
+	let parent_dir = resource_path.split('/').slice(0, -1).join('/');
+	if (parent_dir !== '' && !(await is_collection_path(bucket, env, parent_dir, await bucket.head(parent_dir)))) {
+		throw new DavError(409, 'Conflict');
 	}
 
-	if (destination_exists) {
-		// Delete the destination first
-		await handle_delete(new Request(new URL(destination_header), request), bucket);
+	// The request headers describe the (ignored) body, so they aren't kept on the marker
+	await put_collection_marker(bucket, resource_path);
+	return new Response('', { status: 201 });
+}
+
+// Dead properties set by PROPPATCH are kept in the custom metadata, keyed by their
+// percent-encoded {namespace}name so the key stays a valid metadata name
+const DEAD_PROPERTY_PREFIX = 'prop-';
+const DEFAULT_METADATA_NAMESPACE = 'urn:r2-webdav:metadata';
+
+function metadata_namespace(env: Env): string {
+	return env.METADATA_NAMESPACE ?? DEFAULT_METADATA_NAMESPACE;
+}
+
+function dead_property_key(namespace: string, name: string): string {
+	let clark_name = encodeURIComponent(`{${namespace}}${name}`);
+	return DEAD_PROPERTY_PREFIX + clark_name.replace(/[()]/g, (c) => '%' + c.charCodeAt(0).toString(16));
+}
+
+function parse_dead_property_key(key: string): { namespace: string; name: string } | null {
+	if (!key.startsWith(DEAD_PROPERTY_PREFIX)) {
+		return null;
+	}
+	try {
+		let match = decodeURIComponent(key.slice(DEAD_PROPERTY_PREFIX.length)).match(/^\{(.*)\}(.+)$/);
+		return match ? { namespace: match[1], name: match[2] } : null;
+	} catch (error) {
+		return null;
+	}
+}
+
+// Custom metadata keys shown as properties. Those the server keeps for itself, marking collections or
+// holding dead properties, aren't, nor those that aren't valid XML names.
+function is_metadata_name(key: string): boolean {
+	return key !== 'resourcetype' && !key.startsWith(DEAD_PROPERTY_PREFIX) && /^[A-Za-z_][\w.-]*$/.test(key);
+}
+
+// Dead properties set by PROPPATCH, and the custom metadata written by other tools as properties in
+// METADATA_NAMESPACE
+function generate_dead_properties(env: Env, object: R2Object): Property[] {
+	let namespace = metadata_namespace(env);
+	return Object.entries(object.customMetadata ?? {}).flatMap(([key, value]) => {
+		let property = parse_dead_property_key(key);
+		if (property !== null) {
+			return [{ ...property, value }];
+		}
+		return is_metadata_name(key) ? [{ namespace, name: key, value: escape_xml(value) }] : [];
+	});
+}
+
+function make_href(object: R2Object | null): string {
+	if (object === null) {
+		return '/';
 	}
+	return encode_href(object.key + (is_collection(object) ? '/' : ''));
+}
 
-	let is_dir = resource?.customMetadata?.resourcetype === '<collection />';
+// Namespace of the live properties specific to this server
+const R2_NAMESPACE = 'urn:r2-webdav';
 
-	if (is_dir) {
-		let depth = request.headers.get('Depth') ?? 'infinity';
-		switch (depth) {
-			case 'infinity': {
-				let prefix = resource_path + '/';
-				const move = async (object: R2Object) => {
-					let target = destination + '/' + object.key.slice(prefix.length);
-					target = target.endsWith('/') ? target.slice(0, -1) : target;
-					let src = await bucket.get(object.key);
-					if (src !== null) {
-						await bucket.put(target, src.body, {
-							httpMetadata: object.httpMetadata,
-							customMetadata: object.customMetadata,
-						});
-						await bucket.delete(object.key);
-					}
-				};
-				let promise_array = [move(resource)];
-				for await (let object of listAll(bucket, prefix, true)) {
-					promise_array.push(move(object));
-				}
-				await Promise.all(promise_array);
-				if (destination_exists) {
-					return new Response(null, { status: 204 });
-				} else {
-					return new Response('', { status: 201 });
-				}
+// R2 only keeps a SHA-256 when it was given one on upload, so multipart uploads have none
+function generate_checksum_properties(object: R2Object): Property[] {
+	let sha256 = object.checksums?.sha256;
+	return sha256 === undefined ? [] : [{ namespace: R2_NAMESPACE, name: 'sha256', value: to_hex(sha256) }];
+}
+
+// Without an object the entry describes a collection that has no marker, like the root,
+// its path is then taken from the href. The lock properties are only there when LOCK is supported,
+// locks being the ones that may cover the entry.
+function make_propfind_entry(
+	env: Env,
+	object: R2Object | null,
+	href: string = make_href(object),
+	collection_properties: Property[] = [],
+	locks: Lock[] | null = null,
+): PropfindEntry {
+	let path = object?.key ?? decodeURIComponent(href).slice(1).replace(/\/$/, '');
+	let properties: Property[] = Object.entries(fromR2Object(env, object, path)).flatMap(([name, value]) =>
+		value === undefined ? [] : [{ namespace: 'DAV:', name, value }],
+	);
+	if (object !== null) {
+		properties.push(...generate_checksum_properties(object), ...generate_dead_properties(env, object));
+	}
+	if (object !== null && !is_collection(object)) {
+		properties.push({ namespace: R2_NAMESPACE, name: 'storageclass', value: escape_xml(object.storageClass) });
+	}
+	if (object === null || is_collection(object)) {
+		properties.push(...collection_properties);
+	}
+	if (locks !== null) {
+		// A lock held on an ancestor is held on a collection
+		let is_dir = object === null || is_collection(object);
+		let lockdiscovery = locks_covering(locks, path).map((lock) =>
+			generate_activelock(lock, lock_root_href(lock.path, lock.path !== path || is_dir)),
+		);
+		properties.push(
+			{ namespace: 'DAV:', name: 'supportedlock', value: SUPPORTED_LOCKS },
+			{ namespace: 'DAV:', name: 'lockdiscovery', value: lockdiscovery.join('') },
+		);
+	}
+	return { href, properties };
+}
+
+// The body of a PROPFIND, PROPPATCH or LOCK, which must be the DAV: element name. It's a 400 when
+// the body isn't XML and a 422 when it's another element, saying what's wrong.
+function parse_request_xml(body: string, name: string): XmlElement {
+	let element: XmlElement;
+	try {
+		element = parse_xml(body);
+	} catch (error) {
+		throw new DavError(400, `Malformed XML: ${error instanceof Error ? error.message : error}`);
+	}
+	if (element.namespace !== 'DAV:' || element.name !== name) {
+		throw new DavError(422, `Expected a DAV:${name} element`);
+	}
+	return element;
+}
+
+// A DAV:error body, naming the condition that failed when there is one, or else describing the error
+function generate_dav_error(status: number, condition?: string, description?: string): Response {
+	let content = '';
+	if (condition !== undefined) {
+		content = `<${condition}/>`;
+	} else if (description !== undefined) {
+		content = `<responsedescription>${escape_xml(description)}</responsedescription>`;
+	}
+	let error = content === '' ? '<error xmlns="DAV:"/>' : `<error xmlns="DAV:">\n\t${content}\n</error>`;
+	return new Response(
+		`<?xml version="1.0" encoding="utf-8"?>
+${error}
+`,
+		{
+			status: status,
+			headers: {
+				'Content-Type': 'application/xml; charset="utf-8"',
+			},
+		},
+	);
+}
+
+// "quota:<path>" like the lock and upload entries, the path being the root
+const QUOTA_CACHE_KEY = 'quota:';
+const QUOTA_CACHE_TTL = 300;
+
+// The bytes used by the whole bucket. Summing them means listing every object, so when a KV namespace
+// is bound the total is cached for a few minutes and can lag behind recent writes.
+async function get_used_bytes(bucket: R2Bucket, kv: KVNamespace | undefined): Promise<number> {
+	let cached = await kv?.get(QUOTA_CACHE_KEY);
+	if (cached) {
+		return Number(cached);
+	}
+	let used = 0;
+	for await (let object of listAll(bucket, '', true)) {
+		used += object.size;
+	}
+	await kv?.put(QUOTA_CACHE_KEY, used.toString(), { expirationTtl: QUOTA_CACHE_TTL });
+	return used;
+}
+
+// Refuses a write growing the bucket by added_bytes past QUOTA_BYTES, going by the cached usage
+async function check_quota(bucket: R2Bucket, env: Env, added_bytes: number): Promise<void> {
+	if (env.QUOTA_BYTES === undefined || added_bytes <= 0) {
+		return;
+	}
+	if ((await get_used_bytes(bucket, env.kv)) + added_bytes > Number(env.QUOTA_BYTES)) {
+		throw new DavError(507, 'Insufficient Storage', 'quota-not-exceeded');
+	}
+}
+
+const QUOTA_PROPERTIES = ['quota-used-bytes', 'quota-available-bytes'];
+
+// RFC 4331 quota properties, reported for the whole bucket on collections. Like the RFC has it they
+// aren't part of allprop, the usage is only summed when one of them is asked for by name.
+async function generate_quota_properties(bucket: R2Bucket, env: Env, propfind: PropfindRequest): Promise<Property[]> {
+	if (env.QUOTA_BYTES === undefined || propfind.mode === 'allprop') {
+		return [];
+	} else if (propfind.mode === 'propname') {
+		return QUOTA_PROPERTIES.map((name) => ({ namespace: 'DAV:', name, value: '' }));
+	} else if (!propfind.names.some(({ namespace, name }) => namespace === 'DAV:' && QUOTA_PROPERTIES.includes(name))) {
+		return [];
+	}
+	let used = await get_used_bytes(bucket, env.kv);
+	let available = Math.max(Number(env.QUOTA_BYTES) - used, 0);
+	return [
+		{ namespace: 'DAV:', name: 'quota-used-bytes', value: used.toString() },
+		{ namespace: 'DAV:', name: 'quota-available-bytes', value: available.toString() },
+	];
+}
+
+async function handle_propfind(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	let resource_path = make_resource_path(request);
+
+	let depth = (request.headers.get('Depth') ?? 'infinity').toLowerCase();
+	if (depth !== '0' && depth !== '1' && depth !== 'infinity') {
+		throw new DavError(400, 'Bad Request');
+	}
+	let body = await request.text();
+	let propfind = parse_propfind(body.trim() === '' ? null : parse_request_xml(body, 'propfind'));
+	if (propfind === null) {
+		throw new DavError(422, 'Expected allprop, propname or prop in propfind');
+	}
+	let minimal = prefers_minimal(request);
+	if (minimal) {
+		propfind = minimal_propfind(propfind);
+	}
+
+	// A path that is neither an object nor has keys below it doesn't exist, an empty folder has its marker
+	let object = resource_path === '' ? null : await bucket.head(resource_path);
+	if (resource_path !== '' && object === null && !(await has_members(bucket, env, resource_path))) {
+		throw new DavError(404, 'Not Found');
+	}
+	let is_dir = object === null || is_collection(object);
+	let quota = is_dir ? await generate_quota_properties(bucket, env, propfind) : [];
+	let prefix = resource_path === '' ? resource_path : resource_path + '/';
+	let href = object === null && resource_path !== '' ? encode_href(prefix) : make_href(object);
+	// The locks of every entry, looked up once: those covering the resource and those below it
+	let locks: Lock[] | null = null;
+	if (env.kv !== undefined && supports_locking(env)) {
+		locks = await find_locks(env.kv, resource_path);
+		if (is_dir && depth !== '0') {
+			locks.push(...(await find_member_locks(env.kv, resource_path)));
+		}
+	}
+	let root = make_propfind_entry(env, object, href, quota, locks);
+
+	let entries: AsyncIterable<PropfindEntry> | PropfindEntry[] = [root];
+	if (is_dir && depth === '1') {
+		entries = propfind_members(bucket, env, root, prefix, quota, locks);
+	} else if (is_dir && depth === 'infinity') {
+		// Deep listings of big buckets can run into the subrequest budget, or past the limit they're refused.
+		// Either has to be known before the 207 is sent, so deep listings are collected first.
+		let limit = Number(env.PROPFIND_INFINITY_LIMIT ?? Infinity);
+		let collected: PropfindEntry[] = [];
+		for await (let entry of propfind_descendants(bucket, env, root, prefix, quota, locks)) {
+			if (collected.length > limit) {
+				throw new DavError(403, 'Forbidden', 'propfind-finite-depth');
 			}
-			case '0': {
-				let object = await bucket.get(resource.key);
-				if (object === null) {
-					return new Response('Not Found', { status: 404 });
+			collected.push(entry);
+		}
+		entries = collected;
+	}
+
+	let headers = new Headers({ 'Content-Type': 'text/xml', Vary: 'Prefer' });
+	if (minimal) {
+		headers.set('Preference-Applied', 'return=minimal');
+	}
+	return new Response(stream_multistatus(entries, propfind), { status: 207, headers });
+}
+
+// Prefer: return=minimal, among the comma separated preferences of the header
+function prefers_minimal(request: Request): boolean {
+	return (request.headers.get('Prefer') ?? '').split(',').some((preference) => {
+		let [name, value] = preference.split(';')[0].split('=').map((part) => part.trim().toLowerCase());
+		return name === 'return' && value?.replace(/^"(.*)"$/, '$1') === 'minimal';
+	});
+}
+
+// The collection and its direct members, for Depth: 1
+async function* propfind_members(
+	bucket: R2Bucket,
+	env: Env,
+	root: PropfindEntry,
+	prefix: string,
+	quota: Property[],
+	locks: Lock[] | null,
+): AsyncGenerator<PropfindEntry> {
+	yield root;
+	for await (let entry of list_dir(bucket, env, prefix)) {
+		if (is_hidden(env, entry)) {
+			continue;
+		}
+		yield typeof entry === 'string'
+			? make_propfind_entry(env, null, encode_href(entry), quota, locks)
+			: make_propfind_entry(env, entry, make_href(entry), quota, locks);
+	}
+}
+
+// The collection and everything below it, for Depth: infinity
+async function* propfind_descendants(
+	bucket: R2Bucket,
+	env: Env,
+	root: PropfindEntry,
+	prefix: string,
+	quota: Property[],
+	locks: Lock[] | null,
+): AsyncGenerator<PropfindEntry> {
+	yield root;
+	for await (let object of listAll(bucket, prefix, true)) {
+		if (!is_hidden(env, object) && foreign_marker_folder(env, object.key) === null) {
+			yield make_propfind_entry(env, object, make_href(object), quota, locks);
+		}
+	}
+}
+
+const STATUS_TEXT: Record<number, string> = {
+	200: 'OK',
+	403: 'Forbidden',
+	404: 'Not Found',
+	409: 'Conflict',
+	423: 'Locked',
+	424: 'Failed Dependency',
+	500: 'Internal Server Error',
+	507: 'Insufficient Storage',
+};
+
+// R2 can't change the custom metadata of an object in place, the body has to go through the worker
+// and be uploaded again. Past this size PROPPATCH gives up instead, with 507 for every property.
+const DEFAULT_PROPPATCH_MAX_BYTES = 100 * 1024 * 1024;
+
+// R2 refuses custom metadata past 2 KiB, counting the keys and the values. Dead properties that
+// don't fit are a 507 for every property as well, rather than a failed put.
+const MAX_CUSTOM_METADATA_BYTES = 2048;
+
+function custom_metadata_size(customMetadata: Record<string, string>): number {
+	let encoder = new TextEncoder();
+	return Object.entries(customMetadata).reduce(
+		(size, [key, value]) => size + encoder.encode(key).length + encoder.encode(value).length,
+		0,
+	);
+}
+
+async function handle_proppatch(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	const resource_path = make_resource_path(request);
+
+	let object = await bucket.head(resource_path);
+	if (object === null) {
+		throw new DavError(404, 'Not Found');
+	}
+
+	let propertyupdate = parse_request_xml(await request.text(), 'propertyupdate');
+
+	// Instructions are applied in document order
+	let customMetadata = { ...object.customMetadata };
+	let results: { property: XmlElement; status: number }[] = [];
+	for (let instruction of child_elements(propertyupdate)) {
+		if (instruction.namespace !== 'DAV:' || (instruction.name !== 'set' && instruction.name !== 'remove')) {
+			continue;
+		}
+		for (let prop of child_elements(instruction)) {
+			if (prop.namespace !== 'DAV:' || prop.name !== 'prop') {
+				continue;
+			}
+			for (let property of child_elements(prop)) {
+				// Live properties are computed from the object, they can't be changed
+				if (property.namespace === 'DAV:' || property.namespace === R2_NAMESPACE) {
+					results.push({ property, status: 403 });
+					continue;
 				}
-				await bucket.put(destination, object.body, {
-					httpMetadata: object.httpMetadata,
-					customMetadata: object.customMetadata,
-				});
-				await bucket.delete(resource.key);
-				if (destination_exists) {
-					return new Response(null, { status: 204 });
+				// The properties custom metadata is shown as change that metadata, as text
+				let is_metadata = property.namespace === metadata_namespace(env) && is_metadata_name(property.name);
+				let key = is_metadata ? property.name : dead_property_key(property.namespace, property.name);
+				if (instruction.name === 'set' && is_metadata) {
+					customMetadata[key] = text_content(property);
+				} else if (instruction.name === 'set') {
+					customMetadata[key] = property.children.map((child) => serialize_xml(child, property.namespace)).join('');
 				} else {
-					return new Response('', { status: 201 });
+					delete customMetadata[key];
 				}
-			}
-			default: {
-				return new Response('Bad Request', { status: 400 });
+				results.push({ property, status: 200 });
 			}
 		}
-	} else {
-		let src = await bucket.get(resource.key);
+	}
+
+	// PROPPATCH is atomic, when anything fails nothing is applied
+	let max_bytes = Number(env.PROPPATCH_MAX_BYTES ?? DEFAULT_PROPPATCH_MAX_BYTES);
+	if (results.some(({ status }) => status !== 200)) {
+		results = results.map(({ property, status }) => ({ property, status: status === 200 ? 424 : status }));
+	} else if (
+		results.length > 0 &&
+		(object.size > max_bytes || custom_metadata_size(customMetadata) > MAX_CUSTOM_METADATA_BYTES)
+	) {
+		results = results.map(({ property }) => ({ property, status: 507 }));
+	} else if (results.length > 0) {
+		const src = await bucket.get(object.key);
 		if (src === null) {
-			return new Response('Not Found', { status: 404 });
+			throw new DavError(404, 'Not Found');
+		}
+		await bucket.put(
+			object.key,
+			src.body,
+			metadata_only({
+				httpMetadata: object.httpMetadata,
+				customMetadata: customMetadata,
+				storageClass: object.storageClass,
+				sha256: src.checksums.sha256,
+			}),
+		);
+	}
+
+	let propstats = '';
+	for (let status of new Set(results.map(({ status }) => status))) {
+		propstats += `
+		<propstat>
+			<prop>
+				${results
+					.filter((result) => result.status === status)
+					.map(({ property }) => `<${property.name} xmlns="${escape_xml(property.namespace)}"/>`)
+					.join('\n				')}
+			</prop>
+			<status>HTTP/1.1 ${status} ${STATUS_TEXT[status]}</status>
+		</propstat>`;
+	}
+
+	return new Response(
+		`<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:">
+	<response>
+		<href>${make_href(object)}</href>${propstats}
+	</response>
+</multistatus>
+`,
+		{
+			status: 207,
+			headers: {
+				'Content-Type': 'application/xml; charset="utf-8"',
+			},
+		},
+	);
+}
+
+type Destination = {
+	path: string;
+	overwrite: boolean;
+};
+
+// The Destination and Overwrite headers of COPY and MOVE. The destination can be an absolute URL
+// or path, one on another host is a 502 as only this server's bucket can be written to.
+function parse_destination(request: Request): Destination {
+	let header = request.headers.get('Destination');
+	let overwrite = (request.headers.get('Overwrite') ?? 'T').trim().toUpperCase();
+	if (header === null || (overwrite !== 'T' && overwrite !== 'F')) {
+		throw new DavError(400, 'Bad Request');
+	}
+	let url: URL;
+	try {
+		url = new URL(header, request.url);
+	} catch {
+		throw new DavError(400, 'Bad Request');
+	}
+	if (url.host !== new URL(request.url).host) {
+		throw new DavError(502, 'Bad Gateway');
+	}
+	let path = normalize_path(url.pathname);
+	if (path === null) {
+		throw new DavError(403, 'Forbidden');
+	}
+	return { path, overwrite: overwrite === 'T' };
+}
+
+// Where a Destination is written: the secondary bucket when its path is under SECONDARY_PREFIX, at
+// the rest of the path. Copying onto the root of the secondary bucket is forbidden like onto ours.
+function resolve_destination(bucket: R2Bucket, env: Env, path: string): { bucket: R2Bucket; path: string } {
+	let prefix = (env.SECONDARY_PREFIX ?? '').replace(/^\/+|\/+$/g, '');
+	if (env.secondary_bucket === undefined || prefix === '') {
+		return { bucket, path };
+	} else if (path === prefix) {
+		throw new DavError(403, 'Forbidden');
+	} else if (path.startsWith(prefix + '/')) {
+		return { bucket: env.secondary_bucket, path: path.slice(prefix.length + 1) };
+	}
+	return { bucket, path };
+}
+
+// Removes what Overwrite: T replaces. It may already be gone, that isn't an error here.
+async function delete_destination(request: Request, target: R2Bucket, env: Env, destination: string): Promise<void> {
+	try {
+		await handle_delete(new Request(new URL(encode_href(destination), request.url), { method: 'DELETE' }), target, env);
+	} catch (error) {
+		if (!(error instanceof DavError) || error.status !== 404) {
+			throw error;
+		}
+	}
+}
+
+// A resource can't be copied or moved onto itself, nor a collection into itself (RFC 4918 section 9.8.5)
+function is_within(path: string, ancestor: string): boolean {
+	return ancestor === '' || path === ancestor || path.startsWith(ancestor + '/');
+}
+
+async function handle_copy(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	let resource_path = make_resource_path(request);
+	let { path: destination_path, overwrite } = parse_destination(request);
+	let { bucket: target, path: destination } = resolve_destination(bucket, env, destination_path);
+	if (target === bucket && is_within(destination, resource_path)) {
+		throw new DavError(403, 'Forbidden');
+	}
+	let continue_after = parse_continuation(request);
+
+	// Check if the parent directory exists, a file in its place doesn't count
+	let destination_parent = destination.split('/').slice(0, -1).join('/');
+	if (
+		destination_parent !== '' &&
+		!(await is_collection_path(target, env, destination_parent, await target.head(destination_parent)))
+	) {
+		throw new DavError(409, 'Conflict');
+	}
+
+	// Check if the destination already exists, when continuing it's the one written so far
+	let destination_object = await target.head(destination);
+	let replaces_dir = await is_collection_path(target, env, destination, destination_object);
+	let destination_exists = destination_object !== null || replaces_dir;
+	if (!overwrite && destination_exists && continue_after === null) {
+		throw new DavError(412, 'Precondition Failed');
+	}
+
+	// A folder of another tool is copied with a marker of our own
+	let resource = await bucket.head(resource_path);
+	let is_dir = await is_collection_path(bucket, env, resource_path, resource);
+	if (resource === null && !is_dir) {
+		throw new DavError(404, 'Not Found');
+	}
+
+	let depth = is_dir ? request.headers.get('Depth') ?? 'infinity' : '0';
+	if (depth !== '0' && depth !== 'infinity') {
+		throw new DavError(400, 'Bad Request');
+	}
+
+	// Copying with Overwrite: T replaces the destination. The put overwrites a file, the members of a
+	// collection the copy doesn't have are only deleted once it went through.
+	if (depth === 'infinity') {
+		let copy = await copy_collection(
+			bucket,
+			resource_path,
+			resource,
+			target,
+			destination,
+			continue_after,
+			env.budget,
+			() => 0,
+		);
+		let failed = copy.moves.filter((_, index) => copy.results[index] === 'failed').map(({ source }) => source);
+		if (copy.exhausted) {
+			return budget_exhausted_response(copy.continue_after);
+		} else if (failed.length > 0) {
+			return generate_failed_response(failed);
+		}
+	} else if (resource === null) {
+		await put_collection_marker(target, destination);
+	} else if ((await copy_object(bucket, resource.key, target, destination)) === null) {
+		throw new DavError(404, 'Not Found');
+	}
+
+	if (replaces_dir) {
+		let copied = depth === 'infinity' ? resource_path : null;
+		let failed = await delete_replaced_members(env, bucket, copied, target, destination);
+		if (failed.length > 0) {
+			return generate_failed_response(failed);
+		}
+	}
+	if (destination_exists) {
+		return new Response(null, { status: 204 });
+	} else {
+		return new Response('', { status: 201 });
+	}
+}
+
+// Deletes what's left of a collection replaced by a copy, the keys below destination without a counterpart
+// below copied, and returns those that couldn't be. A copy without members, copied is null, leaves none.
+async function delete_replaced_members(
+	env: Env,
+	bucket: R2Bucket,
+	copied: string | null,
+	target: R2Bucket,
+	destination: string,
+): Promise<string[]> {
+	let members = new Set<string>();
+	if (copied !== null) {
+		for await (let object of listAll(bucket, copied + '/', true)) {
+			members.add(object.key.slice(copied.length + 1));
+		}
+	}
+	// The copy was written over our marker, a Hadoop one would still mark the collection
+	let stale = copied === null ? marker_keys(env, destination).filter((key) => key !== destination) : [];
+	for await (let object of listAll(target, destination + '/', true)) {
+		if (!members.has(object.key.slice(destination.length + 1))) {
+			stale.push(object.key);
 		}
-		await bucket.put(destination, src.body, {
+	}
+	return await delete_many(target, stale);
+}
+
+// R2 has no server-side copy, so the body is streamed through the worker.
+// The http and custom metadata of the source are kept as is.
+async function copy_object(
+	bucket: R2Bucket,
+	source: string,
+	target_bucket: R2Bucket,
+	target: string,
+): Promise<R2Object | null> {
+	let src = await bucket.get(source);
+	if (src === null) {
+		return null;
+	}
+	try {
+		return await target_bucket.put(target, src.body, {
 			httpMetadata: src.httpMetadata,
 			customMetadata: src.customMetadata,
+			storageClass: src.storageClass,
+			sha256: src.checksums.sha256,
 		});
-		await bucket.delete(resource.key);
-		if (destination_exists) {
-			return new Response(null, { status: 204 });
-		} else {
-			return new Response('', { status: 201 });
+	} catch (error) {
+		// A put that never got to the body, out of budget among others, leaves it to be cancelled
+		if (!src.body.locked) {
+			await src.body.cancel();
 		}
+		throw error;
 	}
 }
 
-const DAV_CLASS = '1, 3';
-const SUPPORT_METHODS = ['OPTIONS', 'PROPFIND', 'PROPPATCH', 'MKCOL', 'GET', 'HEAD', 'PUT', 'DELETE', 'COPY', 'MOVE'];
+async function handle_move(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	let resource_path = make_resource_path(request);
+	let { path: destination_path, overwrite } = parse_destination(request);
+	let { bucket: target, path: destination } = resolve_destination(bucket, env, destination_path);
+	if (target === bucket && is_within(destination, resource_path)) {
+		throw new DavError(403, 'Forbidden');
+	}
+	let continue_after = parse_continuation(request);
+
+	// Check if the parent directory exists, a file in its place doesn't count
+	let destination_parent = destination.split('/').slice(0, -1).join('/');
+	if (
+		destination_parent !== '' &&
+		!(await is_collection_path(target, env, destination_parent, await target.head(destination_parent)))
+	) {
+		throw new DavError(409, 'Conflict');
+	}
 
-async function dispatch_handler(request: Request, bucket: R2Bucket): Promise<Response> {
-	switch (request.method) {
-		case 'OPTIONS': {
-			return new Response(null, {
-				status: 204,
-				headers: {
-					Allow: SUPPORT_METHODS.join(', '),
-					DAV: DAV_CLASS,
-				},
-			});
+	// Check if the destination already exists, when continuing it's the one written so far
+	let destination_exists = (await target.head(destination)) !== null || (await has_members(target, env, destination));
+	if (!overwrite && destination_exists && continue_after === null) {
+		throw new DavError(412, 'Precondition Failed');
+	}
+
+	// A folder of another tool is moved with a marker of our own, and its own markers are deleted
+	let resource = await bucket.head(resource_path);
+	let is_dir = await is_collection_path(bucket, env, resource_path, resource);
+	if (resource === null && !is_dir) {
+		throw new DavError(404, 'Not Found');
+	}
+
+	// MOVE on a collection always acts as Depth: infinity
+	if (is_dir && (request.headers.get('Depth') ?? 'infinity') !== 'infinity') {
+		throw new DavError(400, 'Bad Request');
+	}
+
+	if (destination_exists && continue_after === null) {
+		// Delete the destination first
+		await delete_destination(request, target, env, destination);
+	}
+
+	if (is_dir) {
+		// The sources are deleted together once they have been copied, enough subrequests are kept for that.
+		// The keys that couldn't be are left where they were, and so is the collection itself, rather than
+		// failing the whole MOVE. A MOVE that is continued needs it still there.
+		const deletes = (count: number) => Math.ceil(count / DELETE_BATCH_SIZE) + 1;
+		let copy = await copy_collection(
+			bucket,
+			resource_path,
+			resource,
+			target,
+			destination,
+			continue_after,
+			env.budget,
+			deletes,
+		);
+		let failed = copy.moves.filter((_, index) => copy.results[index] === 'failed').map(({ source }) => source);
+		let copied = copy.moves
+			.filter((move, index) => copy.results[index] === 'copied' && move.source !== resource_path)
+			.map(({ source }) => source);
+		failed.push(...(await delete_many(bucket, copied)));
+		if (copy.exhausted) {
+			return budget_exhausted_response(copy.continue_after);
+		} else if (failed.length > 0) {
+			return generate_failed_response(failed);
 		}
-		case 'HEAD': {
-			return await handle_head(request, bucket);
+		await bucket.delete(marker_keys(env, resource_path));
+	} else if (!(await move_object(bucket, resource_path, target, destination))) {
+		throw new DavError(404, 'Not Found');
+	}
+
+	if (destination_exists) {
+		return new Response(null, { status: 204 });
+	} else {
+		return new Response('', { status: 201 });
+	}
+}
+
+type Move = {
+	source: string;
+	size: number;
+	target: string;
+};
+
+type CopyResult = 'copied' | 'missing' | 'failed' | 'exhausted';
+
+// Copies a member of a collection being copied or moved and checks the copy is whole
+async function copy_member(bucket: R2Bucket, move: Move, target_bucket: R2Bucket): Promise<CopyResult> {
+	try {
+		let copy = await copy_object(bucket, move.source, target_bucket, move.target);
+		if (copy === null) {
+			return 'missing';
+		} else if (copy.size === move.size) {
+			return 'copied';
 		}
-		case 'GET': {
-			return await handle_get(request, bucket);
+		logger.error('Copied object has the wrong size', { key: move.source, size: copy.size, expected: move.size });
+	} catch (error) {
+		if (error instanceof BudgetExhausted) {
+			return 'exhausted';
 		}
-		case 'PUT': {
-			return await handle_put(request, bucket);
+		logger.error('Failed to copy object', { key: move.source, error });
+	}
+	return 'failed';
+}
+
+type CollectionCopy = {
+	moves: Move[];
+	results: CopyResult[];
+	// Whether the budget ran out, then a repeated request continues after continue_after,
+	// a member relative to the collection, or from the start when it's null
+	exhausted: boolean;
+	continue_after: string | null;
+};
+
+// Members of a collection copied at once
+const COPY_CONCURRENCY = 6;
+
+// Copies a collection and everything below it, or only the members after continue_after when a COPY
+// or MOVE is continued. Members are copied in the order they're listed, a few at a time, and one is only
+// started while the budget still has its get and put besides the reserve(count) subrequests kept for what
+// comes after count copies. The members listed by then are all there is to continue from.
+async function copy_collection(
+	bucket: R2Bucket,
+	path: string,
+	collection: R2Object | null,
+	target: R2Bucket,
+	destination: string,
+	continue_after: string | null,
+	budget: Budget | undefined,
+	reserve: (count: number) => number,
+): Promise<CollectionCopy> {
+	let prefix = path + '/';
+	let start_after = continue_after === null ? undefined : prefix + continue_after;
+	let moves: Move[] = [];
+	let results: CopyResult[] = [];
+	let running = new Set<Promise<void>>();
+	// The copies running still count for their get and put, even once they have made one of them
+	const affords = (subrequests: number) =>
+		budget === undefined || budget.remaining - 2 * running.size - reserve(moves.length + 1) >= subrequests;
+	const start = (move: Move, copy_move = () => copy_member(bucket, move, target)) => {
+		let index = moves.push(move) - 1;
+		results.push('exhausted');
+		let copy: Promise<void> = copy_move().then((result) => {
+			results[index] = result;
+			running.delete(copy);
+		});
+		running.add(copy);
+	};
+
+	let exhausted = false;
+	try {
+		if (continue_after === null) {
+			if (!affords(2)) {
+				throw new BudgetExhausted();
+			}
+			// Without a marker of ours there's nothing to copy, the copy gets one
+			let move = { source: path, size: collection?.size ?? 0, target: destination };
+			start(move, collection === null ? () => create_collection(target, destination) : undefined);
 		}
-		case 'DELETE': {
-			return await handle_delete(request, bucket);
+		let members = listAll(bucket, prefix, true, start_after);
+		while (true) {
+			// Listing the next member may take a page of the listing as well
+			if (!affords(3)) {
+				throw new BudgetExhausted();
+			}
+			let next = await members.next();
+			if (next.done) {
+				break;
+			}
+			let object = next.value;
+			start({ source: object.key, size: object.size, target: destination + '/' + object.key.slice(prefix.length) });
+			if (running.size >= COPY_CONCURRENCY) {
+				await Promise.race(running);
+			}
 		}
-		case 'MKCOL': {
-			return await handle_mkcol(request, bucket);
+	} catch (error) {
+		if (!(error instanceof BudgetExhausted)) {
+			await Promise.all(running);
+			throw error;
 		}
-		case 'PROPFIND': {
-			return await handle_propfind(request, bucket);
+		exhausted = true;
+	}
+	await Promise.all(running);
+
+	// A copy can still run out with its retries, everything before the first one left undone is done
+	let undone = results.indexOf('exhausted');
+	if (!exhausted && undone < 0) {
+		return { moves, results, exhausted: false, continue_after: null };
+	}
+	let copied = undone < 0 ? moves.length : undone;
+	if (copied === 0) {
+		// Repeating the request would get no further
+		throw new DavError(507, 'Subrequest budget too small to copy anything, raise SUBREQUEST_LIMIT');
+	}
+	let last = moves[copied - 1].source;
+	return { moves, results, exhausted: true, continue_after: last === path ? '' : last.slice(prefix.length) };
+}
+
+async function create_collection(bucket: R2Bucket, path: string): Promise<CopyResult> {
+	try {
+		await put_collection_marker(bucket, path);
+		return 'copied';
+	} catch (error) {
+		if (error instanceof BudgetExhausted) {
+			return 'exhausted';
 		}
-		case 'PROPPATCH': {
-			return await handle_proppatch(request, bucket);
+		logger.error('Failed to create collection', { key: path, error });
+		return 'failed';
+	}
+}
+
+// Sent with the 507 of a COPY or MOVE of a collection that ran out of subrequests, naming the last member
+// it got to. The request is continued by repeating it with the header.
+const CONTINUE_HEADER = 'X-Continue-After';
+
+function parse_continuation(request: Request): string | null {
+	let header = request.headers.get(CONTINUE_HEADER);
+	if (header === null) {
+		return null;
+	}
+	try {
+		return decodeURIComponent(header);
+	} catch {
+		throw new DavError(400, 'Bad Request');
+	}
+}
+
+function budget_exhausted_response(continue_after: string | null): Response {
+	if (continue_after === null) {
+		return generate_dav_error(507, undefined, new BudgetExhausted().message);
+	}
+	let response = generate_dav_error(
+		507,
+		undefined,
+		`Subrequest budget exhausted, repeat the request with the ${CONTINUE_HEADER} header of this response`,
+	);
+	response.headers.set(CONTINUE_HEADER, encodeURIComponent(continue_after));
+	return response;
+}
+
+// R2 has no rename, the source is only deleted once the copy has been written
+async function move_object(
+	bucket: R2Bucket,
+	source: string,
+	target_bucket: R2Bucket,
+	target: string,
+): Promise<boolean> {
+	if ((await copy_object(bucket, source, target_bucket, target)) === null) {
+		return false;
+	}
+	await bucket.delete(source);
+	return true;
+}
+
+async function handle_lock(request: Request, bucket: R2Bucket, env: Env, kv: KVNamespace): Promise<Response> {
+	let resource_path = make_resource_path(request);
+
+	// A LOCK without a body refreshes the locks whose tokens are in the If header
+	let body = await request.text();
+	if (body.trim() === '') {
+		return refresh_locks(request, bucket, kv, resource_path);
+	}
+
+	let lockinfo = parse_request_xml(body, 'lockinfo');
+	let lockscope = find_child(lockinfo, 'DAV:', 'lockscope');
+	let locktype = find_child(lockinfo, 'DAV:', 'locktype');
+	let owner = find_child(lockinfo, 'DAV:', 'owner');
+	let scope = lockscope && child_elements(lockscope)[0]?.name;
+	let depth = request.headers.get('Depth') ?? 'infinity';
+	if (
+		(scope !== 'exclusive' && scope !== 'shared') ||
+		!(locktype && find_child(locktype, 'DAV:', 'write')) ||
+		(depth !== '0' && depth !== 'infinity')
+	) {
+		throw new DavError(400, 'Bad Request');
+	}
+
+	if ((await find_conflicts(kv, resource_path, scope, depth)).length > 0) {
+		return generate_dav_error(423, 'no-conflicting-lock');
+	}
+
+	let resource = resource_path === '' ? null : await bucket.head(resource_path);
+	let created = false;
+	if (resource_path !== '' && resource === null) {
+		// Locking an unmapped URL creates an empty resource, its parent must exist
+		let dirpath = resource_path.split('/').slice(0, -1).join('/');
+		if (dirpath !== '' && !(await is_collection_path(bucket, env, dirpath, await bucket.head(dirpath)))) {
+			throw new DavError(409, 'Conflict');
 		}
-		case 'COPY': {
-			return await handle_copy(request, bucket);
+		await bucket.put(resource_path, new Uint8Array(), metadata_only());
+		created = true;
+	}
+
+	let lock = await create_lock(kv, resource_path, {
+		owner: owner ? serialize_xml(owner) : '',
+		scope: scope,
+		depth: depth,
+		timeout: parse_timeout(request.headers.get('Timeout')),
+	});
+	let href = lock_root_href(resource_path, resource !== null && is_collection(resource));
+
+	return new Response(
+		`<?xml version="1.0" encoding="utf-8"?>
+<prop xmlns="DAV:">
+	<lockdiscovery>
+		${generate_activelock(lock, href)}
+	</lockdiscovery>
+</prop>
+`,
+		{
+			status: created ? 201 : 200,
+			headers: {
+				'Content-Type': 'application/xml; charset="utf-8"',
+				'Lock-Token': `<${lock.token}>`,
+			},
+		},
+	);
+}
+
+// The lockroot of a lock, collections have their trailing slash like everywhere else
+function lock_root_href(path: string, is_dir: boolean): string {
+	return encode_href(path + (path === '' || is_dir ? '/' : ''));
+}
+
+// The If header has already been evaluated, any token it submits matches a lock on the resource.
+// A refresh gets no Lock-Token header, the client knows it.
+async function refresh_locks(
+	request: Request,
+	bucket: R2Bucket,
+	kv: KVNamespace,
+	resource_path: string,
+): Promise<Response> {
+	let if_header = request.headers.get('If');
+	let tokens = if_header === null ? [] : submitted_tokens(parse_if_header(if_header) ?? []);
+	let timeout = parse_timeout(request.headers.get('Timeout'));
+	let activelocks: string[] = [];
+	for (let token of tokens) {
+		let lock = await refresh_lock(kv, resource_path, token, timeout);
+		if (lock !== null) {
+			let root = lock.path === '' ? null : await bucket.head(lock.path);
+			activelocks.push(generate_activelock(lock, lock_root_href(lock.path, is_collection(root))));
 		}
+	}
+	if (activelocks.length === 0) {
+		return generate_dav_error(412, 'lock-token-matches-request-uri');
+	}
+
+	return new Response(
+		`<?xml version="1.0" encoding="utf-8"?>
+<prop xmlns="DAV:">
+	<lockdiscovery>
+		${activelocks.join('\n\t\t')}
+	</lockdiscovery>
+</prop>
+`,
+		{
+			status: 200,
+			headers: { 'Content-Type': 'application/xml; charset="utf-8"' },
+		},
+	);
+}
+
+async function handle_unlock(request: Request, kv: KVNamespace): Promise<Response> {
+	let resource_path = make_resource_path(request);
+	let token = request.headers.get('Lock-Token')?.trim().replace(/^<(.*)>$/, '$1');
+	if (!token) {
+		throw new DavError(400, 'Bad Request');
+	}
+	if (!(await remove_lock(kv, resource_path, token))) {
+		throw new DavError(409, 'Conflict');
+	}
+	return new Response(null, { status: 204 });
+}
+
+type ModifiedPath = { path: string; recursive: boolean };
+
+// The paths a request modifies, and whether everything below them is modified as well
+function modified_paths(request: Request): ModifiedPath[] {
+	let resource_path = make_resource_path(request);
+	switch (request.method) {
+		case 'PUT':
+		case 'PROPPATCH':
+		case 'MKCOL':
+			return [{ path: resource_path, recursive: false }];
+		case 'DELETE':
+			return [{ path: resource_path, recursive: true }];
+		case 'COPY':
 		case 'MOVE': {
-			return await handle_move(request, bucket);
+			let paths: ModifiedPath[] = request.method === 'MOVE' ? [{ path: resource_path, recursive: true }] : [];
+			try {
+				paths.push({ path: parse_destination(request).path, recursive: true });
+			} catch (error) {
+				// An invalid Destination is rejected by the handler
+			}
+			return paths;
 		}
-		default: {
-			return new Response('Method Not Allowed', {
-				status: 405,
-				headers: {
-					Allow: SUPPORT_METHODS.join(', '),
-					DAV: DAV_CLASS,
-				},
-			});
+		default:
+			return [];
+	}
+}
+
+// A locked resource can only be modified by requests submitting its lock token in the If header
+async function is_lock_satisfied(request: Request, kv: KVNamespace, tokens: string[]): Promise<boolean> {
+	for (let { path, recursive } of modified_paths(request)) {
+		let locks = await find_locks(kv, path);
+		if (recursive) {
+			locks.push(...(await find_member_locks(kv, path)));
+		}
+		if (!is_unlocked(locks, tokens)) {
+			return false;
 		}
 	}
+	return true;
 }
 
-function is_authorized(authorization_header: string, username: string, password: string): boolean {
-    const encoder = new TextEncoder();
+// What the If header compares a resource with, resource being a tagged URL or null for the request URL
+async function get_resource_state(
+	request: Request,
+	bucket: R2Bucket,
+	kv: KVNamespace | undefined,
+	resource: string | null,
+): Promise<ResourceState> {
+	let path = resource === null ? make_resource_path(request) : normalize_path(new URL(resource, request.url).pathname);
+	if (path === null) {
+		return { etag: undefined, tokens: [] };
+	}
+	let object = path === '' ? null : await bucket.head(path);
+	let locks = kv === undefined ? [] : await find_locks(kv, path);
+	return { etag: object?.etag, tokens: locks.map((lock) => lock.token) };
+}
 
-    const header = encoder.encode(authorization_header);
-    const expected = encoder.encode(`Basic ${btoa(`${username}:${password}`)}`);
+type Handler = (request: Request, bucket: R2Bucket, env: Env) => Promise<Response>;
 
-    return header.byteLength === expected.byteLength && crypto.subtle.timingSafeEqual(header, expected);
+// A flag is set by "true", "1" or "yes" and cleared by "false", "0" or "no", in any case, a string from the
+// dashboard or a wrangler.toml boolean. Anything else leaves it at its default.
+function is_flag_set(value: string | boolean | undefined, default_value: boolean = false): boolean {
+	let flag = String(value).toLowerCase();
+	if (['true', '1', 'yes'].includes(flag)) {
+		return true;
+	} else if (['false', '0', 'no'].includes(flag)) {
+		return false;
+	}
+	return default_value;
 }
 
-export default {
-	async fetch(request: Request, env: Env, ctx: ExecutionContext): Promise<Response> {
-		const { bucket } = env;
+// Class 2 (locking) needs the KV namespace, and there's nothing to lock in read-only mode
+function supports_locking(env: Env): boolean {
+	return env.kv !== undefined && !is_flag_set(env.READ_ONLY);
+}
+
+function dav_class(env: Env): string {
+	return supports_locking(env) ? '1, 2, 3' : '1, 3';
+}
+
+async function handle_options(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	return new Response(null, {
+		status: 204,
+		headers: {
+			Allow: supported_methods(env).join(', '),
+			DAV: dav_class(env),
+			// Makes Microsoft clients use WebDAV instead of FrontPage extensions
+			'MS-Author-Via': 'DAV',
+			'Accept-Ranges': 'bytes',
+		},
+	});
+}
+
+// The asterisk-form target of "OPTIONS *" reaches the worker as the path "*", or "/*" from some
+// proxies. A key "*" can't be asked about, everything else about it still works.
+function is_server_options(request: Request): boolean {
+	let pathname = new URL(request.url).pathname;
+	return request.method === 'OPTIONS' && (pathname === '*' || pathname === '/*');
+}
 
+function make_handlers(env: Env): Record<string, Handler> {
+	let handlers: Record<string, Handler> = {
+		OPTIONS: handle_options,
+		PROPFIND: handle_propfind,
+		GET: handle_get,
+		HEAD: handle_head,
+	};
+	if (is_flag_set(env.READ_ONLY)) {
+		return handlers;
+	}
+	Object.assign(handlers, {
+		PROPPATCH: handle_proppatch,
+		MKCOL: handle_mkcol,
+		PUT: handle_put,
+		DELETE: handle_delete,
+		COPY: handle_copy,
+		MOVE: handle_move,
+	});
+	const kv = env.kv;
+	if (kv) {
+		handlers.LOCK = (request, bucket, env) => handle_lock(request, bucket, env, kv);
+		handlers.UNLOCK = (request) => handle_unlock(request, kv);
+	}
+	return handlers;
+}
+
+function supported_methods(env: Env): string[] {
+	return Object.keys(make_handlers(env));
+}
+
+// The Allow header lists what the router has, less the methods that don't apply to the resource
+function method_not_allowed(env: Env, inapplicable: string[] = []): Response {
+	return new Response('Method Not Allowed', {
+		status: 405,
+		headers: {
+			Allow: supported_methods(env)
+				.filter((method) => !inapplicable.includes(method))
+				.join(', '),
+			DAV: dav_class(env),
+		},
+	});
+}
+
+// Every request goes through here, so in read-only mode no write can reach the bucket
+async function dispatch_handler(request: Request, bucket: R2Bucket, env: Env): Promise<Response> {
+	let handlers = make_handlers(env);
+	if (!Object.keys(handlers).includes(request.method)) {
+		return method_not_allowed(env);
+	}
+
+	if (normalize_path(new URL(request.url).pathname) === null) {
+		return new Response('Forbidden', { status: 403 });
+	}
+	try {
+		let if_header = request.headers.get('If');
+		let if_lists: IfList[] | null = if_header === null ? [] : parse_if_header(if_header);
+		if (if_lists === null) {
+			throw new DavError(400, 'Bad Request');
+		}
+		// Missing lock tokens come first, so a 412 only means the conditions don't hold
+		if (env.kv && !(await is_lock_satisfied(request, env.kv, submitted_tokens(if_lists)))) {
+			throw new DavError(423, 'Locked', 'lock-token-submitted');
+		}
+		let kv = env.kv;
 		if (
-			request.method !== 'OPTIONS' &&
-			!is_authorized(request.headers.get('Authorization') ?? '', env.USERNAME, env.PASSWORD)
+			if_lists.length > 0 &&
+			!(await evaluate_if(if_lists, (resource) => get_resource_state(request, bucket, kv, resource)))
 		) {
+			throw new DavError(412, 'Precondition Failed');
+		}
+		return to_dav_error(request, await handlers[request.method](request, bucket, env));
+	} catch (error) {
+		return make_error_response(request, error);
+	}
+}
+
+// Methods only WebDAV clients send, their errors get DAV:error bodies rather than plain text ones
+const DAV_METHODS = ['PROPFIND', 'PROPPATCH', 'MKCOL', 'COPY', 'MOVE', 'LOCK', 'UNLOCK'];
+const DAV_ERROR_STATUSES = [403, 404, 409, 423];
+
+function to_dav_error(request: Request, response: Response): Response {
+	if (
+		!DAV_METHODS.includes(request.method) ||
+		!DAV_ERROR_STATUSES.includes(response.status) ||
+		response.headers.get('Content-Type')?.includes('xml')
+	) {
+		return response;
+	}
+	return generate_dav_error(response.status);
+}
+
+// The one place errors become responses, anything but a DavError is a bug or an R2 failure
+function make_error_response(request: Request, error: unknown): Response {
+	if (!(error instanceof DavError)) {
+		logger.error('Request failed', { method: request.method, path: new URL(request.url).pathname, error });
+		return new Response('Internal Server Error', { status: 500 });
+	} else if (error.condition !== undefined) {
+		return generate_dav_error(error.status, error.condition);
+	} else if (DAV_METHODS.includes(request.method)) {
+		return generate_dav_error(error.status, undefined, error.message);
+	}
+	return new Response(error.message, { status: error.status });
+}
+
+// The accounts allowed in, USERNAME and PASSWORD plus the user:password pairs listed in USERS
+function make_credentials(env: Env): [string, string][] {
+	let credentials: [string, string][] = [];
+	if (env.USERNAME !== undefined && env.PASSWORD !== undefined) {
+		credentials.push([env.USERNAME, env.PASSWORD]);
+	}
+	for (let entry of (env.USERS ?? '').split(/[\n,]/).map((entry) => entry.trim())) {
+		let colon = entry.indexOf(':');
+		if (colon > 0) {
+			credentials.push([entry.slice(0, colon), entry.slice(colon + 1)]);
+		}
+	}
+	return credentials;
+}
+
+async function sha256_digest(value: string): Promise<ArrayBuffer> {
+	return await crypto.subtle.digest('SHA-256', new TextEncoder().encode(value));
+}
+
+// The user the Authorization header authenticates, or null. Comparing digests keeps the comparison
+// constant-time whatever the lengths, and every account is checked so the time doesn't tell which one matched.
+async function authenticate_basic(
+	authorization_header: string,
+	credentials: [string, string][],
+): Promise<string | null> {
+	const header = await sha256_digest(authorization_header);
+	let user: string | null = null;
+	for (let [username, password] of credentials) {
+		// btoa only takes Latin-1, clients encode the credentials as UTF-8
+		let encoded = String.fromCharCode(...new TextEncoder().encode(`${username}:${password}`));
+		let expected = await sha256_digest(`Basic ${btoa(encoded)}`);
+		if (crypto.subtle.timingSafeEqual(header, expected) && user === null) {
+			user = username;
+		}
+	}
+	return user;
+}
+
+async function authenticate_bearer(authorization_header: string, token: string | undefined): Promise<string | null> {
+	if (token === undefined) {
+		return null;
+	}
+	let header = await sha256_digest(authorization_header);
+	let expected = await sha256_digest(`Bearer ${token}`);
+	return crypto.subtle.timingSafeEqual(header, expected) ? 'bearer' : null;
+}
+
+function auth_mode(env: Env): 'basic' | 'bearer' {
+	return env.AUTH_MODE?.toLowerCase() === 'bearer' ? 'bearer' : 'basic';
+}
+
+// A download through a temporary link, see presign.ts. The signature covers the folder of the user
+// the link was made by as well, so changing the user parameter breaks it. A link can't make links,
+// only credentials can.
+async function is_signed_download(request: Request, env: Env, user: string | null): Promise<boolean> {
+	let params = new URL(request.url).searchParams;
+	return (
+		(request.method === 'GET' || request.method === 'HEAD') &&
+		!params.has('presign') &&
+		env.SIGNING_SECRET !== undefined &&
+		(await verify_signature(
+			env.SIGNING_SECRET,
+			user_prefix(env, user) + make_resource_path(request),
+			params.get('expires') ?? '',
+			params.get('signature') ?? '',
+		))
+	);
+}
+
+// The identity of the client, its username with Basic auth and "bearer" with a token, or null
+async function authenticate(request: Request, env: Env): Promise<string | null> {
+	let authorization_header = request.headers.get('Authorization') ?? '';
+	if (auth_mode(env) === 'bearer') {
+		return await authenticate_bearer(authorization_header, env.BEARER_TOKEN);
+	}
+	return await authenticate_basic(authorization_header, make_credentials(env));
+}
+
+const DEFAULT_MAX_RETRIES = 2;
+const DEFAULT_SUBREQUEST_LIMIT = 950;
+
+function max_retries(env: Env): number {
+	let retries = Number(env.R2_MAX_RETRIES ?? DEFAULT_MAX_RETRIES);
+	return Number.isInteger(retries) && retries >= 0 ? retries : DEFAULT_MAX_RETRIES;
+}
+
+function subrequest_limit(env: Env): number {
+	let limit = Number(env.SUBREQUEST_LIMIT ?? DEFAULT_SUBREQUEST_LIMIT);
+	return Number.isInteger(limit) && limit > 0 ? limit : DEFAULT_SUBREQUEST_LIMIT;
+}
+
+// The folder USER_ISOLATION confines user to, relative to ROOT_PREFIX. Admins and anonymous requests,
+// which only reach OPTIONS, get the whole bucket.
+function user_prefix(env: Env, user: string | null): string {
+	let admins = (env.ADMIN_USERS ?? '').split(',').map((admin) => admin.trim());
+	if (!is_flag_set(env.USER_ISOLATION) || user === null || admins.includes(user)) {
+		return '';
+	}
+	return `users/${encodeURIComponent(user)}/`;
+}
+
+// ROOT_PREFIX as a key prefix, "photos" and "/photos/" both give "photos/"
+function root_prefix(env: Env): string {
+	let prefix = (env.ROOT_PREFIX ?? '').replace(/^\/+|\/+$/g, '');
+	return prefix === '' ? '' : prefix + '/';
+}
+
+// Uploaded HTML runs sandboxed without scripts, the index page only needs its inline styles
+const DEFAULT_CONTENT_SECURITY_POLICY =
+	"default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'; sandbox";
+
+// With SECURITY_HEADERS, downloads can't be sniffed or run as another type than they were stored with.
+// WebDAV clients ignore these headers, browsers opening uploaded files are what they're for.
+function add_security_headers(request: Request, env: Env, response: Response) {
+	if (!is_flag_set(env.SECURITY_HEADERS) || (request.method !== 'GET' && request.method !== 'HEAD')) {
+		return;
+	}
+	response.headers.set('X-Content-Type-Options', 'nosniff');
+	response.headers.set('Content-Security-Policy', env.CONTENT_SECURITY_POLICY ?? DEFAULT_CONTENT_SECURITY_POLICY);
+	if (env.FRAME_OPTIONS !== undefined) {
+		response.headers.set('X-Frame-Options', env.FRAME_OPTIONS);
+	}
+}
+
+// Without CORS_ORIGINS every origin is allowed. With it only the listed ones are, and since the headers
+// then depend on the Origin, responses say so with Vary.
+function add_cors_headers(request: Request, env: Env, response: Response) {
+	let origin = request.headers.get('Origin');
+	if (env.CORS_ORIGINS === undefined) {
+		response.headers.set('Access-Control-Allow-Origin', origin ?? '*');
+	} else {
+		let allowed = env.CORS_ORIGINS.split(',').map((entry) => entry.trim());
+		response.headers.append('Vary', 'Origin');
+		if (origin === null || !(allowed.includes(origin) || allowed.includes('*'))) {
+			return;
+		}
+		response.headers.set('Access-Control-Allow-Origin', origin);
+	}
+	response.headers.set('Access-Control-Allow-Methods', supported_methods(env).join(', '));
+	response.headers.set(
+		'Access-Control-Allow-Headers',
+		[
+			'authorization',
+			'content-type',
+			'depth',
+			'overwrite',
+			'destination',
+			'range',
+			'timeout',
+			'if',
+			'lock-token',
+			'if-match',
+			'if-none-match',
+			'if-modified-since',
+			'if-unmodified-since',
+			'if-range',
+			'content-range',
+			'x-storage-class',
+			'prefer',
+			CONTINUE_HEADER.toLowerCase(),
+			SSEC_HEADER.toLowerCase(),
+		].join(', '),
+	);
+	response.headers.set(
+		'Access-Control-Expose-Headers',
+		[
+			'content-type',
+			'content-length',
+			'dav',
+			'etag',
+			'last-modified',
+			'location',
+			'date',
+			'content-range',
+			'lock-token',
+			'preference-applied',
+			CONTINUE_HEADER.toLowerCase(),
+			'retry-after',
+		].join(', '),
+	);
+	response.headers.set('Access-Control-Allow-Credentials', 'false');
+	response.headers.set('Access-Control-Max-Age', '86400');
+}
+
+// The versions of the objects a write replaces or removes, whose cache entries are then of no use
+async function replaced_versions(request: Request, bucket: R2Bucket, scope: string): Promise<CachedVersion[]> {
+	let paths = modified_paths(request).filter(({ path }) => path !== '');
+	let objects = await Promise.all(paths.map(({ path }) => bucket.head(path)));
+	return objects.flatMap((object) =>
+		object === null ? [] : [{ href: encode_href(scope + object.key), etag: object.etag }],
+	);
+}
+
+// Every response gets the CORS and security headers, those refusing the request before it's handled too
+async function handle_request(request: Request, env: Env, ctx: ExecutionContext): Promise<Response> {
+	let response = await serve_request(request, env, ctx);
+	add_cors_headers(request, env, response);
+	add_security_headers(request, env, response);
+	return response;
+}
+
+async function serve_request(request: Request, env: Env, ctx: ExecutionContext): Promise<Response> {
+	// "OPTIONS *" asks about the server rather than a resource, there's nothing to resolve or check
+	if (is_server_options(request)) {
+		return await handle_options(request, env.bucket, env);
+	}
+
+	// Failed attempts count against the client IP, past its limit it doesn't get to try any more
+	let limited = await check_rate_limit(request, env, null, ctx, false);
+	if (limited !== null) {
+		return limited;
+	}
+
+	// A signature stands in for the credentials, and only for downloading the file it was made for
+	let params = new URL(request.url).searchParams;
+	let user: string | null;
+	if (params.has('signature')) {
+		user = params.get('user');
+		if (!(await is_signed_download(request, env, user))) {
+			await check_rate_limit(request, env, null, ctx);
+			return new Response('Forbidden', { status: 403 });
+		}
+	} else {
+		user = await authenticate(request, env);
+		if (request.method !== 'OPTIONS' && user === null) {
+			await check_rate_limit(request, env, null, ctx);
 			return new Response('Unauthorized', {
 				status: 401,
 				headers: {
-					'WWW-Authenticate': 'Basic realm="webdav"',
+					'WWW-Authenticate': auth_mode(env) === 'bearer' ? 'Bearer realm="webdav"' : 'Basic realm="webdav"',
 				},
 			});
 		}
+	}
 
-		let response: Response = await dispatch_handler(request, bucket);
+	limited = await check_rate_limit(request, env, user, ctx);
+	if (limited !== null) {
+		return limited;
+	}
 
-		// Set CORS headers
-		response.headers.set('Access-Control-Allow-Origin', request.headers.get('Origin') ?? '*');
-		response.headers.set('Access-Control-Allow-Methods', SUPPORT_METHODS.join(', '));
-		response.headers.set(
-			'Access-Control-Allow-Headers',
-			['authorization', 'content-type', 'depth', 'overwrite', 'destination', 'range'].join(', '),
-		);
-		response.headers.set(
-			'Access-Control-Expose-Headers',
-			['content-type', 'content-length', 'dav', 'etag', 'last-modified', 'location', 'date', 'content-range'].join(
-				', ',
-			),
-		);
-		response.headers.set('Access-Control-Allow-Credentials', 'false');
-		response.headers.set('Access-Control-Max-Age', '86400');
+	// A key sent by the client takes the place of the configured one
+	let ssec_key = request.headers.get(SSEC_HEADER) ?? env.SSEC_KEY;
+	if (ssec_key !== undefined && !is_ssec_key(ssec_key)) {
+		if (request.headers.has(SSEC_HEADER)) {
+			return new Response('Bad Request', { status: 400 });
+		}
+		logger.error('SSEC_KEY must be 64 hex digits');
+		return new Response('Internal Server Error', { status: 500 });
+	}
+
+	// Events that can't be signed would be of no use to receivers, reads have none to send
+	let webhooks = env.WEBHOOK_URL !== undefined;
+	if (webhooks && env.WEBHOOK_SECRET === undefined && request_kind(request.method) === 'write') {
+		logger.error('WEBHOOK_URL needs WEBHOOK_SECRET');
+		return new Response('Internal Server Error', { status: 500 });
+	}
 
+	// From here on only the files of the user are reachable, in both buckets. Locks and uploads are
+	// kept by their path in the bucket, whatever folder they're reached from. Both buckets count
+	// their calls against the one budget, retries included, and leave one for the webhook reporting
+	// the writes of both.
+	let scope = user_prefix(env, user);
+	let budget: Budget = { remaining: subrequest_limit(env) - (webhooks ? 1 : 0) };
+	let events: WebhookEvent[] = [];
+	const stored = (binding: string, bucket: R2Bucket) => {
+		let wrapped = retry_bucket(budget_bucket(ssec_bucket(bucket, ssec_key), budget), max_retries(env));
+		if (!webhooks) {
+			return wrapped;
+		}
+		return notify_bucket(wrapped, (key, object) => events.push(object_event(binding, key, object)));
+	};
+	env = {
+		...env,
+		kv: env.kv && prefix_kv(env.kv, root_prefix(env) + scope),
+		secondary_bucket:
+			env.secondary_bucket && prefix_bucket(stored('secondary_bucket', env.secondary_bucket), root_prefix(env) + scope),
+		user,
+		budget,
+	};
+	const bucket = prefix_bucket(stored('bucket', env.bucket), root_prefix(env) + scope);
+
+	let response: Response;
+	if (is_flag_set(env.CACHE_ENABLED)) {
+		let resource_path = make_resource_path(request);
+		let href = encode_href(scope + resource_path);
+		// Cached downloads are only served when their ETag is still the current one
+		let current = is_cacheable(request) && resource_path !== '' ? await bucket.head(resource_path) : null;
+		let cached = current === null ? undefined : await match_cache(request, href, current.etag);
+		if (cached !== undefined) {
+			response = cached;
+		} else {
+			let replaced = await replaced_versions(request, bucket, scope);
+			response = await dispatch_handler(request, bucket, env);
+			store_in_cache(request, href, response, ctx);
+			if (response.ok) {
+				invalidate_cache(request, replaced, ctx);
+			}
+		}
+	} else {
+		response = await dispatch_handler(request, bucket, env);
+	}
+
+	if (events.length > 0) {
+		// Delivered in one POST once the response is on its way, a slow receiver doesn't hold it up
+		ctx.waitUntil(send_webhook(env.WEBHOOK_URL!, env.WEBHOOK_SECRET!, events));
+	}
+	return response;
+}
+
+const DEFAULT_RATE_LIMIT_WINDOW = 60;
+
+// The 429 of a request over RATE_LIMIT_READS or RATE_LIMIT_WRITES, null when it's let through.
+// Without count it's only checked, the request counts once it's known whose it is.
+async function check_rate_limit(
+	request: Request,
+	env: Env,
+	user: string | null,
+	ctx: ExecutionContext,
+	count: boolean = true,
+): Promise<Response | null> {
+	let kind = request_kind(request.method);
+	let requests = Number(kind === 'read' ? env.RATE_LIMIT_READS : env.RATE_LIMIT_WRITES);
+	if (!(requests > 0)) {
+		return null;
+	} else if (env.kv === undefined) {
+		logger.error('RATE_LIMIT_READS and RATE_LIMIT_WRITES need the kv namespace');
+		return new Response('Internal Server Error', { status: 500 });
+	}
+	let window = Number(env.RATE_LIMIT_WINDOW ?? DEFAULT_RATE_LIMIT_WINDOW);
+	if (!(Number.isInteger(window) && window > 0)) {
+		window = DEFAULT_RATE_LIMIT_WINDOW;
+	}
+	let identity = user === null ? `ip:${request.headers.get('CF-Connecting-IP') ?? 'unknown'}` : `user:${user}`;
+	let retry_after = count
+		? await count_request(env.kv, kind, identity, { requests, window }, ctx)
+		: await peek_requests(env.kv, kind, identity, { requests, window });
+	if (retry_after === null) {
+		return null;
+	}
+	return new Response('Too Many Requests', { status: 429, headers: { 'Retry-After': retry_after.toString() } });
+}
+
+// Resumable uploads without a chunk for this long are aborted by the scheduled handler
+const DEFAULT_UPLOAD_MAX_AGE = 24 * 3600;
+
+// Aborts the resumable uploads nobody sent a chunk to for UPLOAD_MAX_AGE seconds, so their parts
+// stop taking up storage. Past UPLOAD_TTL their entry is gone and couldn't tell which upload to abort.
+async function abort_stale_uploads(bucket: R2Bucket, kv: KVNamespace, env: Env): Promise<void> {
+	let max_age = Math.min(Number(env.UPLOAD_MAX_AGE ?? DEFAULT_UPLOAD_MAX_AGE), UPLOAD_TTL);
+	for (let { path, upload_id } of await find_stale_uploads(kv, max_age)) {
+		// R2 may have aborted it already
+		await bucket
+			.resumeMultipartUpload(path, upload_id)
+			.abort()
+			.catch((error) => logger.warn('Failed to abort a stale upload', { path, error }));
+		await delete_upload(kv, path);
+		logger.info('Aborted a stale upload', { path });
+	}
+}
+
+const AUDIT_CURSOR_KEY = 'audit:cursor';
+// Objects checked by one run of the scheduled handler, each is read whole
+const AUDIT_BATCH_SIZE = 100;
+
+// Recomputes the SHA-256 of the next batch of objects and logs those that don't match the one R2 keeps,
+// and those without one. The listing continues where the last run stopped and starts over once done.
+async function audit_checksums(bucket: R2Bucket, kv: KVNamespace): Promise<void> {
+	let cursor = (await kv.get(AUDIT_CURSOR_KEY)) ?? undefined;
+	let listing = await bucket.list({ cursor, limit: AUDIT_BATCH_SIZE });
+	for (let { key } of listing.objects) {
+		try {
+			let object = await bucket.get(key);
+			if (object === null || is_collection(object)) {
+				continue;
+			}
+			let expected = object.checksums.sha256;
+			if (expected === undefined) {
+				// Multipart uploads have none
+				await object.body.cancel();
+				logger.warn('Object without a checksum', { key });
+				continue;
+			}
+			let digest = new crypto.DigestStream('SHA-256');
+			await object.body.pipeTo(digest);
+			if (to_hex(await digest.digest) !== to_hex(expected)) {
+				logger.error('Checksum mismatch', { key });
+			}
+		} catch (error) {
+			logger.error('Failed to audit an object', { key, error });
+		}
+	}
+	if (listing.truncated) {
+		await kv.put(AUDIT_CURSOR_KEY, listing.cursor);
+	} else {
+		await kv.delete(AUDIT_CURSOR_KEY);
+		logger.info('Checksum audit completed');
+	}
+}
+
+export default {
+	async fetch(request: Request, env: Env, ctx: ExecutionContext): Promise<Response> {
+		set_log_level(env.LOG_LEVEL);
+		let start = Date.now();
+		let response = await handle_request(request, env, ctx);
+		logger.info('Request', {
+			method: request.method,
+			path: new URL(request.url).pathname,
+			status: response.status,
+			bytes: response.headers.has('Content-Length') ? Number(response.headers.get('Content-Length')) : null,
+			duration: Date.now() - start,
+		});
 		return response;
 	},
+
+	// Run by the cron triggers of wrangler.toml
+	async scheduled(controller: ScheduledController, env: Env, ctx: ExecutionContext): Promise<void> {
+		set_log_level(env.LOG_LEVEL);
+		let kv = env.kv;
+		if (kv === undefined) {
+			return;
+		}
+		// The uploads of every user are kept by their key in the bucket, see prefix_kv
+		await abort_stale_uploads(retry_bucket(env.bucket, max_retries(env)), kv, env);
+		if (is_flag_set(env.INTEGRITY_AUDIT)) {
+			let bucket = prefix_bucket(
+				retry_bucket(ssec_bucket(env.bucket, env.SSEC_KEY), max_retries(env)),
+				root_prefix(env),
+			);
+			await audit_checksums(bucket, kv);
+		}
+	},
 };
@@ -0,0 +1,38 @@
+// Language variants of a file, like page.en.html and page.fr.html standing in for page.html,
+// chosen with Accept-Language.
+
+// The language of the file called name if it's a variant of the one called file, null otherwise
+export function variant_language(file: string, name: string): string | null {
+	let dot = file.lastIndexOf('.');
+	let stem = dot > 0 ? file.slice(0, dot) : file;
+	let extension = dot > 0 ? file.slice(dot) : '';
+	if (!name.startsWith(stem + '.') || !name.endsWith(extension)) {
+		return null;
+	}
+	let language = name.slice(stem.length + 1, name.length - extension.length);
+	return /^[a-z]{2,3}(-[a-z0-9]{1,8})*$/i.test(language) ? language : null;
+}
+
+// The available language Accept-Language prefers, by quality and then by the order of the header.
+// A range like "en" matches "en-GB" as well, "*" matches any. Null when none is acceptable.
+export function negotiate_language(accept_language: string | null, available: string[]): string | null {
+	let best: string | null = null;
+	let best_quality = 0;
+	for (let entry of (accept_language ?? '').split(',')) {
+		let [range, ...parameters] = entry.split(';').map((part) => part.trim().toLowerCase());
+		let q = parameters.find((parameter) => parameter.startsWith('q='));
+		let quality = q === undefined ? 1 : Number(q.slice(2)) || 0;
+		if (range === '' || quality <= best_quality) {
+			continue;
+		}
+		let match = available.find((language) => {
+			let tag = language.toLowerCase();
+			return range === '*' || tag === range || tag.startsWith(range + '-');
+		});
+		if (match !== undefined) {
+			best = match;
+			best_quality = quality;
+		}
+	}
+	return best;
+}
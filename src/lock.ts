@@ -0,0 +1,188 @@
+// WebDAV write locks (RFC 4918 section 6), kept in a KV namespace.
+//
+// Every locked path has one KV entry `lock:<path>` holding the JSON array of its active locks.
+// Entries expire through the KV TTL, the `expires` timestamp of each lock is what decides though,
+// since KV doesn't accept TTLs below 60 seconds. KV is eventually consistent, so two LOCK requests
+// reaching different locations at the same time may both succeed.
+
+import { escape_xml } from './xml';
+
+export type Lock = {
+	token: string;
+	path: string;
+	// The <owner> element sent by the client, serialized
+	owner: string;
+	scope: 'exclusive' | 'shared';
+	depth: '0' | 'infinity';
+	// Seconds
+	timeout: number;
+	// Milliseconds since epoch
+	expires: number;
+};
+
+const KEY_PREFIX = 'lock:';
+const MIN_KV_TTL = 60;
+export const DEFAULT_LOCK_TIMEOUT = 3600;
+export const MAX_LOCK_TIMEOUT = 7 * 24 * 3600;
+
+// Parses a Timeout header like "Second-3600, Infinite" and takes the first usable value
+export function parse_timeout(header: string | null): number {
+	for (let value of (header ?? '').split(',').map((value) => value.trim().toLowerCase())) {
+		if (value === 'infinite') {
+			return MAX_LOCK_TIMEOUT;
+		}
+		let match = value.match(/^second-(\d+)$/);
+		if (match) {
+			return Math.min(Math.max(parseInt(match[1], 10), 1), MAX_LOCK_TIMEOUT);
+		}
+	}
+	return DEFAULT_LOCK_TIMEOUT;
+}
+
+function lock_key(path: string): string {
+	return KEY_PREFIX + path;
+}
+
+function ancestors(path: string): string[] {
+	if (path === '') {
+		return [];
+	}
+	let segments = path.split('/');
+	return segments.map((_, index) => segments.slice(0, index).join('/'));
+}
+
+// The active locks held directly on path. Their path is the one they're looked up by, a lock taken
+// through another folder prefix of the bucket has it relative to that one, see prefix_kv.
+export async function get_locks(kv: KVNamespace, path: string): Promise<Lock[]> {
+	let locks = (await kv.get<Lock[]>(lock_key(path), 'json')) ?? [];
+	let now = Date.now();
+	return locks.filter((lock) => lock.expires > now).map((lock) => ({ ...lock, path }));
+}
+
+async function put_locks(kv: KVNamespace, path: string, locks: Lock[]): Promise<void> {
+	if (locks.length === 0) {
+		await kv.delete(lock_key(path));
+		return;
+	}
+	let expires = Math.max(...locks.map((lock) => lock.expires));
+	await kv.put(lock_key(path), JSON.stringify(locks), {
+		expirationTtl: Math.max(Math.ceil((expires - Date.now()) / 1000), MIN_KV_TTL),
+	});
+}
+
+// The locks covering path, its own ones and the depth infinity locks of its ancestors
+export async function find_locks(kv: KVNamespace, path: string): Promise<Lock[]> {
+	let inherited = await Promise.all(
+		ancestors(path).map(async (ancestor) =>
+			(await get_locks(kv, ancestor)).filter((lock) => lock.depth === 'infinity'),
+		),
+	);
+	return [...inherited.flat(), ...(await get_locks(kv, path))];
+}
+
+// The locks held on the members of the collection at path, at any depth
+export async function find_member_locks(kv: KVNamespace, path: string): Promise<Lock[]> {
+	let prefix = lock_key(path === '' ? '' : path + '/');
+	let keys: string[] = [];
+	let cursor: string | undefined = undefined;
+	while (true) {
+		let result: KVNamespaceListResult<unknown> = await kv.list({ prefix, cursor });
+		keys.push(...result.keys.map((key) => key.name).filter((name) => name !== lock_key(path)));
+		if (result.list_complete) {
+			break;
+		}
+		cursor = result.cursor;
+	}
+	let locks = await Promise.all(keys.map((key) => get_locks(kv, key.slice(KEY_PREFIX.length))));
+	return locks.flat();
+}
+
+// A new lock conflicts with any exclusive lock, and an exclusive one with any lock at all
+export async function find_conflicts(kv: KVNamespace, path: string, scope: Lock['scope'], depth: Lock['depth']) {
+	let locks = await find_locks(kv, path);
+	if (depth === 'infinity') {
+		locks.push(...(await find_member_locks(kv, path)));
+	}
+	return locks.filter((lock) => scope === 'exclusive' || lock.scope === 'exclusive');
+}
+
+export async function create_lock(
+	kv: KVNamespace,
+	path: string,
+	options: Pick<Lock, 'owner' | 'scope' | 'depth' | 'timeout'>,
+): Promise<Lock> {
+	let lock: Lock = {
+		token: `opaquelocktoken:${crypto.randomUUID()}`,
+		path,
+		...options,
+		expires: Date.now() + options.timeout * 1000,
+	};
+	await put_locks(kv, path, [...(await get_locks(kv, path)), lock]);
+	return lock;
+}
+
+// Restarts the timeout of the lock with token if it covers path, returns null when there is no such lock
+export async function refresh_lock(
+	kv: KVNamespace,
+	path: string,
+	token: string,
+	timeout: number,
+): Promise<Lock | null> {
+	let lock = (await find_locks(kv, path)).find((lock) => lock.token === token);
+	if (lock === undefined) {
+		return null;
+	}
+	let refreshed = { ...lock, timeout, expires: Date.now() + timeout * 1000 };
+	let locks = await get_locks(kv, lock.path);
+	await put_locks(kv, lock.path, locks.map((other) => (other.token === token ? refreshed : other)));
+	return refreshed;
+}
+
+// Removes the lock with token if it covers path, returns false when there is no such lock
+export async function remove_lock(kv: KVNamespace, path: string, token: string): Promise<boolean> {
+	let lock = (await find_locks(kv, path)).find((lock) => lock.token === token);
+	if (lock === undefined) {
+		return false;
+	}
+	await put_locks(kv, lock.path, (await get_locks(kv, lock.path)).filter((other) => other.token !== token));
+	return true;
+}
+
+// Every lock needs its token submitted, except that any token of a shared lock unlocks the others on its path
+export function is_unlocked(locks: Lock[], tokens: string[]): boolean {
+	let unlocked_paths = new Set(locks.filter((lock) => tokens.includes(lock.token)).map((lock) => lock.path));
+	return locks.every(
+		(lock) => tokens.includes(lock.token) || (lock.scope === 'shared' && unlocked_paths.has(lock.path)),
+	);
+}
+
+// The locks among locks that cover path, held on it or depth infinity ones held on an ancestor
+export function locks_covering(locks: Lock[], path: string): Lock[] {
+	return locks.filter(
+		(lock) =>
+			lock.path === path ||
+			(lock.depth === 'infinity' && (lock.path === '' || path.startsWith(lock.path + '/'))),
+	);
+}
+
+// The value of the supportedlock property, every resource can be locked either way
+export const SUPPORTED_LOCKS = `<lockentry>
+			<lockscope><exclusive/></lockscope>
+			<locktype><write/></locktype>
+		</lockentry>
+		<lockentry>
+			<lockscope><shared/></lockscope>
+			<locktype><write/></locktype>
+		</lockentry>`;
+
+export function generate_activelock(lock: Lock, href: string): string {
+	return `<activelock>
+			<locktype><write/></locktype>
+			<lockscope><${lock.scope}/></lockscope>
+			<depth>${lock.depth}</depth>
+			${lock.owner}
+			<timeout>Second-${lock.timeout}</timeout>
+			<locktoken><href>${escape_xml(lock.token)}</href></locktoken>
+			<lockroot><href>${escape_xml(href)}</href></lockroot>
+		</activelock>`;
+}
@@ -0,0 +1,32 @@
+// Leveled logging. Every message is one JSON line, so the Workers logs can be filtered on its fields.
+
+const LEVELS = ['error', 'warn', 'info', 'debug'] as const;
+type LogLevel = (typeof LEVELS)[number];
+
+const DEFAULT_LEVEL = LEVELS.indexOf('info');
+let max_level = DEFAULT_LEVEL;
+
+// Takes LOG_LEVEL, an unknown or missing level means info
+export function set_log_level(level: string | undefined) {
+	let index = LEVELS.indexOf((level ?? '').toLowerCase() as LogLevel);
+	max_level = index < 0 ? DEFAULT_LEVEL : index;
+}
+
+function log(level: LogLevel, message: string, fields: Record<string, unknown>) {
+	if (LEVELS.indexOf(level) > max_level) {
+		return;
+	}
+	// Errors have no enumerable properties, JSON would turn them into {}
+	let values = Object.entries(fields).map(([name, value]) => [
+		name,
+		value instanceof Error ? value.stack ?? String(value) : value,
+	]);
+	console[level](JSON.stringify({ level, message, ...Object.fromEntries(values) }));
+}
+
+export const logger = {
+	error: (message: string, fields: Record<string, unknown> = {}) => log('error', message, fields),
+	warn: (message: string, fields: Record<string, unknown> = {}) => log('warn', message, fields),
+	info: (message: string, fields: Record<string, unknown> = {}) => log('info', message, fields),
+	debug: (message: string, fields: Record<string, unknown> = {}) => log('debug', message, fields),
+};
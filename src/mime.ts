@@ -0,0 +1,72 @@
+// Content types guessed from file extensions, for uploads that don't say what they are.
+
+const MIME_TYPES = new Map(
+	Object.entries({
+		// Text
+		css: 'text/css',
+		csv: 'text/csv',
+		htm: 'text/html',
+		html: 'text/html',
+		ics: 'text/calendar',
+		js: 'text/javascript',
+		md: 'text/markdown',
+		mjs: 'text/javascript',
+		txt: 'text/plain',
+		vcf: 'text/vcard',
+		xml: 'application/xml',
+		json: 'application/json',
+		// Images
+		avif: 'image/avif',
+		bmp: 'image/bmp',
+		gif: 'image/gif',
+		heic: 'image/heic',
+		ico: 'image/vnd.microsoft.icon',
+		jpeg: 'image/jpeg',
+		jpg: 'image/jpeg',
+		png: 'image/png',
+		svg: 'image/svg+xml',
+		tif: 'image/tiff',
+		tiff: 'image/tiff',
+		webp: 'image/webp',
+		// Audio and video
+		aac: 'audio/aac',
+		flac: 'audio/flac',
+		m4a: 'audio/mp4',
+		mkv: 'video/x-matroska',
+		mov: 'video/quicktime',
+		mp3: 'audio/mpeg',
+		mp4: 'video/mp4',
+		ogg: 'audio/ogg',
+		wav: 'audio/wav',
+		webm: 'video/webm',
+		// Fonts
+		otf: 'font/otf',
+		ttf: 'font/ttf',
+		woff: 'font/woff',
+		woff2: 'font/woff2',
+		// Documents and archives
+		'7z': 'application/x-7z-compressed',
+		docx: 'application/vnd.openxmlformats-officedocument.wordprocessingml.document',
+		epub: 'application/epub+zip',
+		gz: 'application/gzip',
+		pdf: 'application/pdf',
+		pptx: 'application/vnd.openxmlformats-officedocument.presentationml.presentation',
+		tar: 'application/x-tar',
+		wasm: 'application/wasm',
+		xlsx: 'application/vnd.openxmlformats-officedocument.spreadsheetml.sheet',
+		zip: 'application/zip',
+	}),
+);
+
+// Types that say nothing about the content, a guess from the extension is better than these
+const GENERIC_TYPES = ['application/octet-stream', 'binary/octet-stream'];
+
+export function guess_content_type(path: string): string | undefined {
+	let name = path.split('/').pop() ?? '';
+	let dot = name.lastIndexOf('.');
+	return dot > 0 ? MIME_TYPES.get(name.slice(dot + 1).toLowerCase()) : undefined;
+}
+
+export function is_generic_content_type(content_type: string | null): boolean {
+	return content_type === null || GENERIC_TYPES.includes(content_type.split(';')[0].trim().toLowerCase());
+}
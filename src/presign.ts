@@ -0,0 +1,35 @@
+// Temporary download links. "?expires=<unix seconds>&signature=<hex>" lets anyone download one file
+// until it expires, the signature is an HMAC-SHA256 of its path and expiry keyed with SIGNING_SECRET.
+
+// Seconds, links live an hour unless asked otherwise and a week at most
+export const DEFAULT_LINK_LIFETIME = 3600;
+export const MAX_LINK_LIFETIME = 7 * 24 * 3600;
+
+async function signing_key(secret: string, usage: 'sign' | 'verify'): Promise<CryptoKey> {
+	let raw = new TextEncoder().encode(secret);
+	return await crypto.subtle.importKey('raw', raw, { name: 'HMAC', hash: 'SHA-256' }, false, [usage]);
+}
+
+function signed_data(path: string, expires: number): Uint8Array {
+	return new TextEncoder().encode(`${path}\n${expires}`);
+}
+
+export async function sign_path(secret: string, path: string, expires: number): Promise<string> {
+	let signature = await crypto.subtle.sign('HMAC', await signing_key(secret, 'sign'), signed_data(path, expires));
+	return [...new Uint8Array(signature)].map((byte) => byte.toString(16).padStart(2, '0')).join('');
+}
+
+// Whether signature was made for path and expires, and expires is still ahead
+export async function verify_signature(
+	secret: string,
+	path: string,
+	expires: string,
+	signature: string,
+): Promise<boolean> {
+	let expiry = Number(expires);
+	if (!Number.isInteger(expiry) || expiry * 1000 < Date.now() || !/^([0-9a-f]{2})+$/i.test(signature)) {
+		return false;
+	}
+	let bytes = new Uint8Array(signature.match(/../g)!.map((byte) => parseInt(byte, 16)));
+	return await crypto.subtle.verify('HMAC', await signing_key(secret, 'verify'), bytes, signed_data(path, expiry));
+}
@@ -0,0 +1,137 @@
+// The Multi-Status bodies of PROPFIND responses (RFC 4918 section 9.1).
+//
+// The structure is written with a "D:" prefix for the DAV: namespace. Property values are XML fragments
+// in the namespace of their property, like `<collection />` for resourcetype, so every prop element
+// declares DAV: as the default namespace and other properties declare their own.
+
+import { XmlElement, child_elements, escape_xml, find_child } from './xml';
+
+export type PropertyName = { namespace: string; name: string };
+
+// value is XML, empty for properties without one
+export type Property = PropertyName & { value: string };
+
+export type PropfindEntry = {
+	href: string;
+	properties: Property[];
+};
+
+// What a PROPFIND asks for. allprop returns everything, since all our properties are cheap to compute,
+// so the properties it may <include> are of no concern. A minimal prop leaves out the missing ones.
+export type PropfindRequest =
+	| { mode: 'allprop' }
+	| { mode: 'propname' }
+	| { mode: 'prop'; names: PropertyName[]; minimal?: boolean };
+
+// What a parsed PROPFIND body asks for, no body at all asks for allprop. Null when it asks for nothing.
+export function parse_propfind(propfind: XmlElement | null): PropfindRequest | null {
+	if (propfind === null || find_child(propfind, 'DAV:', 'allprop') !== undefined) {
+		return { mode: 'allprop' };
+	} else if (find_child(propfind, 'DAV:', 'propname') !== undefined) {
+		return { mode: 'propname' };
+	}
+	let prop = find_child(propfind, 'DAV:', 'prop');
+	if (prop === undefined) {
+		return null;
+	}
+	return { mode: 'prop', names: child_elements(prop).map(({ namespace, name }) => ({ namespace, name })) };
+}
+
+// What an allprop still returns with Prefer: return=minimal
+const MINIMAL_PROPERTIES: PropertyName[] = [{ namespace: 'DAV:', name: 'resourcetype' }];
+
+// The request as Prefer: return=minimal (RFC 8144) shrinks it: allprop only returns resourcetype, and
+// the properties an entry doesn't have aren't listed with 404. propname is left as it is.
+export function minimal_propfind(request: PropfindRequest): PropfindRequest {
+	if (request.mode === 'allprop') {
+		return { mode: 'prop', names: MINIMAL_PROPERTIES, minimal: true };
+	} else if (request.mode === 'prop') {
+		return { ...request, minimal: true };
+	}
+	return request;
+}
+
+function same_name(a: PropertyName, b: PropertyName): boolean {
+	return a.namespace === b.namespace && a.name === b.name;
+}
+
+function generate_property(property: PropertyName, value: string = ''): string {
+	let tag = property.namespace === 'DAV:' ? `D:${property.name}` : property.name;
+	let attributes = property.namespace === 'DAV:' ? '' : ` xmlns="${escape_xml(property.namespace)}"`;
+	return value === '' ? `<${tag}${attributes}/>` : `<${tag}${attributes}>${value}</${tag}>`;
+}
+
+function generate_propstat(properties: string[], status: string): string {
+	return `
+		<D:propstat>
+			<D:prop xmlns="DAV:">
+				${properties.join('\n				')}
+			</D:prop>
+			<D:status>HTTP/1.1 ${status}</D:status>
+		</D:propstat>`;
+}
+
+// One response element. Requested properties the resource doesn't have are listed with 404.
+function generate_propfind_response(entry: PropfindEntry, request: PropfindRequest): string {
+	let propstats: string[] = [];
+	if (request.mode === 'allprop') {
+		let found = entry.properties.map((property) => generate_property(property, property.value));
+		propstats.push(generate_propstat(found, '200 OK'));
+	} else if (request.mode === 'propname') {
+		propstats.push(generate_propstat(entry.properties.map((property) => generate_property(property)), '200 OK'));
+	} else {
+		let found: string[] = [];
+		let missing: string[] = [];
+		for (let name of request.names) {
+			let property = entry.properties.find((property) => same_name(property, name));
+			if (property === undefined) {
+				missing.push(generate_property(name));
+			} else {
+				found.push(generate_property(property, property.value));
+			}
+		}
+		if (found.length > 0 || missing.length === 0 || request.minimal) {
+			propstats.push(generate_propstat(found, '200 OK'));
+		}
+		if (missing.length > 0 && !request.minimal) {
+			propstats.push(generate_propstat(missing, '404 Not Found'));
+		}
+	}
+	return `
+	<D:response>
+		<D:href>${escape_xml(entry.href)}</D:href>${propstats.join('')}
+	</D:response>`;
+}
+
+const MULTISTATUS_START = `<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">`;
+const MULTISTATUS_END = `
+</D:multistatus>
+`;
+
+// The body is written as the entries arrive, so a big listing is never held in memory as a whole.
+// Once the 207 is sent an error can't change it anymore, the body just breaks off.
+export function stream_multistatus(
+	entries: AsyncIterable<PropfindEntry> | Iterable<PropfindEntry>,
+	request: PropfindRequest,
+): ReadableStream<Uint8Array> {
+	let encoder = new TextEncoder();
+	let iterator = Symbol.asyncIterator in entries ? entries[Symbol.asyncIterator]() : entries[Symbol.iterator]();
+	return new ReadableStream({
+		start(controller) {
+			controller.enqueue(encoder.encode(MULTISTATUS_START));
+		},
+		async pull(controller) {
+			let { done, value } = await iterator.next();
+			if (done) {
+				controller.enqueue(encoder.encode(MULTISTATUS_END));
+				controller.close();
+			} else {
+				controller.enqueue(encoder.encode(generate_propfind_response(value, request)));
+			}
+		},
+		async cancel() {
+			await iterator.return?.();
+		},
+	});
+}
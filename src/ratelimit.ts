@@ -0,0 +1,61 @@
+// Requests per identity, an authenticated user or else a client IP, counted in KV over fixed windows.
+//
+// The count of the current window is kept in the entry `ratelimit:<kind>:<identity>:<window start>`.
+// KV has no atomic increment and takes about one write per key and second, so counts are approximate:
+// concurrent requests may be counted as one, and a burst can get a little past the limit.
+
+export type RateLimit = {
+	requests: number;
+	// Seconds
+	window: number;
+};
+
+export type RequestKind = 'read' | 'write';
+
+const KEY_PREFIX = 'ratelimit:';
+// KV entries can't expire any sooner
+const MIN_TTL = 60;
+// Methods counted as reads, every other one is a write
+const READ_METHODS = ['GET', 'HEAD', 'OPTIONS', 'PROPFIND'];
+
+export function request_kind(method: string): RequestKind {
+	return READ_METHODS.includes(method) ? 'read' : 'write';
+}
+
+function current_window(kind: RequestKind, identity: string, limit: RateLimit): { key: string; retry_after: number } {
+	let now = Math.floor(Date.now() / 1000);
+	let start = now - (now % limit.window);
+	return { key: `${KEY_PREFIX}${kind}:${identity}:${start}`, retry_after: start + limit.window - now };
+}
+
+// Null when identity still has requests left in the current window, and the seconds until it ends
+// otherwise. The request isn't counted.
+export async function peek_requests(
+	kv: KVNamespace,
+	kind: RequestKind,
+	identity: string,
+	limit: RateLimit,
+): Promise<number | null> {
+	let { key, retry_after } = current_window(kind, identity, limit);
+	let count = Number((await kv.get(key)) ?? 0);
+	return count >= limit.requests ? retry_after : null;
+}
+
+// Counts a request, null when it's within the limit and the seconds until the window ends otherwise.
+// The request doesn't wait for the count to be written, a write KV refuses only lowers it.
+export async function count_request(
+	kv: KVNamespace,
+	kind: RequestKind,
+	identity: string,
+	limit: RateLimit,
+	ctx: ExecutionContext,
+): Promise<number | null> {
+	let { key, retry_after } = current_window(kind, identity, limit);
+	let count = Number((await kv.get(key)) ?? 0);
+	if (count >= limit.requests) {
+		return retry_after;
+	}
+	let expirationTtl = Math.max(limit.window, MIN_TTL);
+	ctx.waitUntil(kv.put(key, String(count + 1), { expirationTtl }).catch(() => {}));
+	return null;
+}
@@ -0,0 +1,85 @@
+// Resumable uploads, PUT requests carrying "Content-Range: bytes start-end/total".
+//
+// Every chunk becomes a part of an R2 multipart upload. The upload in progress for a path is kept in
+// the KV entry `upload:<path>`, a new first chunk starts it over. R2 needs all parts but the last to
+// have the same size, so the first chunk decides the size of the following ones.
+
+export type Upload = {
+	upload_id: string;
+	total: number;
+	part_size: number;
+	// Offset the next chunk has to start at
+	received: number;
+	parts: R2UploadedPart[];
+};
+
+export type ChunkRange = {
+	start: number;
+	end: number;
+	total: number;
+};
+
+const KEY_PREFIX = 'upload:';
+// R2 aborts incomplete multipart uploads after a week
+export const UPLOAD_TTL = 7 * 24 * 3600;
+// R2 refuses smaller parts, except for the last one, and bigger ones
+export const MIN_PART_SIZE = 5 * 1024 * 1024;
+export const MAX_PART_SIZE = 5 * 1024 * 1024 * 1024;
+
+function upload_key(path: string): string {
+	return KEY_PREFIX + path;
+}
+
+// Parses "bytes 0-1048575/4194304", null when it's malformed or the total length is unknown
+export function parse_content_range(header: string): ChunkRange | null {
+	let match = header.trim().match(/^bytes (\d+)-(\d+)\/(\d+)$/i);
+	if (match === null) {
+		return null;
+	}
+	let [start, end, total] = match.slice(1).map(Number);
+	return start <= end && end < total ? { start, end, total } : null;
+}
+
+export async function get_upload(kv: KVNamespace, path: string): Promise<Upload | null> {
+	return await kv.get<Upload>(upload_key(path), 'json');
+}
+
+// Kept as the metadata of the KV entry as well, so stale uploads are found by listing the keys
+type UploadMetadata = {
+	upload_id: string;
+	// Milliseconds since epoch of the last chunk
+	updated: number;
+};
+
+export async function put_upload(kv: KVNamespace, path: string, upload: Upload): Promise<void> {
+	let metadata: UploadMetadata = { upload_id: upload.upload_id, updated: Date.now() };
+	await kv.put(upload_key(path), JSON.stringify(upload), { expirationTtl: UPLOAD_TTL, metadata });
+}
+
+// The uploads that haven't received a chunk for max_age seconds, with the id of their multipart upload
+export async function find_stale_uploads(
+	kv: KVNamespace,
+	max_age: number,
+): Promise<{ path: string; upload_id: string }[]> {
+	let cutoff = Date.now() - max_age * 1000;
+	let stale: { path: string; upload_id: string }[] = [];
+	let cursor: string | undefined = undefined;
+	while (true) {
+		let result: KVNamespaceListResult<UploadMetadata> = await kv.list<UploadMetadata>({ prefix: KEY_PREFIX, cursor });
+		for (let { name, metadata } of result.keys) {
+			// Entries stored without metadata are left to expire
+			if (metadata !== undefined && metadata.updated < cutoff) {
+				stale.push({ path: name.slice(KEY_PREFIX.length), upload_id: metadata.upload_id });
+			}
+		}
+		if (result.list_complete) {
+			break;
+		}
+		cursor = result.cursor;
+	}
+	return stale;
+}
+
+export async function delete_upload(kv: KVNamespace, path: string): Promise<void> {
+	await kv.delete(upload_key(path));
+}
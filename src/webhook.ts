@@ -0,0 +1,61 @@
+// Webhooks, a POST to WEBHOOK_URL with a JSON event for every file a request wrote to or deleted from
+// either bucket, `{"events": [...]}` in the order they happened.
+//
+// The body is signed with an HMAC-SHA256 keyed with WEBHOOK_SECRET, sent as "X-Webhook-Signature: sha256=<hex>"
+// so receivers can tell it came from here. Deliveries are best effort, a failed one is logged and not retried.
+
+import { logger } from './log';
+
+export type WebhookEvent = {
+	event: 'created' | 'deleted';
+	// The binding of the bucket, "bucket" or "secondary_bucket"
+	bucket: string;
+	// The key in that bucket
+	path: string;
+	// Null for deletes, only the key is known
+	size: number | null;
+	etag: string | null;
+	// ISO 8601
+	timestamp: string;
+};
+
+const SIGNATURE_HEADER = 'X-Webhook-Signature';
+
+// object is the one written, null for a delete
+export function object_event(bucket: string, key: string, object: R2Object | null): WebhookEvent {
+	return {
+		event: object === null ? 'deleted' : 'created',
+		bucket,
+		path: key,
+		size: object?.size ?? null,
+		etag: object?.httpEtag ?? null,
+		timestamp: new Date().toISOString(),
+	};
+}
+
+async function sign_body(secret: string, body: string): Promise<string> {
+	let encoder = new TextEncoder();
+	let raw = encoder.encode(secret);
+	let key = await crypto.subtle.importKey('raw', raw, { name: 'HMAC', hash: 'SHA-256' }, false, ['sign']);
+	let signature = await crypto.subtle.sign('HMAC', key, encoder.encode(body));
+	return [...new Uint8Array(signature)].map((byte) => byte.toString(16).padStart(2, '0')).join('');
+}
+
+export async function send_webhook(url: string, secret: string, events: WebhookEvent[]): Promise<void> {
+	let body = JSON.stringify({ events });
+	try {
+		let response = await fetch(url, {
+			method: 'POST',
+			headers: {
+				'Content-Type': 'application/json',
+				[SIGNATURE_HEADER]: `sha256=${await sign_body(secret, body)}`,
+			},
+			body,
+		});
+		if (!response.ok) {
+			logger.error('Webhook delivery failed', { count: events.length, status: response.status });
+		}
+	} catch (error) {
+		logger.error('Webhook delivery failed', { count: events.length, error });
+	}
+}
@@ -0,0 +1,235 @@
+// A small, non-validating XML parser for WebDAV request bodies, with namespace resolution.
+
+export type XmlElement = {
+	namespace: string;
+	name: string;
+	attributes: Record<string, string>;
+	children: XmlNode[];
+};
+
+export type XmlNode = XmlElement | string;
+
+export class XmlError extends Error {}
+
+const NAME_PATTERN = /[^\s<>\/=!?"']+/y;
+const ENTITIES: Record<string, string> = { lt: '<', gt: '>', amp: '&', quot: '"', apos: "'" };
+
+function decode_entities(text: string): string {
+	if (/&(?!(#x[0-9a-fA-F]+|#[0-9]+|lt|gt|amp|quot|apos);)/.test(text)) {
+		throw new XmlError('Invalid entity reference');
+	}
+	return text.replace(/&(#x[0-9a-fA-F]+|#[0-9]+|lt|gt|amp|quot|apos);/g, (_, entity: string) => {
+		if (entity.startsWith('#x')) {
+			return String.fromCodePoint(parseInt(entity.slice(2), 16));
+		} else if (entity.startsWith('#')) {
+			return String.fromCodePoint(parseInt(entity.slice(1), 10));
+		}
+		return ENTITIES[entity];
+	});
+}
+
+class XmlParser {
+	private position = 0;
+
+	constructor(private readonly source: string) {}
+
+	parse(): XmlElement {
+		this.skip_misc();
+		if (!this.starts_with('<')) {
+			throw new XmlError('Expected a root element');
+		}
+		let root = this.parse_element(new Map([['', '']]));
+		this.skip_misc();
+		if (this.position < this.source.length) {
+			throw new XmlError('Unexpected content after the root element');
+		}
+		return root;
+	}
+
+	private starts_with(value: string): boolean {
+		return this.source.startsWith(value, this.position);
+	}
+
+	private expect(value: string) {
+		if (!this.starts_with(value)) {
+			throw new XmlError(`Expected "${value}" at offset ${this.position}`);
+		}
+		this.position += value.length;
+	}
+
+	private skip_whitespace() {
+		while (this.position < this.source.length && /\s/.test(this.source[this.position])) {
+			this.position++;
+		}
+	}
+
+	private skip_past(value: string) {
+		let end = this.source.indexOf(value, this.position);
+		if (end < 0) {
+			throw new XmlError(`Missing "${value}"`);
+		}
+		this.position = end + value.length;
+	}
+
+	private read_name(): string {
+		NAME_PATTERN.lastIndex = this.position;
+		let match = NAME_PATTERN.exec(this.source);
+		if (match === null) {
+			throw new XmlError(`Expected a name at offset ${this.position}`);
+		}
+		this.position += match[0].length;
+		return match[0];
+	}
+
+	// Whitespace, comments, processing instructions and the doctype around the root element
+	private skip_misc() {
+		while (true) {
+			this.skip_whitespace();
+			if (this.starts_with('<?')) {
+				this.skip_past('?>');
+			} else if (this.starts_with('<!--')) {
+				this.skip_past('-->');
+			} else if (this.starts_with('<!DOCTYPE')) {
+				let end = this.source.indexOf('>', this.position);
+				// Entity declarations are never needed by WebDAV, refuse them instead of expanding them
+				if (end < 0 || this.source.slice(this.position, end).includes('[')) {
+					throw new XmlError('Unsupported doctype');
+				}
+				this.position = end + 1;
+			} else {
+				return;
+			}
+		}
+	}
+
+	private parse_element(scope: Map<string, string>): XmlElement {
+		this.expect('<');
+		let qualified_name = this.read_name();
+
+		let raw_attributes: [string, string][] = [];
+		while (true) {
+			this.skip_whitespace();
+			if (this.starts_with('/>') || this.starts_with('>')) {
+				break;
+			}
+			let name = this.read_name();
+			this.skip_whitespace();
+			this.expect('=');
+			this.skip_whitespace();
+			let quote = this.source[this.position];
+			if (quote !== '"' && quote !== "'") {
+				throw new XmlError(`Expected a quoted value for attribute ${name}`);
+			}
+			let end = this.source.indexOf(quote, this.position + 1);
+			if (end < 0) {
+				throw new XmlError(`Unterminated value for attribute ${name}`);
+			}
+			raw_attributes.push([name, decode_entities(this.source.slice(this.position + 1, end))]);
+			this.position = end + 1;
+		}
+
+		let namespaces = new Map(scope);
+		let attributes: Record<string, string> = {};
+		for (let [name, value] of raw_attributes) {
+			if (name === 'xmlns') {
+				namespaces.set('', value);
+			} else if (name.startsWith('xmlns:')) {
+				namespaces.set(name.slice('xmlns:'.length), value);
+			} else {
+				attributes[name] = value;
+			}
+		}
+
+		let element: XmlElement = { ...resolve_name(qualified_name, namespaces), attributes, children: [] };
+		if (this.starts_with('/>')) {
+			this.position += 2;
+			return element;
+		}
+		this.position += 1;
+
+		while (true) {
+			if (this.position >= this.source.length) {
+				throw new XmlError(`Unclosed element <${qualified_name}>`);
+			}
+			if (this.starts_with('</')) {
+				this.position += 2;
+				let end_name = this.read_name();
+				if (end_name !== qualified_name) {
+					throw new XmlError(`Mismatched end tag </${end_name}> for <${qualified_name}>`);
+				}
+				this.skip_whitespace();
+				this.expect('>');
+				return element;
+			} else if (this.starts_with('<!--')) {
+				this.skip_past('-->');
+			} else if (this.starts_with('<![CDATA[')) {
+				let start = this.position + '<![CDATA['.length;
+				this.skip_past(']]>');
+				element.children.push(this.source.slice(start, this.position - ']]>'.length));
+			} else if (this.starts_with('<?')) {
+				this.skip_past('?>');
+			} else if (this.starts_with('<')) {
+				element.children.push(this.parse_element(namespaces));
+			} else {
+				let end = this.source.indexOf('<', this.position);
+				end = end < 0 ? this.source.length : end;
+				element.children.push(decode_entities(this.source.slice(this.position, end)));
+				this.position = end;
+			}
+		}
+	}
+}
+
+function resolve_name(qualified_name: string, namespaces: Map<string, string>): { namespace: string; name: string } {
+	let colon = qualified_name.indexOf(':');
+	let prefix = colon < 0 ? '' : qualified_name.slice(0, colon);
+	let namespace = namespaces.get(prefix);
+	if (namespace === undefined) {
+		throw new XmlError(`Undeclared namespace prefix "${prefix}"`);
+	}
+	return { namespace, name: qualified_name.slice(colon + 1) };
+}
+
+export function parse_xml(source: string): XmlElement {
+	return new XmlParser(source).parse();
+}
+
+export function escape_xml(value: string): string {
+	return value
+		.replace(/&/g, '&amp;')
+		.replace(/</g, '&lt;')
+		.replace(/>/g, '&gt;')
+		.replace(/"/g, '&quot;')
+		.replace(/'/g, '&apos;');
+}
+
+export function child_elements(element: XmlElement): XmlElement[] {
+	return element.children.filter((node): node is XmlElement => typeof node !== 'string');
+}
+
+export function find_child(element: XmlElement, namespace: string, name: string): XmlElement | undefined {
+	return child_elements(element).find((child) => child.namespace === namespace && child.name === name);
+}
+
+export function text_content(node: XmlNode): string {
+	return typeof node === 'string' ? node : node.children.map(text_content).join('');
+}
+
+// Serializes a node back to XML, declaring namespaces whenever they differ from the enclosing one
+export function serialize_xml(node: XmlNode, parent_namespace: string = 'DAV:'): string {
+	if (typeof node === 'string') {
+		return escape_xml(node);
+	}
+	let attributes = Object.entries(node.attributes)
+		.filter(([name]) => !name.includes(':'))
+		.map(([name, value]) => ` ${name}="${escape_xml(value)}"`)
+		.join('');
+	if (node.namespace !== parent_namespace) {
+		attributes = ` xmlns="${escape_xml(node.namespace)}"` + attributes;
+	}
+	if (node.children.length === 0) {
+		return `<${node.name}${attributes}/>`;
+	}
+	let content = node.children.map((child) => serialize_xml(child, node.namespace)).join('');
+	return `<${node.name}${attributes}>${content}</${node.name}>`;
+}
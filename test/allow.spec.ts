@@ -0,0 +1,48 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, put_object } from './helpers';
+
+const WRITABLE_METHODS = 'OPTIONS, PROPFIND, GET, HEAD, PROPPATCH, MKCOL, PUT, DELETE, COPY, MOVE';
+
+describe('Allow header of 405s', () => {
+	it('lists every registered method for an unknown one', async () => {
+		let response = await dav('PATCH', '/a.txt');
+		expect(response.status).toBe(405);
+		expect(response.headers.get('Allow')).toBe(WRITABLE_METHODS);
+	});
+
+	it('adds locking with a KV namespace', async () => {
+		let response = await dav('PATCH', '/a.txt', { vars: { kv: env.kv } });
+		expect(response.headers.get('Allow')).toBe(WRITABLE_METHODS + ', LOCK, UNLOCK');
+	});
+
+	it('only lists the reads for a DELETE on a read-only bucket', async () => {
+		let response = await dav('DELETE', '/a.txt', { vars: { READ_ONLY: 'true' } });
+		expect(response.status).toBe(405);
+		expect(response.headers.get('Allow')).toBe('OPTIONS, PROPFIND, GET, HEAD');
+	});
+
+	it('leaves out MKCOL for what already exists', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('MKCOL', '/a.txt');
+		expect(response.status).toBe(405);
+		expect(response.headers.get('Allow')!.split(', ')).not.toContain('MKCOL');
+	});
+
+	it('leaves out PUT for a collection', async () => {
+		let response = await dav('PUT', '/docs/', { body: 'a' });
+		expect(response.status).toBe(405);
+		expect(response.headers.get('Allow')!.split(', ')).not.toContain('PUT');
+	});
+});
+
+describe('OPTIONS *', () => {
+	it('answers with the capabilities of the server and no body', async () => {
+		let response = await dav('OPTIONS', '/*', { anonymous: true });
+		expect(response.status).toBe(204);
+		expect(response.headers.get('Allow')).toBe(WRITABLE_METHODS);
+		expect(response.headers.get('DAV')).toBe('1, 3');
+		expect(response.headers.get('MS-Author-Via')).toBe('DAV');
+		expect(await response.text()).toBe('');
+	});
+});
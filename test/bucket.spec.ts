@@ -0,0 +1,145 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { budget_bucket, metadata_only, notify_bucket, prefix_bucket, prefix_kv, retry_bucket } from '../src/bucket';
+import { BudgetExhausted } from '../src/error';
+import { stub_bucket } from './helpers';
+
+describe('prefix_bucket', () => {
+	it('keeps the keys under the prefix', async () => {
+		let bucket = prefix_bucket(env.bucket, 'users/a/');
+		let object = await bucket.put('docs/a.txt', 'a');
+		expect(object.key).toBe('docs/a.txt');
+		expect(await env.bucket.head('users/a/docs/a.txt')).not.toBeNull();
+		expect((await bucket.head('docs/a.txt'))?.key).toBe('docs/a.txt');
+		expect(await (await bucket.get('docs/a.txt'))?.text()).toBe('a');
+	});
+
+	it('only lists the keys under the prefix, without it', async () => {
+		await env.bucket.put('users/a/docs/a.txt', 'a');
+		await env.bucket.put('users/a/b.txt', 'b');
+		await env.bucket.put('users/b/c.txt', 'c');
+		let bucket = prefix_bucket(env.bucket, 'users/a/');
+		let listing = await bucket.list({ delimiter: '/' });
+		expect(listing.objects.map((object) => object.key)).toEqual(['b.txt']);
+		expect(listing.delimitedPrefixes).toEqual(['docs/']);
+		let after = await bucket.list({ startAfter: 'b.txt' });
+		expect(after.objects.map((object) => object.key)).toEqual(['docs/a.txt']);
+	});
+
+	it('deletes the keys under the prefix', async () => {
+		await env.bucket.put('users/a/a.txt', 'a');
+		await env.bucket.put('a.txt', 'a');
+		await prefix_bucket(env.bucket, 'users/a/').delete(['a.txt']);
+		expect(await env.bucket.head('users/a/a.txt')).toBeNull();
+		expect(await env.bucket.head('a.txt')).not.toBeNull();
+	});
+});
+
+describe('budget_bucket', () => {
+	it('counts every call and refuses them once the budget is spent', async () => {
+		let budget = { remaining: 2 };
+		let bucket = budget_bucket(env.bucket, budget);
+		await bucket.put('a.txt', 'a');
+		await bucket.head('a.txt');
+		expect(budget.remaining).toBe(0);
+		await expect(bucket.get('a.txt')).rejects.toBeInstanceOf(BudgetExhausted);
+		expect(budget.remaining).toBe(0);
+	});
+});
+
+describe('notify_bucket', () => {
+	it('reports writes and deletes once they are done', async () => {
+		let notified: [string, string | null][] = [];
+		let bucket = notify_bucket(env.bucket, (key, object) => notified.push([key, object?.etag ?? null]));
+		let object = await bucket.put('a.txt', 'a');
+		await bucket.delete(['a.txt', 'b.txt']);
+		expect(notified).toEqual([
+			['a.txt', object.etag],
+			['a.txt', null],
+			['b.txt', null],
+		]);
+	});
+
+	it("doesn't report writes whose condition failed", async () => {
+		await env.bucket.put('a.txt', 'a');
+		let notified: string[] = [];
+		let bucket = notify_bucket(env.bucket, (key) => notified.push(key));
+		expect(await bucket.put('a.txt', 'b', { onlyIf: { etagMatches: 'other' } })).toBeNull();
+		expect(notified).toEqual([]);
+	});
+
+	it("doesn't report puts of metadata only", async () => {
+		let notified: string[] = [];
+		let bucket = notify_bucket(env.bucket, (key) => notified.push(key));
+		await bucket.put('a.txt', 'a', metadata_only({ customMetadata: { color: 'blue' } }));
+		expect(notified).toEqual([]);
+		expect((await env.bucket.head('a.txt'))?.customMetadata).toEqual({ color: 'blue' });
+	});
+});
+
+describe('retry_bucket', () => {
+	// A bucket whose first few gets fail with error, as many as failures
+	const failing_bucket = (failures: number, error: string) => {
+		let calls = 0;
+		let bucket = stub_bucket({
+			get: (key: string, options?: R2GetOptions) => {
+				calls++;
+				return calls <= failures ? Promise.reject(new Error(error)) : env.bucket.get(key, options);
+			},
+		});
+		return { bucket, calls: () => calls };
+	};
+	const INTERNAL_ERROR = 'get: We encountered an internal error. Please try again. (10001)';
+
+	it('retries transient errors until the operation goes through', async () => {
+		await env.bucket.put('a.txt', 'a');
+		let { bucket, calls } = failing_bucket(2, INTERNAL_ERROR);
+		let object = await retry_bucket(bucket, 2).get('a.txt');
+		expect(await object?.text()).toBe('a');
+		expect(calls()).toBe(3);
+	});
+
+	it('gives up after max_retries', async () => {
+		let { bucket, calls } = failing_bucket(3, INTERNAL_ERROR);
+		await expect(retry_bucket(bucket, 2).get('a.txt')).rejects.toThrow('(10001)');
+		expect(calls()).toBe(3);
+	});
+
+	it("doesn't retry other errors", async () => {
+		let { bucket, calls } = failing_bucket(1, 'get: Unauthorized (10002)');
+		await expect(retry_bucket(bucket, 2).get('a.txt')).rejects.toThrow('(10002)');
+		expect(calls()).toBe(1);
+	});
+
+	it("doesn't retry conditional puts", async () => {
+		let calls = 0;
+		let bucket = stub_bucket({
+			put: () => {
+				calls++;
+				return Promise.reject(new Error(INTERNAL_ERROR));
+			},
+		});
+		let put = retry_bucket(bucket, 2).put('a.txt', 'a', { onlyIf: { etagDoesNotMatch: '*' } });
+		await expect(put).rejects.toThrow('(10001)');
+		expect(calls).toBe(1);
+	});
+});
+
+describe('prefix_kv', () => {
+	it('keys the entries by their path under the prefix', async () => {
+		let kv = prefix_kv(env.kv!, 'users/a/');
+		await kv.put('lock:docs/a.txt', 'x');
+		await kv.put('lock:', 'root');
+		expect(await env.kv!.get('lock:users/a/docs/a.txt')).toBe('x');
+		expect(await env.kv!.get('lock:users/a')).toBe('root');
+		expect(await kv.get('lock:docs/a.txt')).toBe('x');
+	});
+
+	it('lists the entries under the prefix, without it', async () => {
+		await env.kv!.put('lock:users/a/docs/a.txt', 'x');
+		await env.kv!.put('lock:users/b/docs/b.txt', 'y');
+		let kv = prefix_kv(env.kv!, 'users/a/');
+		let listing = await kv.list({ prefix: 'lock:docs/' });
+		expect(listing.keys.map((key) => key.name)).toEqual(['lock:docs/a.txt']);
+	});
+});
@@ -0,0 +1,49 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, put_object, stub_bucket } from './helpers';
+
+// The cache outlives each test's storage, so every test downloads paths of its own
+describe('CACHE_ENABLED', () => {
+	let gets = 0;
+	const bucket = stub_bucket({
+		get: (key: string, options?: R2GetOptions) => {
+			gets++;
+			return env.bucket.get(key, options);
+		},
+	});
+	const get = async (path: string, headers: Record<string, string> = {}) => {
+		let response = await dav('GET', path, { headers, vars: { bucket, CACHE_ENABLED: 'true' } });
+		return await response.text();
+	};
+
+	it('serves repeated downloads from the cache', async () => {
+		await put_object('repeated.txt', 'content');
+		gets = 0;
+		expect(await get('/repeated.txt')).toBe('content');
+		expect(await get('/repeated.txt')).toBe('content');
+		expect(gets).toBe(1);
+	});
+
+	it('serves the new content once the object was overwritten through the worker', async () => {
+		await put_object('overwritten.txt', 'old');
+		expect(await get('/overwritten.txt')).toBe('old');
+		let response = await dav('PUT', '/overwritten.txt', { body: 'new', vars: { bucket, CACHE_ENABLED: 'true' } });
+		expect(response.status).toBe(204);
+		expect(await get('/overwritten.txt')).toBe('new');
+	});
+
+	it('serves the new content once the object was overwritten elsewhere', async () => {
+		await put_object('elsewhere.txt', 'old');
+		expect(await get('/elsewhere.txt')).toBe('old');
+		await put_object('elsewhere.txt', 'new');
+		expect(await get('/elsewhere.txt')).toBe('new');
+	});
+
+	it('reads ranges from R2', async () => {
+		await put_object('ranged.txt', '0123456789');
+		expect(await get('/ranged.txt')).toBe('0123456789');
+		gets = 0;
+		expect(await get('/ranged.txt', { Range: 'bytes=2-4' })).toBe('234');
+		expect(gets).toBe(1);
+	});
+});
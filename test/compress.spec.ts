@@ -0,0 +1,96 @@
+import { describe, expect, it } from 'vitest';
+import { is_compressible, negotiate_encoding } from '../src/compress';
+import { dav, put_object } from './helpers';
+
+// The runtime compresses the body of a response carrying Content-Encoding on its way out, a test calling
+// the worker directly only sees the headers that ask for it.
+describe('compressed downloads', () => {
+	const vars = { COMPRESSION_ENABLED: 'true' };
+	const text_plain = { httpMetadata: { contentType: 'text/plain' } };
+	const text = async () => await put_object('a.txt', 'hello '.repeat(100), text_plain);
+
+	it('compresses a text file for a client accepting gzip', async () => {
+		await text();
+		let response = await dav('GET', '/a.txt', { headers: { 'Accept-Encoding': 'gzip' }, vars });
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Encoding')).toBe('gzip');
+		expect(response.headers.get('Vary')).toContain('Accept-Encoding');
+		expect(response.headers.get('Content-Length')).toBeNull();
+		expect(response.headers.get('ETag')).toMatch(/^W\//);
+	});
+
+	it('sends it as stored to a client accepting no encoding', async () => {
+		let object = await text();
+		let response = await dav('GET', '/a.txt', { vars });
+		expect(response.headers.get('Content-Encoding')).toBeNull();
+		expect(response.headers.get('Vary')).toContain('Accept-Encoding');
+		expect(response.headers.get('Content-Length')).toBe(object.size.toString());
+		expect(await response.text()).toBe('hello '.repeat(100));
+	});
+
+	it('is off without COMPRESSION_ENABLED', async () => {
+		await text();
+		let response = await dav('GET', '/a.txt', { headers: { 'Accept-Encoding': 'gzip' } });
+		expect(response.headers.get('Content-Encoding')).toBeNull();
+	});
+
+	it("doesn't compress types outside COMPRESSIBLE_TYPES", async () => {
+		await put_object('a.png', 'png', { httpMetadata: { contentType: 'image/png' } });
+		let response = await dav('GET', '/a.png', { headers: { 'Accept-Encoding': 'gzip' }, vars });
+		expect(response.headers.get('Content-Encoding')).toBeNull();
+	});
+
+	it("doesn't compress ranges", async () => {
+		await text();
+		let headers = { 'Accept-Encoding': 'gzip', Range: 'bytes=0-4' };
+		let response = await dav('GET', '/a.txt', { headers, vars });
+		expect(response.status).toBe(206);
+		expect(response.headers.get('Content-Encoding')).toBeNull();
+		expect(await response.text()).toBe('hello');
+	});
+
+	it('sends objects stored encoded with their own encoding', async () => {
+		let httpMetadata = { contentType: 'text/plain', contentEncoding: 'gzip' };
+		await put_object('a.txt.gz', 'gzipped', { httpMetadata });
+		let response = await dav('GET', '/a.txt.gz', { headers: { 'Accept-Encoding': 'br, gzip' }, vars });
+		expect(response.headers.get('Content-Encoding')).toBe('gzip');
+		expect(response.headers.get('ETag')).not.toMatch(/^W\//);
+	});
+});
+
+describe('negotiate_encoding', () => {
+	it('prefers brotli, then gzip, then identity', () => {
+		expect(negotiate_encoding('br, gzip')).toBe('br');
+		expect(negotiate_encoding('gzip, br')).toBe('br');
+		expect(negotiate_encoding('gzip, deflate')).toBe('gzip');
+		expect(negotiate_encoding('deflate')).toBeNull();
+		expect(negotiate_encoding(null)).toBeNull();
+	});
+
+	it('goes by the q-values of the client', () => {
+		expect(negotiate_encoding('br;q=0.5, gzip')).toBe('gzip');
+		expect(negotiate_encoding('br;q=0, gzip;q=0.1')).toBe('gzip');
+		expect(negotiate_encoding('*;q=0.5, gzip;q=0')).toBe('br');
+		expect(negotiate_encoding('br;q=0, gzip;q=0')).toBeNull();
+	});
+});
+
+describe('is_compressible', () => {
+	it('matches the allowlist, wildcards included', () => {
+		expect(is_compressible('text/html; charset=utf-8')).toBe(true);
+		expect(is_compressible('application/json')).toBe(true);
+		expect(is_compressible('image/png')).toBe(false);
+		expect(is_compressible(undefined)).toBe(false);
+		expect(is_compressible('image/png', 'image/*')).toBe(true);
+	});
+});
+
+describe('brotli downloads', () => {
+	it('are sent to a client accepting br and gzip', async () => {
+		await put_object('a.txt', 'hello '.repeat(100), { httpMetadata: { contentType: 'text/plain' } });
+		let headers = { 'Accept-Encoding': 'br, gzip' };
+		let response = await dav('GET', '/a.txt', { headers, vars: { COMPRESSION_ENABLED: 'true' } });
+		expect(response.headers.get('Content-Encoding')).toBe('br');
+		expect(response.headers.get('Vary')).toContain('Accept-Encoding');
+	});
+});
@@ -0,0 +1,174 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, list_keys, put_collection, put_object, stub_bucket } from './helpers';
+
+describe.each(['COPY', 'MOVE'])('%s outcomes', (method) => {
+	const transfer = (destination: string, overwrite?: 'T' | 'F') => {
+		let headers: Record<string, string> = { Destination: destination };
+		if (overwrite !== undefined) {
+			headers.Overwrite = overwrite;
+		}
+		return dav(method, '/a.txt', { headers });
+	};
+
+	it('creates a new destination with 201', async () => {
+		await put_object('a.txt', 'a');
+		expect((await transfer('/b.txt')).status).toBe(201);
+		expect(await (await env.bucket.get('b.txt'))?.text()).toBe('a');
+		expect(await env.bucket.head('a.txt')).toEqual(method === 'COPY' ? expect.anything() : null);
+	});
+
+	it('replaces an existing destination with 204 and Overwrite: T', async () => {
+		await put_object('a.txt', 'a');
+		await put_object('b.txt', 'b');
+		expect((await transfer('/b.txt', 'T')).status).toBe(204);
+		expect(await (await env.bucket.get('b.txt'))?.text()).toBe('a');
+	});
+
+	it('refuses an existing destination with 412 and Overwrite: F', async () => {
+		await put_object('a.txt', 'a');
+		await put_object('b.txt', 'b');
+		expect((await transfer('/b.txt', 'F')).status).toBe(412);
+		expect(await (await env.bucket.get('b.txt'))?.text()).toBe('b');
+		expect(await list_keys()).toEqual(['a.txt', 'b.txt']);
+	});
+
+	it("refuses a destination whose parent doesn't exist with 409", async () => {
+		await put_object('a.txt', 'a');
+		expect((await transfer('/missing/b.txt')).status).toBe(409);
+		expect(await list_keys()).toEqual(['a.txt']);
+	});
+});
+
+describe('MOVE of a collection', () => {
+	async function nested_collection() {
+		await put_collection('docs');
+		await put_collection('docs/sub');
+		await put_object('docs/a.txt', 'a', { httpMetadata: { contentType: 'text/plain' }, customMetadata: { tag: 'x' } });
+		await put_object('docs/sub/b.txt', 'b');
+	}
+
+	it('moves the whole tree under the new prefix', async () => {
+		await nested_collection();
+		expect((await dav('MOVE', '/docs/', { headers: { Destination: '/archive/' } })).status).toBe(201);
+		expect(await list_keys()).toEqual(['archive', 'archive/a.txt', 'archive/sub', 'archive/sub/b.txt']);
+		let moved = await env.bucket.get('archive/a.txt');
+		expect(await moved?.text()).toBe('a');
+		expect(moved?.httpMetadata?.contentType).toBe('text/plain');
+		expect(moved?.customMetadata?.tag).toBe('x');
+		expect((await env.bucket.head('archive/sub'))?.customMetadata?.resourcetype).toBe('<collection />');
+	});
+
+	it("lists the keys it couldn't move in a 207 and leaves them with the collection", async () => {
+		await nested_collection();
+		let bucket = stub_bucket({
+			put: (key: string, value: ReadableStream | null, options?: R2PutOptions) => {
+				if (key === 'archive/sub/b.txt') {
+					return Promise.reject(new Error('We encountered an internal error. Please try again.'));
+				}
+				return env.bucket.put(key, value, options);
+			},
+		});
+		let response = await dav('MOVE', '/docs/', { headers: { Destination: '/archive/' }, vars: { bucket } });
+		expect(response.status).toBe(207);
+		expect(await response.text()).toContain('<href>/docs/sub/b.txt</href>');
+		expect(await list_keys('docs')).toEqual(['docs', 'docs/sub/b.txt']);
+		expect(await list_keys('archive')).toEqual(['archive', 'archive/a.txt', 'archive/sub']);
+	});
+
+	it('refuses a Destination within the collection with 403, moving nothing', async () => {
+		await nested_collection();
+		for (let destination of ['/docs/', '/docs/sub/inner/']) {
+			expect((await dav('MOVE', '/docs/', { headers: { Destination: destination } })).status).toBe(403);
+		}
+		expect(await list_keys('docs')).toEqual(['docs', 'docs/a.txt', 'docs/sub', 'docs/sub/b.txt']);
+	});
+});
+
+describe('COPY with Overwrite: T', () => {
+	const failing_put = (failing: string) =>
+		stub_bucket({
+			put: (key: string, value: ReadableStream | null, options?: R2PutOptions) => {
+				if (key === failing) {
+					return Promise.reject(new Error('We encountered an internal error. Please try again.'));
+				}
+				return env.bucket.put(key, value, options);
+			},
+		});
+
+	async function replaced_collection() {
+		await put_collection('docs');
+		await put_object('docs/a.txt', 'a');
+		await put_collection('archive');
+		await put_object('archive/a.txt', 'old');
+		await put_object('archive/stale.txt', 'stale');
+	}
+
+	it('keeps the file it would replace when the copy fails', async () => {
+		await put_object('a.txt', 'a');
+		await put_object('b.txt', 'b');
+		let bucket = failing_put('b.txt');
+		let response = await dav('COPY', '/a.txt', { headers: { Destination: '/b.txt' }, vars: { bucket } });
+		expect(response.status).toBe(500);
+		expect(await (await env.bucket.get('b.txt'))?.text()).toBe('b');
+	});
+
+	it('deletes the members of the replaced collection the copy has not', async () => {
+		await replaced_collection();
+		expect((await dav('COPY', '/docs/', { headers: { Destination: '/archive/' } })).status).toBe(204);
+		expect(await list_keys('archive')).toEqual(['archive', 'archive/a.txt']);
+		expect(await (await env.bucket.get('archive/a.txt'))?.text()).toBe('a');
+	});
+
+	it('keeps the members of the replaced collection when the copy fails', async () => {
+		await replaced_collection();
+		let bucket = failing_put('archive/a.txt');
+		let response = await dav('COPY', '/docs/', { headers: { Destination: '/archive/' }, vars: { bucket } });
+		expect(response.status).toBe(207);
+		expect(await list_keys('archive')).toEqual(['archive', 'archive/a.txt', 'archive/stale.txt']);
+	});
+
+	it('deletes every member of a collection replaced by a file', async () => {
+		await put_object('a.txt', 'a');
+		await put_collection('docs');
+		await put_object('docs/b.txt', 'b');
+		expect((await dav('COPY', '/a.txt', { headers: { Destination: '/docs' } })).status).toBe(204);
+		expect(await list_keys('docs')).toEqual(['docs']);
+		expect(await (await env.bucket.get('docs'))?.text()).toBe('a');
+	});
+});
+
+describe('SECONDARY_PREFIX', () => {
+	const vars = { SECONDARY_PREFIX: 'production' };
+
+	it('writes a Destination under the prefix to the secondary bucket', async () => {
+		await put_object('a.txt', 'a', { httpMetadata: { contentType: 'text/plain' } });
+		let response = await dav('COPY', '/a.txt', { headers: { Destination: '/production/b.txt' }, vars });
+		expect(response.status).toBe(201);
+		let copied = await env.secondary_bucket!.get('b.txt');
+		expect(await copied?.text()).toBe('a');
+		expect(copied?.httpMetadata?.contentType).toBe('text/plain');
+		expect(await list_keys()).toEqual(['a.txt']);
+	});
+
+	it('removes the source of a MOVE from the bucket', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('MOVE', '/a.txt', { headers: { Destination: '/production/a.txt' }, vars });
+		expect(response.status).toBe(201);
+		expect(await (await env.secondary_bucket!.get('a.txt'))?.text()).toBe('a');
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('forbids the root of the secondary bucket', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('COPY', '/a.txt', { headers: { Destination: '/production' }, vars });
+		expect(response.status).toBe(403);
+	});
+
+	it('keeps the other Destinations in the bucket', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('COPY', '/a.txt', { headers: { Destination: '/b.txt' }, vars })).status).toBe(201);
+		expect(await list_keys()).toEqual(['a.txt', 'b.txt']);
+		expect((await env.secondary_bucket!.list()).objects).toEqual([]);
+	});
+});
@@ -0,0 +1,72 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav } from './helpers';
+
+// What a browser sends before a cross-origin PROPFIND, without credentials
+function preflight(origin: string, vars = {}): Promise<Response> {
+	let headers = {
+		Origin: origin,
+		'Access-Control-Request-Method': 'PROPFIND',
+		'Access-Control-Request-Headers': 'authorization, depth',
+	};
+	return dav('OPTIONS', '/docs/', { headers, vars, anonymous: true });
+}
+
+describe('CORS preflight', () => {
+	it('allows the WebDAV methods and headers to a listed origin', async () => {
+		let response = await preflight('https://app.example.com', { CORS_ORIGINS: 'https://app.example.com' });
+		expect(response.ok).toBe(true);
+		expect(response.headers.get('Access-Control-Allow-Origin')).toBe('https://app.example.com');
+		expect(response.headers.get('Vary')).toContain('Origin');
+		let methods = response.headers.get('Access-Control-Allow-Methods')!.split(', ');
+		expect(methods).toEqual(expect.arrayContaining(['PROPFIND', 'PUT', 'MKCOL', 'COPY', 'MOVE', 'DELETE']));
+		let allowed = response.headers.get('Access-Control-Allow-Headers')!.split(', ');
+		expect(allowed).toEqual(expect.arrayContaining(['depth', 'destination', 'if', 'authorization', 'content-type']));
+		let exposed = response.headers.get('Access-Control-Expose-Headers')!.split(', ');
+		expect(exposed).toEqual(expect.arrayContaining(['etag', 'content-range']));
+	});
+
+	it('allows no other origin', async () => {
+		let response = await preflight('https://evil.example.com', { CORS_ORIGINS: 'https://app.example.com' });
+		expect(response.headers.get('Access-Control-Allow-Origin')).toBeNull();
+		expect(response.headers.get('Access-Control-Allow-Methods')).toBeNull();
+	});
+
+	it('allows every origin without CORS_ORIGINS', async () => {
+		let response = await preflight('https://app.example.com');
+		expect(response.headers.get('Access-Control-Allow-Origin')).toBe('https://app.example.com');
+	});
+
+	it('leaves the methods of a read-only bucket out', async () => {
+		let vars = { CORS_ORIGINS: '*', READ_ONLY: 'true' };
+		let methods = (await preflight('https://app.example.com', vars)).headers.get('Access-Control-Allow-Methods');
+		expect(methods!.split(', ')).not.toContain('PUT');
+	});
+});
+
+describe('CORS headers of refusals', () => {
+	const origin = 'https://app.example.com';
+	const allowed_origin = (response: Response) => response.headers.get('Access-Control-Allow-Origin');
+
+	it('are sent with the 401 of a request without credentials', async () => {
+		let response = await dav('GET', '/a.txt', { headers: { Origin: origin }, anonymous: true });
+		expect(response.status).toBe(401);
+		expect(allowed_origin(response)).toBe(origin);
+	});
+
+	it('are sent with the 403 of a bad signature', async () => {
+		let path = '/a.txt?signature=bad&expires=9999999999';
+		let response = await dav('GET', path, { headers: { Origin: origin }, vars: { SIGNING_SECRET: 'secret' } });
+		expect(response.status).toBe(403);
+		expect(allowed_origin(response)).toBe(origin);
+	});
+
+	it('are sent with the 429 of a client past its failed logins', async () => {
+		let headers = { Origin: origin, 'CF-Connecting-IP': '192.0.2.1' };
+		let vars = { kv: env.kv, RATE_LIMIT_WRITES: '1', RATE_LIMIT_WINDOW: '3600' };
+		expect((await dav('PUT', '/a.txt', { body: 'a', headers, vars, anonymous: true })).status).toBe(401);
+		let response = await dav('PUT', '/a.txt', { body: 'a', headers, vars, anonymous: true });
+		expect(response.status).toBe(429);
+		expect(allowed_origin(response)).toBe(origin);
+	});
+});
@@ -0,0 +1,116 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { BudgetExhausted } from '../src/error';
+import { dav, list_keys, put_collection, put_object, stub_bucket } from './helpers';
+
+describe('DELETE', () => {
+	it('deletes a collection with everything below it', async () => {
+		await put_collection('docs');
+		await put_collection('docs/2024');
+		await put_object('docs/a.txt', 'a');
+		await put_object('docs/2024/b.txt', 'b');
+		await put_object('docs/2024/deep/c.txt', 'c');
+		await put_object('docs2.txt', 'kept');
+
+		expect((await dav('DELETE', '/docs/')).status).toBe(204);
+		expect(await list_keys()).toEqual(['docs2.txt']);
+	});
+
+	it('deletes a file', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('DELETE', '/a.txt')).status).toBe(204);
+		expect(await list_keys()).toEqual([]);
+	});
+});
+
+describe('DELETE of a big collection', () => {
+	const big_collection = async () => {
+		await put_collection('docs');
+		for (let i = 0; i < 150; i++) {
+			await put_object(`docs/${i.toString().padStart(3, '0')}.txt`, 'x');
+		}
+	};
+
+	it('deletes 150 keys in one call', async () => {
+		await big_collection();
+		let calls: (string | string[])[] = [];
+		let bucket = stub_bucket({
+			delete: (keys: string | string[]) => {
+				calls.push(keys);
+				return env.bucket.delete(keys);
+			},
+		});
+		expect((await dav('DELETE', '/docs/', { vars: { bucket } })).status).toBe(204);
+		expect(await list_keys()).toEqual([]);
+		// The members go in one batch, then the marker
+		expect(calls).toHaveLength(2);
+		expect(calls[0]).toHaveLength(150);
+	});
+
+	it('lists the keys of a failed batch in a 207 and keeps the collection', async () => {
+		await big_collection();
+		let bucket = stub_bucket({
+			delete: async (keys: string | string[]) => {
+				if (keys.length === 150) {
+					throw new Error('We encountered an internal error. Please try again.');
+				}
+				await env.bucket.delete(keys);
+			},
+		});
+		let response = await dav('DELETE', '/docs/', { vars: { bucket } });
+		expect(response.status).toBe(207);
+		let hrefs = [...(await response.text()).matchAll(/<href>([^<]*)<\/href>/g)].map((match) => match[1]);
+		expect(hrefs).toHaveLength(150);
+		expect(hrefs[0]).toBe('/docs/000.txt');
+		expect(await list_keys('docs')).toContain('docs');
+	});
+});
+
+describe('DELETE past the subrequest budget', () => {
+	// One more member than a delete call takes, so the members go in two batches
+	const bigger_collection = async () => {
+		await put_collection('docs');
+		let keys = Array.from({ length: 1001 }, (_, i) => `docs/${i.toString().padStart(4, '0')}.txt`);
+		await Promise.all(keys.map((key) => put_object(key, 'x')));
+	};
+
+	it('stops with a 507 once the budget runs out, keeping what it deleted', async () => {
+		await bigger_collection();
+		// The budget runs out right after the first batch is deleted
+		let deleted = false;
+		let bucket = stub_bucket({
+			list: (options?: R2ListOptions) => {
+				return deleted ? Promise.reject(new BudgetExhausted()) : env.bucket.list({ ...options, limit: 1000 });
+			},
+			delete: async (keys: string | string[]) => {
+				await env.bucket.delete(keys);
+				deleted = true;
+			},
+		});
+		let response = await dav('DELETE', '/docs/', { vars: { bucket } });
+		expect(response.status).toBe(507);
+		expect(await response.text()).toContain('repeat the request to continue');
+		expect(await list_keys('docs')).toEqual(['docs', 'docs/1000.txt']);
+
+		expect((await dav('DELETE', '/docs/')).status).toBe(204);
+		expect(await list_keys()).toEqual([]);
+	});
+});
+
+describe('DELETE of a missing file', () => {
+	it('is a 404 by default', async () => {
+		expect((await dav('DELETE', '/missing.txt')).status).toBe(404);
+	});
+
+	it('is a 204 with DELETE_MISSING_IS_404 off', async () => {
+		let response = await dav('DELETE', '/missing.txt', { vars: { DELETE_MISSING_IS_404: 'false' } });
+		expect(response.status).toBe(204);
+	});
+
+	it('still deletes existing files either way', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('DELETE', '/a.txt', { vars: { DELETE_MISSING_IS_404: 'false' } });
+		expect(response.status).toBe(204);
+		expect(await list_keys()).toEqual([]);
+	});
+});
@@ -0,0 +1,5 @@
+import { Env } from '../src/index';
+
+declare module 'cloudflare:test' {
+	interface ProvidedEnv extends Env {}
+}
@@ -0,0 +1,71 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, lock, put_object } from './helpers';
+
+const XML_TYPE = 'application/xml; charset="utf-8"';
+
+describe('DAV:error bodies', () => {
+	it('describe a 404 of a PROPFIND', async () => {
+		let response = await dav('PROPFIND', '/missing.txt', { headers: { Depth: '0' } });
+		expect(response.status).toBe(404);
+		expect(response.headers.get('Content-Type')).toBe(XML_TYPE);
+		let body = await response.text();
+		expect(body).toContain('<error xmlns="DAV:">');
+		expect(body).toContain('<responsedescription>Not Found</responsedescription>');
+	});
+
+	it('name the condition of a 423', async () => {
+		await put_object('a.txt', 'a');
+		await lock('/a.txt');
+		for (let method of ['PROPPATCH', 'DELETE', 'PUT']) {
+			let body = method === 'PUT' ? 'b' : undefined;
+			let response = await dav(method, '/a.txt', { body, vars: { kv: env.kv } });
+			expect(response.status).toBe(423);
+			expect(response.headers.get('Content-Type')).toBe(XML_TYPE);
+			expect(await response.text()).toContain('<lock-token-submitted/>');
+		}
+	});
+
+	it('name the condition of a 403 for an infinite PROPFIND past the limit', async () => {
+		await put_object('a.txt', 'a');
+		await put_object('b.txt', 'b');
+		let vars = { PROPFIND_INFINITY_LIMIT: '1' };
+		let response = await dav('PROPFIND', '/', { headers: { Depth: 'infinity' }, vars });
+		expect(response.status).toBe(403);
+		expect(await response.text()).toContain('<propfind-finite-depth/>');
+	});
+
+	it('stay plain text for a GET', async () => {
+		let response = await dav('GET', '/missing.txt');
+		expect(response.status).toBe(404);
+		expect(response.headers.get('Content-Type')).not.toContain('xml');
+	});
+});
+
+describe('malformed request bodies', () => {
+	const truncated = '<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"><D:prop><D:getetag/>';
+
+	it.each(['PROPFIND', 'PROPPATCH', 'LOCK'])('refuses truncated XML in a %s with 400', async (method) => {
+		await put_object('a.txt', 'a');
+		let response = await dav(method, '/a.txt', { body: truncated, headers: { Depth: '0' }, vars: { kv: env.kv } });
+		expect(response.status).toBe(400);
+		expect(response.headers.get('Content-Type')).toBe(XML_TYPE);
+		expect(await response.text()).toContain('<responsedescription>Malformed XML');
+	});
+
+	it('refuses a body of another element with 422, naming the one expected', async () => {
+		await put_object('a.txt', 'a');
+		let body = '<?xml version="1.0" encoding="utf-8"?><D:propertyupdate xmlns:D="DAV:"/>';
+		let response = await dav('PROPFIND', '/a.txt', { body, headers: { Depth: '0' } });
+		expect(response.status).toBe(422);
+		expect(await response.text()).toContain('Expected a DAV:propfind element');
+		let proppatch = await dav('PROPPATCH', '/a.txt', { body: '<propertyupdate xmlns="urn:example"/>' });
+		expect(proppatch.status).toBe(422);
+	});
+
+	it('refuses a PROPFIND asking for nothing with 422', async () => {
+		await put_object('a.txt', 'a');
+		let body = '<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"/>';
+		expect((await dav('PROPFIND', '/a.txt', { body, headers: { Depth: '0' } })).status).toBe(422);
+	});
+});
@@ -0,0 +1,83 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, dav_property, put_object } from './helpers';
+
+// The ETag header of a GET and the getetag property of a PROPFIND of path
+async function etags(path: string, vars = {}): Promise<[string | null, string | undefined]> {
+	let header = (await dav('GET', path, { vars })).headers.get('ETag');
+	let xml = await (await dav('PROPFIND', path, { headers: { Depth: '0' }, vars })).text();
+	return [header, dav_property(xml, 'getetag')];
+}
+
+describe('ETags', () => {
+	it('are the same quoted value in headers and properties', async () => {
+		let { httpEtag } = await put_object('a.txt', 'a');
+		let [header, property] = await etags('/a.txt');
+		expect(header).toBe(httpEtag);
+		expect(header).toMatch(/^"[^"]+"$/);
+		expect(property).toBe(header);
+	});
+
+	it('are weak everywhere with WEAK_ETAGS', async () => {
+		let { httpEtag } = await put_object('a.txt', 'a');
+		let vars = { WEAK_ETAGS: 'true' };
+		let [header, property] = await etags('/a.txt', vars);
+		expect(header).toBe(`W/${httpEtag}`);
+		expect(property).toBe(header);
+		let response = await dav('GET', '/a.txt', { headers: { 'If-None-Match': header! }, vars });
+		expect(response.status).toBe(304);
+		expect(response.headers.get('ETag')).toBe(header);
+	});
+
+	it('are matched by conditions in the form they were sent in', async () => {
+		await put_object('a.txt', 'a');
+		let [header] = await etags('/a.txt');
+		expect((await dav('GET', '/a.txt', { headers: { 'If-None-Match': header! } })).status).toBe(304);
+		expect((await dav('GET', '/a.txt', { headers: { 'If-Match': header! } })).status).toBe(200);
+		let response = await dav('PUT', '/a.txt', { body: 'b', headers: { 'If-Match': header! } });
+		expect(response.status).toBe(204);
+		expect((await dav('PUT', '/a.txt', { body: 'c', headers: { 'If-Match': header! } })).status).toBe(412);
+	});
+
+	it('of multipart uploads compare like any other', async () => {
+		let upload = await env.bucket.createMultipartUpload('big.bin');
+		let part = await upload.uploadPart(1, 'a'.repeat(5 * 1024 * 1024));
+		let object = await upload.complete([part]);
+		let [header, property] = await etags('/big.bin');
+		expect(header).toBe(object.httpEtag);
+		expect(property).toBe(header);
+		let response = await dav('HEAD', '/big.bin', { headers: { 'If-None-Match': header! } });
+		expect(response.status).toBe(304);
+	});
+});
+
+describe('conditional HEAD', () => {
+	it('answers a matching If-None-Match with a bare 304', async () => {
+		let { httpEtag } = await put_object('a.txt', 'abc');
+		let response = await dav('HEAD', '/a.txt', { headers: { 'If-None-Match': httpEtag } });
+		expect(response.status).toBe(304);
+		expect(response.headers.get('ETag')).toBe(httpEtag);
+		expect(response.headers.has('Content-Length')).toBe(false);
+		expect(await response.text()).toBe('');
+	});
+
+	it('answers If-Modified-Since like GET', async () => {
+		let { uploaded } = await put_object('a.txt', 'abc');
+		let headers = { 'If-Modified-Since': uploaded.toUTCString() };
+		expect((await dav('HEAD', '/a.txt', { headers })).status).toBe(304);
+		expect((await dav('GET', '/a.txt', { headers })).status).toBe(304);
+	});
+
+	it('is a 200 once the file changed', async () => {
+		let { httpEtag } = await put_object('a.txt', 'abc');
+		await put_object('a.txt', 'changed');
+		let response = await dav('HEAD', '/a.txt', { headers: { 'If-None-Match': httpEtag } });
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Length')).toBe('7');
+	});
+
+	it('refuses an If-Match that no longer matches with 412', async () => {
+		await put_object('a.txt', 'abc');
+		expect((await dav('HEAD', '/a.txt', { headers: { 'If-Match': '"other"' } })).status).toBe(412);
+	});
+});
@@ -0,0 +1,104 @@
+// Requests to the worker the way WebDAV clients make them, with the bindings of vitest.config.ts
+
+import { createExecutionContext, env, waitOnExecutionContext } from 'cloudflare:test';
+import worker, { Env } from '../src/index';
+
+export const ORIGIN = 'https://dav.example.com';
+export const AUTHORIZATION = `Basic ${btoa('user:password')}`;
+
+// The environment the worker gets, vars override the defaults. The KV namespace is only there when
+// a test asks for it, since it turns on locking.
+export function test_env(vars: Partial<Env> = {}): Env {
+	return { ...env, kv: undefined, USERNAME: 'user', PASSWORD: 'password', ...vars };
+}
+
+export type DavInit = RequestInit & {
+	vars?: Partial<Env>;
+	// Sends no Authorization header unless the test sets one
+	anonymous?: boolean;
+};
+
+// Sends a request to the worker, and waits for what it left running past the response like webhooks
+export async function dav(method: string, path: string, init: DavInit = {}): Promise<Response> {
+	let { vars, anonymous, ...request_init } = init;
+	let headers = new Headers(request_init.headers);
+	if (!anonymous && !headers.has('Authorization')) {
+		headers.set('Authorization', AUTHORIZATION);
+	}
+	let request = new Request(ORIGIN + path, { ...request_init, method, headers });
+	let ctx = createExecutionContext();
+	let response = await worker.fetch(request, test_env(vars), ctx);
+	await waitOnExecutionContext(ctx);
+	return response;
+}
+
+// The test bucket with some of its methods replaced, like a list returning short pages
+export function stub_bucket(overrides: Partial<R2Bucket>): R2Bucket {
+	let bucket = env.bucket;
+	let stub = {
+		head: bucket.head.bind(bucket),
+		get: bucket.get.bind(bucket),
+		put: bucket.put.bind(bucket),
+		delete: bucket.delete.bind(bucket),
+		list: bucket.list.bind(bucket),
+		createMultipartUpload: bucket.createMultipartUpload.bind(bucket),
+		resumeMultipartUpload: bucket.resumeMultipartUpload.bind(bucket),
+		...overrides,
+	};
+	return stub as unknown as R2Bucket;
+}
+
+// Stores a file straight in the bucket, without going through the worker
+export async function put_object(key: string, body: string = '', options?: R2PutOptions): Promise<R2Object> {
+	return await env.bucket.put(key, body, options);
+}
+
+// Stores a collection marker straight in the bucket, like MKCOL does
+export async function put_collection(key: string): Promise<R2Object> {
+	return await env.bucket.put(key, new Uint8Array(), { customMetadata: { resourcetype: '<collection />' } });
+}
+
+// The keys of the bucket under prefix
+export async function list_keys(prefix: string = ''): Promise<string[]> {
+	return (await env.bucket.list({ prefix })).objects.map((object) => object.key);
+}
+
+// The body of a PROPFIND, asking for the properties named or for allprop without any
+export function propfind_body(...names: string[]): string {
+	if (names.length === 0) {
+		return '<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><allprop/></propfind>';
+	}
+	let props = names.map((name) => `<${name}/>`).join('');
+	return `<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop>${props}</prop></propfind>`;
+}
+
+// The hrefs a Multi-Status names, in the order of its responses
+export function multistatus_hrefs(xml: string): string[] {
+	return [...xml.matchAll(/<D:href>([^<]*)<\/D:href>/g)].map((match) => match[1]);
+}
+
+// The response element of a Multi-Status for href, or undefined
+export function multistatus_response(xml: string, href: string): string | undefined {
+	return xml.split('<D:response>').find((response) => response.includes(`<D:href>${href}</D:href>`));
+}
+
+// The value of a DAV: property in a Multi-Status or one of its response elements, undefined when it isn't there
+export function dav_property(xml: string, name: string): string | undefined {
+	let match = xml.match(new RegExp(`<D:${name}>(.*?)</D:${name}>|<D:${name}/>`, 's'));
+	return match === null ? undefined : match[1] ?? '';
+}
+
+// Takes an exclusive write lock on path and returns its token, the locks are kept in the test KV namespace
+export async function lock(path: string, init: DavInit = {}): Promise<string> {
+	let body = `<?xml version="1.0" encoding="utf-8"?>
+<D:lockinfo xmlns:D="DAV:">
+	<D:lockscope><D:exclusive/></D:lockscope>
+	<D:locktype><D:write/></D:locktype>
+	<D:owner>test</D:owner>
+</D:lockinfo>`;
+	let response = await dav('LOCK', path, { body, ...init, vars: { kv: env.kv, ...init.vars } });
+	if (!response.ok) {
+		throw new Error(`LOCK ${path} failed with ${response.status}`);
+	}
+	return response.headers.get('Lock-Token')!.slice(1, -1);
+}
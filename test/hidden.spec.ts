@@ -0,0 +1,59 @@
+import { describe, expect, it } from 'vitest';
+import { is_hidden_file } from '../src/hidden';
+import { dav, list_keys, multistatus_hrefs, put_object } from './helpers';
+
+describe('is_hidden_file', () => {
+	it('hides AppleDouble files and .DS_Store by default', () => {
+		expect(is_hidden_file('docs/._report.pdf')).toBe(true);
+		expect(is_hidden_file('.DS_Store')).toBe(true);
+		expect(is_hidden_file('docs/.DS_Store')).toBe(true);
+	});
+
+	it('leaves other files alone', () => {
+		expect(is_hidden_file('docs/report.pdf')).toBe(false);
+		expect(is_hidden_file('docs/.hidden')).toBe(false);
+		expect(is_hidden_file('._docs/report.pdf')).toBe(false);
+	});
+
+	it('matches the name of folders as well', () => {
+		expect(is_hidden_file('docs/._folder/')).toBe(true);
+	});
+
+	it('takes comma separated patterns', () => {
+		expect(is_hidden_file('docs/Thumbs.db', 'Thumbs.db, *.tmp')).toBe(true);
+		expect(is_hidden_file('docs/draft.tmp', 'Thumbs.db, *.tmp')).toBe(true);
+		expect(is_hidden_file('docs/.DS_Store', 'Thumbs.db, *.tmp')).toBe(false);
+		expect(is_hidden_file('docs/a.txt', ',')).toBe(false);
+	});
+});
+
+describe('HIDE_APPLE_FILES', () => {
+	const vars = { HIDE_APPLE_FILES: 'true' };
+
+	it("pretends to store an AppleDouble upload, but doesn't", async () => {
+		expect((await dav('PUT', '/._foo', { body: 'resource fork', vars })).status).toBe(201);
+		expect((await dav('PUT', '/.DS_Store', { body: 'finder', vars })).status).toBe(201);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('leaves the files already in the bucket out of listings', async () => {
+		await put_object('foo', 'data');
+		await put_object('._foo', 'resource fork');
+		let response = await dav('PROPFIND', '/', { headers: { Depth: '1' }, vars });
+		expect(multistatus_hrefs(await response.text())).toEqual(['/', '/foo']);
+		let json = await (await dav('GET', '/?format=json', { vars })).json<{ name: string }[]>();
+		expect(json.map(({ name }) => name)).toEqual(['foo']);
+	});
+
+	it('hides the patterns of HIDDEN_FILE_PATTERNS instead', async () => {
+		let patterns = { ...vars, HIDDEN_FILE_PATTERNS: '*.tmp' };
+		await dav('PUT', '/draft.tmp', { body: 'draft', vars: patterns });
+		await dav('PUT', '/._foo', { body: 'resource fork', vars: patterns });
+		expect(await list_keys()).toEqual(['._foo']);
+	});
+
+	it('stores everything when off', async () => {
+		expect((await dav('PUT', '/._foo', { body: 'resource fork' })).status).toBe(201);
+		expect(await list_keys()).toEqual(['._foo']);
+	});
+});
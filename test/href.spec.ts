@@ -0,0 +1,32 @@
+import { describe, expect, it } from 'vitest';
+import { dav, list_keys, multistatus_hrefs } from './helpers';
+
+const FOLDER = 'café déjà';
+const FILE = 'ünïcode .txt';
+const FOLDER_HREF = '/' + encodeURIComponent(FOLDER) + '/';
+const FILE_HREF = FOLDER_HREF + encodeURIComponent(FILE);
+
+describe('hrefs', () => {
+	it('round-trips a Unicode name through PUT, PROPFIND and GET', async () => {
+		expect((await dav('MKCOL', FOLDER_HREF)).status).toBe(201);
+		expect((await dav('PUT', FILE_HREF, { body: 'hello' })).status).toBe(201);
+		expect(await list_keys()).toEqual([FOLDER, `${FOLDER}/${FILE}`]);
+
+		let propfind = await dav('PROPFIND', FOLDER_HREF, { headers: { Depth: '1' } });
+		expect(propfind.status).toBe(207);
+		let hrefs = multistatus_hrefs(await propfind.text());
+		expect(hrefs).toEqual([FOLDER_HREF, FILE_HREF]);
+
+		let get = await dav('GET', hrefs[1]);
+		expect(get.status).toBe(200);
+		expect(await get.text()).toBe('hello');
+	});
+
+	it('encodes the characters URLs give a meaning to', async () => {
+		let href = '/' + encodeURIComponent('100% #1?.txt');
+		expect((await dav('PUT', href, { body: 'a' })).status).toBe(201);
+		let propfind = await dav('PROPFIND', '/', { headers: { Depth: '1' } });
+		expect(multistatus_hrefs(await propfind.text())).toEqual(['/', href]);
+		expect(await (await dav('GET', href)).text()).toBe('a');
+	});
+});
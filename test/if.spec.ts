@@ -0,0 +1,115 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { evaluate_if, parse_if_header, submitted_tokens } from '../src/if';
+import { ORIGIN, dav, lock, put_object } from './helpers';
+
+describe('parse_if_header', () => {
+	it('parses untagged lists', () => {
+		expect(parse_if_header('(<urn:uuid:a> ["etag"]) (Not <DAV:no-lock>)')).toEqual([
+			{
+				resource: null,
+				conditions: [
+					{ not: false, type: 'token', value: 'urn:uuid:a' },
+					{ not: false, type: 'etag', value: '"etag"' },
+				],
+			},
+			{ resource: null, conditions: [{ not: true, type: 'token', value: 'DAV:no-lock' }] },
+		]);
+	});
+
+	it('parses tagged lists, a tag applying to the lists after it', () => {
+		expect(parse_if_header('<http://host/a> (<urn:uuid:a>) (["x"]) <http://host/b> (Not ["y"])')).toEqual([
+			{ resource: 'http://host/a', conditions: [{ not: false, type: 'token', value: 'urn:uuid:a' }] },
+			{ resource: 'http://host/a', conditions: [{ not: false, type: 'etag', value: '"x"' }] },
+			{ resource: 'http://host/b', conditions: [{ not: true, type: 'etag', value: '"y"' }] },
+		]);
+	});
+
+	it('refuses malformed headers', () => {
+		expect(parse_if_header('')).toBeNull();
+		expect(parse_if_header('()')).toBeNull();
+		expect(parse_if_header('(<urn:uuid:a>')).toBeNull();
+		expect(parse_if_header('<http://host/a>')).toBeNull();
+		expect(parse_if_header('urn:uuid:a')).toBeNull();
+		expect(parse_if_header('(<urn:uuid:a>) <http://host/a> (<urn:uuid:b>)')).toBeNull();
+	});
+});
+
+describe('submitted_tokens', () => {
+	it("lists the tokens that aren't negated", () => {
+		let lists = parse_if_header('(<urn:uuid:a> Not <urn:uuid:b>) (<urn:uuid:c>)')!;
+		expect(submitted_tokens(lists)).toEqual(['urn:uuid:a', 'urn:uuid:c']);
+	});
+});
+
+describe('evaluate_if', () => {
+	const state = async () => ({ etag: 'abc', tokens: ['urn:uuid:a'] });
+
+	it('holds when any list holds', async () => {
+		expect(await evaluate_if(parse_if_header('(<urn:uuid:b>) (<urn:uuid:a>)')!, state)).toBe(true);
+		expect(await evaluate_if(parse_if_header('(<urn:uuid:b>) (["other"])')!, state)).toBe(false);
+	});
+
+	it('needs every condition of a list', async () => {
+		expect(await evaluate_if(parse_if_header('(<urn:uuid:a> ["abc"])')!, state)).toBe(true);
+		expect(await evaluate_if(parse_if_header('(<urn:uuid:a> ["other"])')!, state)).toBe(false);
+	});
+
+	it('compares entity tags without their quotes or weakness', async () => {
+		expect(await evaluate_if(parse_if_header('(["abc"])')!, state)).toBe(true);
+		expect(await evaluate_if(parse_if_header('([W/"abc"])')!, state)).toBe(true);
+	});
+
+	it('negates conditions with Not', async () => {
+		expect(await evaluate_if(parse_if_header('(Not <DAV:no-lock>)')!, state)).toBe(true);
+		expect(await evaluate_if(parse_if_header('(Not <urn:uuid:a>)')!, state)).toBe(false);
+	});
+
+	it('looks up the state of tagged resources', async () => {
+		let resources: (string | null)[] = [];
+		let tagged = async (resource: string | null) => {
+			resources.push(resource);
+			return { etag: undefined, tokens: resource === 'http://host/b' ? ['urn:uuid:b'] : [] };
+		};
+		let lists = parse_if_header('<http://host/a> (<urn:uuid:b>) <http://host/b> (<urn:uuid:b>)')!;
+		expect(await evaluate_if(lists, tagged)).toBe(true);
+		expect(resources).toEqual(['http://host/a', 'http://host/b']);
+	});
+});
+
+describe('If header on writes', () => {
+	const put = (headers: Record<string, string>, vars = {}) => dav('PUT', '/a.txt', { body: 'b', headers, vars });
+
+	it('lets a write through when an untagged list holds', async () => {
+		let { httpEtag } = await put_object('a.txt', 'a');
+		expect((await put({ If: `([${httpEtag}])` })).status).toBe(204);
+	});
+
+	it('refuses a write with 412 when no list holds', async () => {
+		let { httpEtag } = await put_object('a.txt', 'a');
+		expect((await put({ If: '(["other"])' })).status).toBe(412);
+		expect((await put({ If: `(Not [${httpEtag}])` })).status).toBe(412);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('a');
+	});
+
+	it('evaluates tagged lists against the resource they name', async () => {
+		let { httpEtag } = await put_object('b.txt', 'b');
+		await put_object('a.txt', 'a');
+		expect((await put({ If: `<${ORIGIN}/b.txt> ([${httpEtag}])` })).status).toBe(204);
+		expect((await put({ If: `<${ORIGIN}/a.txt> ([${httpEtag}])` })).status).toBe(412);
+	});
+
+	it('refuses a write to a locked file with 423 without its token', async () => {
+		await put_object('a.txt', 'a');
+		let token = await lock('/a.txt');
+		let vars = { kv: env.kv };
+		expect((await put({}, vars)).status).toBe(423);
+		expect((await put({ If: '(<urn:uuid:00000000-0000-0000-0000-000000000000>)' }, vars)).status).toBe(423);
+		expect((await put({ If: `(<${token}>)` }, vars)).status).toBe(204);
+	});
+
+	it('lets Not <DAV:no-lock> through an unlocked file', async () => {
+		await put_object('a.txt', 'a');
+		expect((await put({ If: '(Not <DAV:no-lock>)' })).status).toBe(204);
+	});
+});
@@ -0,0 +1,40 @@
+import { describe, expect, it } from 'vitest';
+import { DavInit, dav, list_keys, multistatus_hrefs, put_object } from './helpers';
+
+const vars = { USER_ISOLATION: 'true', USERS: 'alice:a-secret,bob:b-secret', ADMIN_USERS: 'user' };
+
+// A request of one of the users of vars
+function as_user(name: string, password: string, init: DavInit = {}): DavInit {
+	let headers = new Headers(init.headers);
+	headers.set('Authorization', `Basic ${btoa(`${name}:${password}`)}`);
+	return { ...init, headers, vars: { ...vars, ...init.vars } };
+}
+const alice = (init?: DavInit) => as_user('alice', 'a-secret', init);
+const bob = (init?: DavInit) => as_user('bob', 'b-secret', init);
+
+describe('USER_ISOLATION', () => {
+	it('stores the files of a user in their own folder', async () => {
+		expect((await dav('PUT', '/a.txt', alice({ body: 'alice' }))).status).toBe(201);
+		expect(await list_keys()).toEqual(['users/alice/a.txt']);
+	});
+
+	it("doesn't let one user get the files of another", async () => {
+		await put_object('users/alice/a.txt', 'alice');
+		expect((await dav('GET', '/a.txt', bob())).status).toBe(404);
+		expect((await dav('GET', '/users/alice/a.txt', bob())).status).toBe(404);
+		expect(await (await dav('GET', '/a.txt', alice())).text()).toBe('alice');
+	});
+
+	it('lists only their own files, relative to their folder', async () => {
+		await put_object('users/alice/a.txt', 'alice');
+		await put_object('users/bob/b.txt', 'bob');
+		let response = await dav('PROPFIND', '/', bob({ headers: { Depth: 'infinity' } }));
+		expect(multistatus_hrefs(await response.text())).toEqual(['/', '/b.txt']);
+	});
+
+	it('gives admins the whole bucket', async () => {
+		await put_object('users/alice/a.txt', 'alice');
+		let response = await dav('GET', '/users/alice/a.txt', { vars });
+		expect(await response.text()).toBe('alice');
+	});
+});
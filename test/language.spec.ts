@@ -0,0 +1,70 @@
+import { describe, expect, it } from 'vitest';
+import { negotiate_language, variant_language } from '../src/language';
+import { dav, put_object } from './helpers';
+
+describe('variant_language', () => {
+	it('finds the language between the name and the extension', () => {
+		expect(variant_language('page.html', 'page.fr.html')).toBe('fr');
+		expect(variant_language('page.html', 'page.en-GB.html')).toBe('en-GB');
+		expect(variant_language('README', 'README.de')).toBe('de');
+	});
+
+	it('ignores other files', () => {
+		expect(variant_language('page.html', 'page.html')).toBeNull();
+		expect(variant_language('page.html', 'other.fr.html')).toBeNull();
+		expect(variant_language('page.html', 'page.fr.txt')).toBeNull();
+		expect(variant_language('page.html', 'page.backup.old.html')).toBeNull();
+	});
+});
+
+describe('negotiate_language', () => {
+	it('picks the preferred language by quality', () => {
+		expect(negotiate_language('fr;q=0.5, en;q=0.8', ['en', 'fr'])).toBe('en');
+		expect(negotiate_language('fr, en', ['en', 'fr'])).toBe('fr');
+	});
+
+	it('matches the subtags of a range', () => {
+		expect(negotiate_language('en', ['de', 'en-GB'])).toBe('en-GB');
+		expect(negotiate_language('EN-gb', ['en-GB'])).toBe('en-GB');
+	});
+
+	it('takes any language for *', () => {
+		expect(negotiate_language('de, *;q=0.1', ['fr'])).toBe('fr');
+	});
+
+	it('is null without an acceptable one', () => {
+		expect(negotiate_language('de', ['en', 'fr'])).toBeNull();
+		expect(negotiate_language('fr;q=0', ['fr'])).toBeNull();
+		expect(negotiate_language(null, ['fr'])).toBeNull();
+	});
+});
+
+describe('LANGUAGE_NEGOTIATION', () => {
+	const vars = { LANGUAGE_NEGOTIATION: 'true', DEFAULT_LANGUAGE: 'en' };
+
+	async function put_variants() {
+		await put_object('docs/page.en.html', '<p>Hello</p>');
+		await put_object('docs/page.fr.html', '<p>Bonjour</p>');
+	}
+
+	it('serves the variant of the language the client prefers', async () => {
+		await put_variants();
+		let response = await dav('GET', '/docs/page.html', { headers: { 'Accept-Language': 'fr, en;q=0.5' }, vars });
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Language')).toBe('fr');
+		expect(response.headers.get('Vary')).toContain('Accept-Language');
+		expect(await response.text()).toBe('<p>Bonjour</p>');
+	});
+
+	it('falls back to DEFAULT_LANGUAGE when it accepts none of them', async () => {
+		await put_variants();
+		let response = await dav('GET', '/docs/page.html', { headers: { 'Accept-Language': 'de' }, vars });
+		expect(response.headers.get('Content-Language')).toBe('en');
+		expect(await response.text()).toBe('<p>Hello</p>');
+	});
+
+	it('is off by default', async () => {
+		await put_variants();
+		expect((await dav('GET', '/docs/page.html', { headers: { 'Accept-Language': 'fr' } })).status).toBe(404);
+	});
+});
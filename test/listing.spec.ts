@@ -0,0 +1,161 @@
+import { describe, expect, it } from 'vitest';
+import { dav, put_collection, put_object } from './helpers';
+
+// The links of an HTML listing's rows, and the one to its next page or null
+function listing_links(html: string): { hrefs: string[]; next: string | null } {
+	let hrefs = [...html.matchAll(/<tr><td><a href="([^"]*)">/g)].map((match) => match[1]);
+	let next = html.match(/<p><a href="([^"]*)">Next page<\/a><\/p>/)?.[1].replaceAll('&amp;', '&') ?? null;
+	return { hrefs, next };
+}
+
+describe('HTML listing pages', () => {
+	it('lists a folder in pages of limit, continuing in order', async () => {
+		for (let name of ['a.txt', 'b.txt', 'c.txt', 'd.txt', 'e.txt']) {
+			await put_object(name, name);
+		}
+		let pages: string[][] = [];
+		let path: string | null = '/?limit=2';
+		while (path !== null) {
+			let response = await dav('GET', path);
+			expect(response.status).toBe(200);
+			let { hrefs, next } = listing_links(await response.text());
+			pages.push(hrefs);
+			path = next;
+		}
+		expect(pages).toEqual([['/a.txt', '/b.txt'], ['/c.txt', '/d.txt'], ['/e.txt']]);
+	});
+
+	it('counts folders against the limit like files', async () => {
+		await put_object('a.txt');
+		await put_collection('b');
+		await put_object('c/d.txt');
+		let response = await dav('GET', '/?limit=2');
+		let { hrefs, next } = listing_links(await response.text());
+		expect(hrefs).toEqual(['/a.txt', '/b/']);
+		expect(next).not.toBeNull();
+		let { hrefs: rest } = listing_links(await (await dav('GET', next!)).text());
+		expect(rest).toEqual(['/c/']);
+	});
+
+	it('has no next page when everything fits', async () => {
+		await put_object('a.txt');
+		let { hrefs, next } = listing_links(await (await dav('GET', '/')).text());
+		expect(hrefs).toEqual(['/a.txt']);
+		expect(next).toBeNull();
+	});
+});
+
+describe('JSON listing', () => {
+	it('describes each member of a mixed folder', async () => {
+		let file = await put_object('docs/a.txt', 'abc');
+		let marker = await put_collection('docs/marked');
+		await put_object('docs/unmarked/b.txt', 'b');
+		let response = await dav('GET', '/docs/?format=json');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Type')).toContain('application/json');
+		expect(await response.json()).toEqual([
+			{ name: 'a.txt', size: 3, modified: file.uploaded.toISOString(), etag: file.httpEtag, is_dir: false },
+			{ name: 'marked', size: null, modified: marker.uploaded.toISOString(), etag: marker.httpEtag, is_dir: true },
+			{ name: 'unmarked', size: null, modified: null, etag: null, is_dir: true },
+		]);
+	});
+
+	it('needs the same credentials as WebDAV', async () => {
+		let response = await dav('GET', '/?format=json', { anonymous: true });
+		expect(response.status).toBe(401);
+	});
+
+	it('only lists below ROOT_PREFIX', async () => {
+		await put_object('public/a.txt', 'a');
+		await put_object('private.txt', 'b');
+		let response = await dav('GET', '/?format=json', { vars: { ROOT_PREFIX: 'public' } });
+		let names = (await response.json<{ name: string }[]>()).map(({ name }) => name);
+		expect(names).toEqual(['a.txt']);
+	});
+});
+
+describe('Translate: f', () => {
+	const headers = { Translate: 'f', Accept: 'text/html, */*' };
+
+	it('gets the raw body of a file', async () => {
+		let body = '<html><body>Hello</body></html>';
+		await put_object('page.html', body, { httpMetadata: { contentType: 'text/html' } });
+		let response = await dav('GET', '/page.html', { headers });
+		expect(response.status).toBe(200);
+		expect(await response.text()).toBe(body);
+	});
+
+	it('never gets the HTML listing of a collection', async () => {
+		await put_collection('docs');
+		await put_object('docs/a.txt', 'a');
+		let response = await dav('GET', '/docs/', { headers });
+		expect(response.status).toBe(405);
+		expect(await response.text()).not.toContain('<table>');
+		let marker = await dav('GET', '/docs', { headers });
+		expect(await marker.text()).not.toContain('<table>');
+	});
+});
+
+describe('HEAD on a collection', () => {
+	it('is a 200 without a body for a folder of another tool', async () => {
+		await put_object('other/a.txt', 'a');
+		let response = await dav('HEAD', '/other/');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Type')).toContain('text/html');
+		expect(await response.text()).toBe('');
+	});
+
+	it('is a 200 without a body for a folder of another tool without the trailing slash', async () => {
+		await put_object('other/a.txt', 'a');
+		let response = await dav('HEAD', '/other');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Type')).toContain('text/html');
+		expect(await response.text()).toBe('');
+	});
+
+	it('is a 200 without a body for a collection with a marker', async () => {
+		await put_collection('docs');
+		for (let path of ['/docs/', '/docs']) {
+			let response = await dav('HEAD', path);
+			expect(response.status).toBe(200);
+			expect(await response.text()).toBe('');
+		}
+	});
+
+	it('agrees with the GET of the listing', async () => {
+		await put_object('other/a.txt', 'a');
+		let head = await dav('HEAD', '/other/');
+		let get = await dav('GET', '/other/');
+		expect(head.headers.get('Content-Type')).toBe(get.headers.get('Content-Type'));
+		expect(await get.text()).toContain('a.txt');
+	});
+});
+
+describe('INDEX_DOCUMENT', () => {
+	const vars = { INDEX_DOCUMENT: 'index.html' };
+
+	it('serves the index document of a folder that has one', async () => {
+		await put_object('site/index.html', '<h1>Home</h1>', { httpMetadata: { contentType: 'text/html' } });
+		let response = await dav('GET', '/site/', { vars });
+		expect(response.status).toBe(200);
+		expect(await response.text()).toBe('<h1>Home</h1>');
+	});
+
+	it('serves the one of the root', async () => {
+		await put_object('index.html', '<h1>Root</h1>', { httpMetadata: { contentType: 'text/html' } });
+		expect(await (await dav('GET', '/', { vars })).text()).toBe('<h1>Root</h1>');
+	});
+
+	it('lists a folder without one', async () => {
+		await put_object('docs/a.txt', 'a');
+		let { hrefs } = listing_links(await (await dav('GET', '/docs/', { vars })).text());
+		expect(hrefs).toEqual(['/docs/a.txt']);
+	});
+
+	it("doesn't change what PROPFIND reports", async () => {
+		await put_object('site/index.html', '<h1>Home</h1>');
+		let response = await dav('PROPFIND', '/site/', { headers: { Depth: '1' }, vars });
+		expect(response.status).toBe(207);
+		expect(await response.text()).toContain('<D:href>/site/index.html</D:href>');
+	});
+});
@@ -0,0 +1,94 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { find_locks } from '../src/lock';
+import { dav, dav_property, lock, propfind_body, put_collection, put_object } from './helpers';
+
+describe('LOCK refresh', () => {
+	const vars = { kv: env.kv };
+	const refresh = (token: string, timeout: string) =>
+		dav('LOCK', '/a.txt', { headers: { If: `(<${token}>)`, Timeout: timeout }, vars });
+
+	it('extends the lock without minting a new token', async () => {
+		await put_object('a.txt', 'a');
+		let token = await lock('/a.txt', { headers: { Timeout: 'Second-60' } });
+		let [before] = await find_locks(env.kv!, 'a.txt');
+
+		let response = await refresh(token, 'Second-3600');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Lock-Token')).toBeNull();
+		let xml = await response.text();
+		expect(xml).toContain(`<href>${token}</href>`);
+		expect(xml).toContain('Second-3600');
+
+		let locks = await find_locks(env.kv!, 'a.txt');
+		expect(locks.map((lock) => lock.token)).toEqual([token]);
+		expect(locks[0].timeout).toBe(3600);
+		expect(locks[0].expires).toBeGreaterThan(before.expires);
+	});
+
+	it("refuses to refresh a lock that doesn't exist with 412", async () => {
+		await put_object('a.txt', 'a');
+		let response = await refresh('urn:uuid:00000000-0000-0000-0000-000000000000', 'Second-3600');
+		expect(response.status).toBe(412);
+	});
+});
+
+describe('LOCK of an unmapped URL', () => {
+	it('creates an empty file in a folder of another tool', async () => {
+		await put_object('other/a.txt', 'a');
+		await lock('/other/new.txt');
+		expect((await env.bucket.head('other/new.txt'))?.size).toBe(0);
+	});
+
+	it('refuses a parent that is a file with 409', async () => {
+		await put_object('a.txt', 'a');
+		let body = '<lockinfo xmlns="DAV:"><lockscope><exclusive/></lockscope><locktype><write/></locktype></lockinfo>';
+		let response = await dav('LOCK', '/a.txt/new.txt', { body, vars: { kv: env.kv } });
+		expect(response.status).toBe(409);
+		expect(await env.bucket.head('a.txt/new.txt')).toBeNull();
+	});
+});
+
+describe('lock properties in PROPFIND', () => {
+	const vars = { kv: env.kv };
+	const propfind = async (path: string, init = {}) => {
+		let body = propfind_body('supportedlock', 'lockdiscovery');
+		let response = await dav('PROPFIND', path, { body, headers: { Depth: '0' }, vars, ...init });
+		return await response.text();
+	};
+
+	it('reports the active lock of a locked resource', async () => {
+		await put_object('a.txt', 'a');
+		let token = await lock('/a.txt');
+		let lockdiscovery = dav_property(await propfind('/a.txt'), 'lockdiscovery')!;
+		expect(lockdiscovery).toContain('<activelock>');
+		expect(lockdiscovery).toContain(`<locktoken><href>${token}</href></locktoken>`);
+		expect(lockdiscovery).toContain('<lockroot><href>/a.txt</href></lockroot>');
+	});
+
+	it('reports the lock of a collection on its members', async () => {
+		await put_collection('docs');
+		await put_object('docs/a.txt', 'a');
+		let token = await lock('/docs/');
+		let lockdiscovery = dav_property(await propfind('/docs/a.txt'), 'lockdiscovery')!;
+		expect(lockdiscovery).toContain(token);
+		expect(lockdiscovery).toContain('<lockroot><href>/docs/</href></lockroot>');
+	});
+
+	it('has an empty lockdiscovery for an unlocked resource, and the supported locks', async () => {
+		await put_object('a.txt', 'a');
+		let xml = await propfind('/a.txt');
+		expect(dav_property(xml, 'lockdiscovery')).toBe('');
+		let supportedlock = dav_property(xml, 'supportedlock')!;
+		expect(supportedlock).toContain('<exclusive/>');
+		expect(supportedlock).toContain('<shared/>');
+	});
+
+	it('reports both as missing without locking', async () => {
+		await put_object('a.txt', 'a');
+		let xml = (await propfind('/a.txt', { vars: {} })).replace(/>\s+</g, '><');
+		expect(xml).toContain('<D:supportedlock/><D:lockdiscovery/>');
+		expect(xml).toContain('HTTP/1.1 404 Not Found');
+		expect(xml).not.toContain('200 OK');
+	});
+});
@@ -0,0 +1,54 @@
+import { describe, expect, it } from 'vitest';
+import { dav, list_keys, propfind_body, put_collection, put_object } from './helpers';
+
+describe('MKCOL', () => {
+	it('creates a collection PROPFIND reports as one', async () => {
+		expect((await dav('MKCOL', '/docs')).status).toBe(201);
+		expect(await list_keys()).toEqual(['docs']);
+
+		let response = await dav('PROPFIND', '/docs/', { headers: { Depth: '0' }, body: propfind_body('resourcetype') });
+		expect(response.status).toBe(207);
+		expect(await response.text()).toContain('<D:resourcetype><collection /></D:resourcetype>');
+	});
+});
+
+describe('MKCOL outcomes', () => {
+	it('creates a collection in an existing one with 201', async () => {
+		await put_collection('docs');
+		expect((await dav('MKCOL', '/docs/sub/')).status).toBe(201);
+		expect(await list_keys()).toEqual(['docs', 'docs/sub']);
+	});
+
+	it("refuses a collection whose parent doesn't exist with 409", async () => {
+		expect((await dav('MKCOL', '/missing/sub')).status).toBe(409);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('refuses a collection in a file with 409', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('MKCOL', '/a.txt/sub')).status).toBe(409);
+	});
+
+	it('refuses what already exists with 405', async () => {
+		await put_collection('docs');
+		await put_object('a.txt', 'a');
+		await put_object('other/b.txt', 'b');
+		for (let path of ['/docs', '/a.txt', '/other', '/']) {
+			expect((await dav('MKCOL', path)).status).toBe(405);
+		}
+		expect(await list_keys()).toEqual(['a.txt', 'docs', 'other/b.txt']);
+	});
+});
+
+describe('MKCOL with a body', () => {
+	it('is refused with 415, creating nothing', async () => {
+		let body = '<?xml version="1.0"?><D:mkcol xmlns:D="DAV:"/>';
+		let response = await dav('MKCOL', '/docs', { body, headers: { 'Content-Type': 'application/xml' } });
+		expect(response.status).toBe(415);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('is fine when the body is empty', async () => {
+		expect((await dav('MKCOL', '/docs', { body: '' })).status).toBe(201);
+	});
+});
@@ -0,0 +1,43 @@
+import { describe, expect, it } from 'vitest';
+import { dav, put_object } from './helpers';
+
+describe('request paths', () => {
+	const vars = { ROOT_PREFIX: 'public' };
+	const get = async (path: string) => {
+		let response = await dav('GET', path, { vars });
+		return { status: response.status, body: await response.text() };
+	};
+
+	it("can't leave the root with %2e%2e segments", async () => {
+		await put_object('secret.txt', 'secret');
+		await put_object('public/a.txt', 'a');
+		expect((await get('/%2e%2e/secret.txt')).status).toBe(404);
+		expect((await get('/%2E%2e/%2e%2e/secret.txt')).status).toBe(404);
+		expect(await get('/docs/%2e%2e/a.txt')).toEqual({ status: 200, body: 'a' });
+	});
+
+	it('takes backslashes for slashes, but not encoded ones', async () => {
+		await put_object('public/docs/a.txt', 'a');
+		expect(await get('/docs\\a.txt')).toEqual({ status: 200, body: 'a' });
+		expect((await get('/docs%5Ca.txt')).status).toBe(403);
+		expect((await get('/docs%2Fa.txt')).status).toBe(403);
+	});
+
+	it('drops . segments but keeps trailing dots of names', async () => {
+		await put_object('public/docs/a.txt', 'a');
+		expect(await get('/docs/./a.txt')).toEqual({ status: 200, body: 'a' });
+		expect(await get('/docs/%2e/a.txt')).toEqual({ status: 200, body: 'a' });
+		expect((await get('/docs/a.txt.')).status).toBe(404);
+		expect((await get('/docs/a.txt%2e')).status).toBe(404);
+		expect((await get('/docs/.../a.txt')).status).toBe(404);
+	});
+
+	it('collapses duplicate slashes', async () => {
+		await put_object('public/docs/a.txt', 'a');
+		expect(await get('//docs///a.txt')).toEqual({ status: 200, body: 'a' });
+	});
+
+	it('refuses null bytes', async () => {
+		expect((await get('/docs/a%00.txt')).status).toBe(403);
+	});
+});
@@ -0,0 +1,32 @@
+import { describe, expect, it } from 'vitest';
+import { sign_path, verify_signature } from '../src/presign';
+
+describe('verify_signature', () => {
+	const expires = Math.floor(Date.now() / 1000) + 3600;
+
+	it('accepts the signature of the path and expiry', async () => {
+		let signature = await sign_path('secret', 'docs/a.txt', expires);
+		expect(signature).toMatch(/^[0-9a-f]{64}$/);
+		expect(await verify_signature('secret', 'docs/a.txt', String(expires), signature)).toBe(true);
+	});
+
+	it('refuses it for another path, expiry or secret', async () => {
+		let signature = await sign_path('secret', 'docs/a.txt', expires);
+		expect(await verify_signature('secret', 'docs/b.txt', String(expires), signature)).toBe(false);
+		expect(await verify_signature('secret', 'docs/a.txt', String(expires + 1), signature)).toBe(false);
+		expect(await verify_signature('other', 'docs/a.txt', String(expires), signature)).toBe(false);
+	});
+
+	it('refuses expired links', async () => {
+		let expired = Math.floor(Date.now() / 1000) - 1;
+		let signature = await sign_path('secret', 'docs/a.txt', expired);
+		expect(await verify_signature('secret', 'docs/a.txt', String(expired), signature)).toBe(false);
+	});
+
+	it('refuses malformed parameters', async () => {
+		let signature = await sign_path('secret', 'docs/a.txt', expires);
+		expect(await verify_signature('secret', 'docs/a.txt', 'soon', signature)).toBe(false);
+		expect(await verify_signature('secret', 'docs/a.txt', String(expires), 'xyz')).toBe(false);
+		expect(await verify_signature('secret', 'docs/a.txt', String(expires), '')).toBe(false);
+	});
+});
@@ -0,0 +1,444 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { PropfindEntry, minimal_propfind, parse_propfind, stream_multistatus } from '../src/propfind';
+import { parse_xml } from '../src/xml';
+import {
+	dav,
+	dav_property,
+	multistatus_hrefs,
+	multistatus_response,
+	propfind_body,
+	put_collection,
+	put_object,
+	stub_bucket,
+} from './helpers';
+
+const ENTRY: PropfindEntry = {
+	href: '/docs/a&b.txt',
+	properties: [
+		{ namespace: 'DAV:', name: 'resourcetype', value: '' },
+		{ namespace: 'DAV:', name: 'getcontentlength', value: '3' },
+		{ namespace: 'urn:example', name: 'color', value: 'blue' },
+	],
+};
+
+// The Multi-Status of the entries, without the whitespace between the elements
+async function multistatus(entries: PropfindEntry[], body: string | null): Promise<string> {
+	let request = parse_propfind(body === null ? null : parse_xml(body))!;
+	let xml = await new Response(stream_multistatus(entries, request)).text();
+	return xml.replace(/>\s+</g, '><').trim();
+}
+
+function fixture(responses: string): string {
+	return `<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">${responses}</D:multistatus>`;
+}
+
+describe('stream_multistatus', () => {
+	it('lists every property for allprop', async () => {
+		expect(await multistatus([ENTRY], null)).toBe(
+			fixture(
+				'<D:response><D:href>/docs/a&amp;b.txt</D:href><D:propstat><D:prop xmlns="DAV:">' +
+					'<D:resourcetype/><D:getcontentlength>3</D:getcontentlength><color xmlns="urn:example">blue</color>' +
+					'</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>',
+			),
+		);
+	});
+
+	it('lists the names without values for propname', async () => {
+		let body = '<propfind xmlns="DAV:"><propname/></propfind>';
+		expect(await multistatus([ENTRY], body)).toBe(
+			fixture(
+				'<D:response><D:href>/docs/a&amp;b.txt</D:href><D:propstat><D:prop xmlns="DAV:">' +
+					'<D:resourcetype/><D:getcontentlength/><color xmlns="urn:example"/>' +
+					'</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>',
+			),
+		);
+	});
+
+	it('lists the missing properties of a prop with 404', async () => {
+		let body = '<propfind xmlns="DAV:"><prop><getcontentlength/><getetag/></prop></propfind>';
+		expect(await multistatus([ENTRY], body)).toBe(
+			fixture(
+				'<D:response><D:href>/docs/a&amp;b.txt</D:href>' +
+					'<D:propstat><D:prop xmlns="DAV:"><D:getcontentlength>3</D:getcontentlength></D:prop>' +
+					'<D:status>HTTP/1.1 200 OK</D:status></D:propstat>' +
+					'<D:propstat><D:prop xmlns="DAV:"><D:getetag/></D:prop>' +
+					'<D:status>HTTP/1.1 404 Not Found</D:status></D:propstat></D:response>',
+			),
+		);
+	});
+
+	it('leaves the missing properties out when minimal', async () => {
+		let request = minimal_propfind({ mode: 'prop', names: [{ namespace: 'DAV:', name: 'getetag' }] });
+		let xml = await new Response(stream_multistatus([ENTRY], request)).text();
+		expect(xml).not.toContain('404 Not Found');
+		expect(xml).not.toContain('getetag');
+	});
+
+	it('writes an empty Multi-Status without entries', async () => {
+		expect(await multistatus([], null)).toBe(fixture(''));
+	});
+
+	it('only makes the entries the reader gets to', async () => {
+		let made = 0;
+		let finished = false;
+		async function* entries() {
+			try {
+				for (let i = 0; i < 100000; i++) {
+					made++;
+					yield { ...ENTRY, href: `/docs/${i}.txt` };
+				}
+			} finally {
+				finished = true;
+			}
+		}
+		let reader = stream_multistatus(entries(), { mode: 'allprop' }).getReader();
+		for (let i = 0; i < 3; i++) {
+			await reader.read();
+		}
+		expect(made).toBeLessThan(5);
+		await reader.cancel();
+		expect(finished).toBe(true);
+	});
+});
+
+describe('parse_propfind', () => {
+	it('asks for allprop without a body', () => {
+		expect(parse_propfind(null)).toEqual({ mode: 'allprop' });
+	});
+
+	it('reads the names of a prop', () => {
+		let body = parse_xml('<D:propfind xmlns:D="DAV:" xmlns:E="urn:example"><D:prop><E:color/></D:prop></D:propfind>');
+		expect(parse_propfind(body)).toEqual({ mode: 'prop', names: [{ namespace: 'urn:example', name: 'color' }] });
+	});
+
+	it('is null for a body asking for nothing', () => {
+		expect(parse_propfind(parse_xml('<propfind xmlns="DAV:"/>'))).toBeNull();
+	});
+});
+
+describe('PROPFIND listings', () => {
+	// R2 pages its listings, two keys a page makes a few of them
+	let pages = 0;
+	const paging_bucket = stub_bucket({
+		list: (options?: R2ListOptions) => {
+			pages++;
+			return env.bucket.list({ ...options, limit: 2 });
+		},
+	});
+
+	it('lists every page of a folder', async () => {
+		for (let name of ['a', 'b', 'c', 'd', 'e']) {
+			await put_object(`${name}.txt`, name);
+		}
+		pages = 0;
+		let response = await dav('PROPFIND', '/', { headers: { Depth: '1' }, vars: { bucket: paging_bucket } });
+		expect(multistatus_hrefs(await response.text())).toEqual(['/', '/a.txt', '/b.txt', '/c.txt', '/d.txt', '/e.txt']);
+		expect(pages).toBe(3);
+	});
+
+	it('lists every page of a deep listing', async () => {
+		for (let name of ['a', 'b', 'c/d', 'c/e']) {
+			await put_object(`${name}.txt`, name);
+		}
+		let response = await dav('PROPFIND', '/', { headers: { Depth: 'infinity' }, vars: { bucket: paging_bucket } });
+		let hrefs = multistatus_hrefs(await response.text());
+		expect(hrefs).toEqual(['/', '/a.txt', '/b.txt', '/c/d.txt', '/c/e.txt']);
+	});
+});
+
+describe('PROPFIND streaming', () => {
+	it('lists a big folder page by page as the body is read', async () => {
+		let names = Array.from({ length: 45 }, (_, i) => `${i.toString().padStart(2, '0')}.txt`);
+		await Promise.all(names.map((name) => put_object(`docs/${name}`, 'x')));
+		await put_collection('docs');
+		let pages = 0;
+		let bucket = stub_bucket({
+			list: (options?: R2ListOptions) => {
+				pages++;
+				return env.bucket.list({ ...options, limit: 10 });
+			},
+		});
+		let response = await dav('PROPFIND', '/docs/', { headers: { Depth: '1' }, vars: { bucket } });
+		let reader = response.body!.getReader();
+		await reader.read();
+		await reader.read();
+		expect(pages).toBeLessThanOrEqual(1);
+		let rest = '';
+		for (let chunk = await reader.read(); !chunk.done; chunk = await reader.read()) {
+			rest += new TextDecoder().decode(chunk.value);
+		}
+		expect(pages).toBe(5);
+		expect(rest).toContain('<D:href>/docs/44.txt</D:href>');
+	});
+});
+
+describe('PROPFIND Depth: 1', () => {
+	it('lists only the direct members of a two-level tree', async () => {
+		await put_collection('docs');
+		await put_object('docs/a.txt', 'a');
+		await put_collection('docs/sub');
+		await put_object('docs/sub/b.txt', 'b');
+		// A folder of another tool, with no marker of its own
+		await put_object('docs/other/c.txt', 'c');
+
+		let response = await dav('PROPFIND', '/docs/', { headers: { Depth: '1' } });
+		expect(response.status).toBe(207);
+		let hrefs = multistatus_hrefs(await response.text());
+		expect(hrefs.sort()).toEqual(['/docs/', '/docs/a.txt', '/docs/other/', '/docs/sub/']);
+	});
+});
+
+describe('PROPFIND depths', () => {
+	const tree = async () => {
+		await put_collection('docs');
+		await put_object('docs/a.txt', 'a');
+		await put_collection('docs/sub');
+		await put_object('docs/sub/b.txt', 'b');
+	};
+	const propfind_hrefs = async (depth?: string) => {
+		let headers: Record<string, string> = depth === undefined ? {} : { Depth: depth };
+		let response = await dav('PROPFIND', '/docs/', { headers });
+		expect(response.status).toBe(207);
+		return multistatus_hrefs(await response.text()).sort();
+	};
+
+	it('only describes the resource itself with Depth: 0', async () => {
+		await tree();
+		expect(await propfind_hrefs('0')).toEqual(['/docs/']);
+	});
+
+	it('adds the direct members with Depth: 1', async () => {
+		await tree();
+		expect(await propfind_hrefs('1')).toEqual(['/docs/', '/docs/a.txt', '/docs/sub/']);
+	});
+
+	it('adds everything below with Depth: infinity, the default', async () => {
+		await tree();
+		let everything = ['/docs/', '/docs/a.txt', '/docs/sub/', '/docs/sub/b.txt'];
+		expect(await propfind_hrefs('infinity')).toEqual(everything);
+		expect(await propfind_hrefs()).toEqual(everything);
+	});
+
+	it('refuses other depths', async () => {
+		await tree();
+		expect((await dav('PROPFIND', '/docs/', { headers: { Depth: '2' } })).status).toBe(400);
+	});
+
+	it('describes a file the same at every depth', async () => {
+		await put_object('a.txt', 'a');
+		for (let depth of ['0', '1', 'infinity']) {
+			let response = await dav('PROPFIND', '/a.txt', { headers: { Depth: depth } });
+			expect(multistatus_hrefs(await response.text())).toEqual(['/a.txt']);
+		}
+	});
+});
+
+describe('PROPFIND dates', () => {
+	const ISO_8601 = /^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z$/;
+	const HTTP_DATE = /^(Mon|Tue|Wed|Thu|Fri|Sat|Sun), \d{2} [A-Z][a-z]{2} \d{4} \d{2}:\d{2}:\d{2} GMT$/;
+
+	it('formats the upload time of an object', async () => {
+		let { uploaded } = await put_object('a.txt', 'a');
+		let xml = await (await dav('PROPFIND', '/a.txt', { headers: { Depth: '0' } })).text();
+		let creationdate = dav_property(xml, 'creationdate')!;
+		let getlastmodified = dav_property(xml, 'getlastmodified')!;
+		expect(creationdate).toMatch(ISO_8601);
+		expect(getlastmodified).toMatch(HTTP_DATE);
+		expect(creationdate).toBe(uploaded.toISOString());
+		expect(getlastmodified).toBe(uploaded.toUTCString());
+	});
+
+	it('dates collections, with a marker or without', async () => {
+		await put_collection('docs');
+		await put_object('other/a.txt', 'a');
+		for (let path of ['/docs/', '/other/']) {
+			let xml = await (await dav('PROPFIND', path, { headers: { Depth: '0' } })).text();
+			expect(dav_property(xml, 'creationdate')).toMatch(ISO_8601);
+			expect(dav_property(xml, 'getlastmodified')).toMatch(HTTP_DATE);
+		}
+	});
+});
+
+describe('PROPFIND displayname and getcontentlength', () => {
+	it('escapes the special characters of a file name', async () => {
+		await put_object('docs/a&b <c>.txt', 'abc');
+		let path = '/docs/' + encodeURIComponent('a&b <c>.txt');
+		let xml = await (await dav('PROPFIND', path, { headers: { Depth: '0' } })).text();
+		expect(dav_property(xml, 'displayname')).toBe('a&amp;b &lt;c&gt;.txt');
+		expect(dav_property(xml, 'getcontentlength')).toBe('3');
+	});
+
+	it('names collections after their last segment and gives them no length', async () => {
+		await put_collection('docs/R&D');
+		await put_object('docs/other/a.txt', 'a');
+		let xml = await (await dav('PROPFIND', '/docs/', { headers: { Depth: '1' } })).text();
+		let marked = multistatus_response(xml, '/docs/R%26D/')!;
+		expect(dav_property(marked, 'displayname')).toBe('R&amp;D');
+		expect(dav_property(marked, 'getcontentlength')).toBeUndefined();
+		let unmarked = multistatus_response(xml, '/docs/other/')!;
+		expect(dav_property(unmarked, 'displayname')).toBe('other');
+		expect(dav_property(unmarked, 'getcontentlength')).toBeUndefined();
+	});
+});
+
+describe('PROPFIND resourcetype', () => {
+	it('tags each entry of a mixed listing', async () => {
+		await put_object('a.txt', 'a');
+		await put_collection('marked');
+		await put_object('unmarked/b.txt', 'b');
+		let xml = await (await dav('PROPFIND', '/', { headers: { Depth: '1' } })).text();
+		let resourcetype = (href: string) => dav_property(multistatus_response(xml, href)!, 'resourcetype');
+		expect(resourcetype('/')).toBe('<collection />');
+		expect(resourcetype('/a.txt')).toBe('');
+		expect(resourcetype('/marked/')).toBe('<collection />');
+		expect(resourcetype('/unmarked/')).toBe('<collection />');
+	});
+});
+
+describe('PROPFIND modes', () => {
+	const file_with_color = async () => {
+		await put_object('a.txt', 'abc');
+		let body = `<D:propertyupdate xmlns:D="DAV:" xmlns:E="urn:example">
+			<D:set><D:prop><E:color>blue</E:color></D:prop></D:set>
+		</D:propertyupdate>`;
+		expect((await dav('PROPPATCH', '/a.txt', { body })).status).toBe(207);
+	};
+	const propfind = async (body: string) => {
+		let response = await dav('PROPFIND', '/a.txt', { body, headers: { Depth: '0' } });
+		expect(response.status).toBe(207);
+		return await response.text();
+	};
+
+	it('returns the live and dead properties with their values for allprop', async () => {
+		await file_with_color();
+		let xml = await propfind(propfind_body());
+		expect(dav_property(xml, 'getcontentlength')).toBe('3');
+		expect(dav_property(xml, 'getetag')).toMatch(/^".+"$/);
+		expect(xml).toContain('<color xmlns="urn:example">blue</color>');
+		expect(xml).not.toContain('404 Not Found');
+	});
+
+	it('returns the names without values for propname', async () => {
+		await file_with_color();
+		let xml = await propfind('<propfind xmlns="DAV:"><propname/></propfind>');
+		expect(xml).toContain('<D:getcontentlength/>');
+		expect(xml).toContain('<D:getetag/>');
+		expect(xml).toContain('<color xmlns="urn:example"/>');
+		expect(xml).not.toContain('blue');
+	});
+
+	it('returns exactly the properties of a prop, the unknown ones with 404', async () => {
+		await file_with_color();
+		let body = `<propfind xmlns="DAV:" xmlns:E="urn:example"><prop>
+			<getcontentlength/><E:color/><E:missing/>
+		</prop></propfind>`;
+		let xml = await propfind(body);
+		let [found, missing] = xml.split('HTTP/1.1 200 OK');
+		expect(found).toContain('<D:getcontentlength>3</D:getcontentlength>');
+		expect(found).toContain('<color xmlns="urn:example">blue</color>');
+		expect(missing).toContain('<missing xmlns="urn:example"/>');
+		expect(missing).toContain('HTTP/1.1 404 Not Found');
+		expect(xml).not.toContain('getetag');
+	});
+});
+
+describe('PROPFIND of what does not exist', () => {
+	it('is a 404 for a path that is neither a file nor a folder', async () => {
+		await put_object('docs/a.txt', 'a');
+		for (let path of ['/missing.txt', '/missing/', '/docs/missing.txt', '/do']) {
+			expect((await dav('PROPFIND', path, { headers: { Depth: '0' } })).status).toBe(404);
+		}
+	});
+
+	it('is a 207 for an empty folder with a marker', async () => {
+		await put_collection('empty');
+		let response = await dav('PROPFIND', '/empty/', { headers: { Depth: '1' } });
+		expect(response.status).toBe(207);
+		expect(multistatus_hrefs(await response.text())).toEqual(['/empty/']);
+	});
+
+	it('is a 207 for the root of an empty bucket', async () => {
+		let response = await dav('PROPFIND', '/', { headers: { Depth: '1' } });
+		expect(response.status).toBe(207);
+		expect(multistatus_hrefs(await response.text())).toEqual(['/']);
+	});
+});
+
+describe('PROPFIND with Prefer: return=minimal', () => {
+	it('leaves out everything but resourcetype for allprop', async () => {
+		await put_object('docs/a.txt', 'abc');
+		let headers = { Depth: '1', Prefer: 'return=minimal' };
+		let response = await dav('PROPFIND', '/docs/', { headers, body: propfind_body() });
+		expect(response.status).toBe(207);
+		expect(response.headers.get('Preference-Applied')).toBe('return=minimal');
+		let xml = await response.text();
+		expect(multistatus_hrefs(xml)).toEqual(['/docs/', '/docs/a.txt']);
+		expect(dav_property(multistatus_response(xml, '/docs/a.txt')!, 'resourcetype')).toBe('');
+		expect(xml).not.toContain('getcontentlength');
+	});
+
+	it('returns everything without it', async () => {
+		await put_object('docs/a.txt', 'abc');
+		let response = await dav('PROPFIND', '/docs/a.txt', { headers: { Depth: '0' }, body: propfind_body() });
+		expect(response.headers.has('Preference-Applied')).toBe(false);
+		expect(dav_property(await response.text(), 'getcontentlength')).toBe('3');
+	});
+});
+
+describe('FOLDER_MARKERS', () => {
+	const vars = { FOLDER_MARKERS: 'slash, $folder$' };
+	const propfind = async (path: string, depth: string, markers?: typeof vars) => {
+		let body = propfind_body('resourcetype');
+		let response = await dav('PROPFIND', path, { headers: { Depth: depth }, body, vars: markers });
+		expect(response.status).toBe(207);
+		return await response.text();
+	};
+
+	it('lists a Hadoop _$folder$ marker as the collection it stands for', async () => {
+		await put_object('photos_$folder$');
+		let xml = await propfind('/', '1', vars);
+		expect(multistatus_hrefs(xml)).toEqual(['/', '/photos/']);
+		expect(dav_property(multistatus_response(xml, '/photos/')!, 'resourcetype')).toBe('<collection />');
+		expect(multistatus_hrefs(await propfind('/photos/', '0', vars))).toEqual(['/photos/']);
+	});
+
+	it('lists it as a file by default', async () => {
+		await put_object('photos_$folder$');
+		let xml = await propfind('/', '1');
+		expect(xml).not.toContain('<D:href>/photos/</D:href>');
+		expect(multistatus_hrefs(xml)).toHaveLength(2);
+	});
+
+	it('lists a trailing-slash marker as a collection by default', async () => {
+		await put_object('photos/');
+		let xml = await propfind('/', '1');
+		expect(multistatus_hrefs(xml)).toEqual(['/', '/photos/']);
+		expect(dav_property(multistatus_response(xml, '/photos/')!, 'resourcetype')).toBe('<collection />');
+	});
+});
+
+describe('PROPFIND getcontenttype', () => {
+	const propfind = async (path: string) => {
+		let response = await dav('PROPFIND', path, { headers: { Depth: '0' }, body: propfind_body('getcontenttype') });
+		expect(response.status).toBe(207);
+		return await response.text();
+	};
+
+	it('reports the stored type', async () => {
+		await put_object('report.bin', 'x', { httpMetadata: { contentType: 'application/pdf' } });
+		expect(dav_property(await propfind('/report.bin'), 'getcontenttype')).toBe('application/pdf');
+	});
+
+	it('guesses it from the extension of a file stored without one', async () => {
+		await put_object('report.pdf', 'x');
+		expect(dav_property(await propfind('/report.pdf'), 'getcontenttype')).toBe('application/pdf');
+	});
+
+	it('leaves it out for collections', async () => {
+		await put_collection('docs.pdf');
+		let xml = await propfind('/docs.pdf/');
+		expect(xml).toMatch(/<D:getcontenttype\/>.*HTTP\/1.1 404 Not Found/s);
+		expect(xml).not.toContain('HTTP/1.1 200 OK');
+	});
+});
@@ -0,0 +1,78 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, put_collection, put_object, stub_bucket } from './helpers';
+
+// A propertyupdate setting the color property of urn:example
+function set_color(color: string): string {
+	return `<?xml version="1.0" encoding="utf-8"?>
+<D:propertyupdate xmlns:D="DAV:" xmlns:E="urn:example">
+	<D:set><D:prop><E:color>${color}</E:color></D:prop></D:set>
+</D:propertyupdate>`;
+}
+
+describe('PROPPATCH', () => {
+	it('changes the metadata of a small object and keeps the rest of it', async () => {
+		let stored = await put_object('a.txt', 'content', { httpMetadata: { contentType: 'text/plain' } });
+		let response = await dav('PROPPATCH', '/a.txt', { body: set_color('blue') });
+		expect(response.status).toBe(207);
+		expect(await response.text()).toContain('HTTP/1.1 200 OK');
+
+		let object = await env.bucket.get('a.txt');
+		expect(Object.values(object!.customMetadata!)).toContain('blue');
+		expect(object!.httpMetadata?.contentType).toBe('text/plain');
+		expect(object!.size).toBe(stored.size);
+		expect(await object!.text()).toBe('content');
+	});
+
+	it("doesn't read objects over PROPPATCH_MAX_BYTES, every property is a 507", async () => {
+		await put_object('big.txt', 'x'.repeat(100));
+		let reads = 0;
+		let bucket = stub_bucket({
+			get: (key: string) => {
+				reads++;
+				return env.bucket.get(key);
+			},
+		});
+		let vars = { bucket, PROPPATCH_MAX_BYTES: '10' };
+		let response = await dav('PROPPATCH', '/big.txt', { body: set_color('blue'), vars });
+		expect(response.status).toBe(207);
+		expect(await response.text()).toContain('HTTP/1.1 507 Insufficient Storage');
+		expect(reads).toBe(0);
+		expect((await env.bucket.head('big.txt'))!.customMetadata).toEqual({});
+	});
+});
+
+describe('custom metadata as properties', () => {
+	const METADATA = 'urn:r2-webdav:metadata';
+	const allprop = async (path: string, vars = {}) =>
+		await (await dav('PROPFIND', path, { headers: { Depth: '0' }, vars })).text();
+
+	it('shows the custom metadata of other tools escaped', async () => {
+		await put_object('a.txt', 'a', { customMetadata: { author: 'A & <B>' } });
+		expect(await allprop('/a.txt')).toContain(`<author xmlns="${METADATA}">A &amp; &lt;B&gt;</author>`);
+	});
+
+	it('round-trips metadata set by PROPPATCH', async () => {
+		await put_object('a.txt', 'a');
+		let body = `<D:propertyupdate xmlns:D="DAV:" xmlns:M="${METADATA}">
+			<D:set><D:prop><M:author>A &amp; B</M:author></D:prop></D:set>
+		</D:propertyupdate>`;
+		expect((await dav('PROPPATCH', '/a.txt', { body })).status).toBe(207);
+		expect((await env.bucket.head('a.txt'))!.customMetadata).toEqual({ author: 'A & B' });
+		expect(await allprop('/a.txt')).toContain(`<author xmlns="${METADATA}">A &amp; B</author>`);
+	});
+
+	it('uses METADATA_NAMESPACE', async () => {
+		await put_object('a.txt', 'a', { customMetadata: { author: 'A' } });
+		expect(await allprop('/a.txt', { METADATA_NAMESPACE: 'urn:example' })).toContain(
+			'<author xmlns="urn:example">A</author>',
+		);
+	});
+
+	it("doesn't leak the metadata kept for itself", async () => {
+		await put_collection('docs');
+		await put_object('a.txt', 'a', { customMetadata: { 'not a name': 'x' } });
+		expect(await allprop('/docs/')).not.toContain(METADATA);
+		expect(await allprop('/a.txt')).not.toContain(METADATA);
+	});
+});
@@ -0,0 +1,316 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, dav_property, list_keys, put_collection, put_object } from './helpers';
+
+describe('create-only PUT', () => {
+	const create = (body: string) => dav('PUT', '/a.txt', { body, headers: { 'If-None-Match': '*' } });
+
+	it('creates a missing file', async () => {
+		expect((await create('a')).status).toBe(201);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('a');
+	});
+
+	it('refuses to replace an existing file', async () => {
+		await put_object('a.txt', 'a');
+		expect((await create('b')).status).toBe(412);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('a');
+	});
+
+	it('lets only one of two concurrent puts through', async () => {
+		let responses = await Promise.all([create('a'), create('b')]);
+		expect(responses.map(({ status }) => status).sort()).toEqual([201, 412]);
+		let winner = responses[0].status === 201 ? 'a' : 'b';
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe(winner);
+	});
+});
+
+describe('PUT content types', () => {
+	// A string body would come with a text/plain type of its own
+	const body = new TextEncoder().encode('a { color: red }');
+
+	it('guesses the type of an upload without one from its extension', async () => {
+		expect((await dav('PUT', '/style.css', { body })).status).toBe(201);
+		expect((await dav('GET', '/style.css')).headers.get('Content-Type')).toBe('text/css');
+	});
+
+	it('guesses it in place of a generic type', async () => {
+		await dav('PUT', '/style.css', { body, headers: { 'Content-Type': 'application/octet-stream' } });
+		expect((await dav('GET', '/style.css')).headers.get('Content-Type')).toBe('text/css');
+	});
+
+	it('keeps the type the client sent', async () => {
+		await dav('PUT', '/style.css', { body, headers: { 'Content-Type': 'text/plain' } });
+		expect((await dav('GET', '/style.css')).headers.get('Content-Type')).toBe('text/plain');
+	});
+});
+
+describe('PUT metadata', () => {
+	const headers = {
+		'Content-Disposition': 'attachment; filename="report.pdf"',
+		'Content-Language': 'fr',
+		'Cache-Control': 'no-cache',
+		'Content-Encoding': 'gzip',
+	};
+
+	it('serves the headers of the upload back with GET and HEAD', async () => {
+		expect((await dav('PUT', '/report.pdf', { body: 'pdf', headers })).status).toBe(201);
+		for (let method of ['GET', 'HEAD']) {
+			let response = await dav(method, '/report.pdf');
+			expect(response.status).toBe(200);
+			for (let [name, value] of Object.entries(headers)) {
+				expect(response.headers.get(name)).toBe(value);
+			}
+		}
+	});
+});
+
+describe('PUT with Content-MD5', () => {
+	const md5_of_hello = 'XUFAKrxLKna5cZ2REBfFkg==';
+
+	it('stores a body matching its digest', async () => {
+		let response = await dav('PUT', '/a.txt', { body: 'hello', headers: { 'Content-MD5': md5_of_hello } });
+		expect(response.status).toBe(201);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('hello');
+	});
+
+	it('refuses a body not matching it, storing nothing', async () => {
+		let response = await dav('PUT', '/a.txt', { body: 'hellp', headers: { 'Content-MD5': md5_of_hello } });
+		expect(response.status).toBe(400);
+		expect(await env.bucket.head('a.txt')).toBeNull();
+	});
+
+	it('refuses a digest that is no MD5', async () => {
+		let response = await dav('PUT', '/a.txt', { body: 'hello', headers: { 'Content-MD5': 'aGVsbG8=' } });
+		expect(response.status).toBe(400);
+		expect(await env.bucket.head('a.txt')).toBeNull();
+	});
+});
+
+describe('PUT past QUOTA_BYTES', () => {
+	const vars = { QUOTA_BYTES: '10' };
+
+	it('refuses an upload past the quota with 507, storing nothing', async () => {
+		await put_object('a.txt', 'abcdef');
+		let response = await dav('PUT', '/b.txt', { body: 'ghijk', vars });
+		expect(response.status).toBe(507);
+		expect(await response.text()).toContain('quota-not-exceeded');
+		expect(await list_keys()).toEqual(['a.txt']);
+	});
+
+	it('takes uploads up to the quota', async () => {
+		await put_object('a.txt', 'abcdef');
+		expect((await dav('PUT', '/b.txt', { body: 'ghij', vars })).status).toBe(201);
+	});
+
+	it('only counts what a replaced file grows by', async () => {
+		await put_object('a.txt', 'abcdef');
+		expect((await dav('PUT', '/a.txt', { body: 'abcdefghij', vars })).status).toBe(204);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('abcdefghij');
+	});
+});
+
+describe('PUT status', () => {
+	it('answers 201 with a Location for a new file', async () => {
+		let response = await dav('PUT', '/docs/new%20file.txt', { body: 'a' });
+		expect(response.status).toBe(201);
+		expect(response.headers.get('Location')).toBe('https://dav.example.com/docs/new%20file.txt');
+		expect(response.headers.get('ETag')).toBe((await env.bucket.head('docs/new file.txt'))!.httpEtag);
+	});
+
+	it('answers 204 without a Location when replacing one', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('PUT', '/a.txt', { body: 'b' });
+		expect(response.status).toBe(204);
+		expect(response.headers.get('Location')).toBeNull();
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('b');
+	});
+});
+
+describe('PUT past MAX_UPLOAD_BYTES', () => {
+	const vars = { MAX_UPLOAD_BYTES: '10' };
+	// A body sent in chunks, without a Content-Length
+	const stream = (...chunks: string[]) =>
+		new ReadableStream<Uint8Array>({
+			start(controller) {
+				chunks.forEach((chunk) => controller.enqueue(new TextEncoder().encode(chunk)));
+				controller.close();
+			},
+		});
+
+	it('refuses a longer Content-Length with 413 before reading anything', async () => {
+		let body = 'x'.repeat(100);
+		let response = await dav('PUT', '/a.txt', { body, headers: { 'Content-Length': '100' }, vars });
+		expect(response.status).toBe(413);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('refuses a stream once it goes past the limit, storing nothing of it', async () => {
+		let response = await dav('PUT', '/a.txt', { body: stream('123456', '789012'), vars });
+		expect(response.status).toBe(413);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('takes a stream up to the limit', async () => {
+		let response = await dav('PUT', '/a.txt', { body: stream('12345', '67890'), vars });
+		expect(response.status).toBe(201);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('1234567890');
+	});
+});
+
+describe('PUT with Expect: 100-continue', () => {
+	// A body that counts how often it's read from, reading nothing before it's asked to
+	const counted_body = (content: string) => {
+		let reads = { count: 0 };
+		let body = new ReadableStream<Uint8Array>(
+			{
+				pull(controller) {
+					reads.count++;
+					controller.enqueue(new TextEncoder().encode(content));
+					controller.close();
+				},
+			},
+			{ highWaterMark: 0 },
+		);
+		return { body, reads };
+	};
+	const headers = { Expect: '100-continue', 'Content-Length': '5' };
+
+	it('reads the body once the checks passed', async () => {
+		let { body, reads } = counted_body('hello');
+		expect((await dav('PUT', '/a.txt', { body, headers })).status).toBe(201);
+		expect(reads.count).toBe(1);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('hello');
+	});
+
+	it('refuses a client without credentials without reading the body', async () => {
+		let { body, reads } = counted_body('hello');
+		expect((await dav('PUT', '/a.txt', { body, headers, anonymous: true })).status).toBe(401);
+		expect(reads.count).toBe(0);
+	});
+
+	it('refuses an upload past the quota without reading the body', async () => {
+		let { body, reads } = counted_body('hello');
+		let response = await dav('PUT', '/a.txt', { body, headers, vars: { QUOTA_BYTES: '4' } });
+		expect(response.status).toBe(507);
+		expect(reads.count).toBe(0);
+		expect(await list_keys()).toEqual([]);
+	});
+});
+
+describe('zero-byte files', () => {
+	it('round-trip through PUT, PROPFIND and GET', async () => {
+		let response = await dav('PUT', '/empty.txt', { body: '', headers: { 'Content-Length': '0' } });
+		expect(response.status).toBe(201);
+		expect((await env.bucket.head('empty.txt'))?.size).toBe(0);
+
+		let xml = await (await dav('PROPFIND', '/empty.txt', { headers: { Depth: '0' } })).text();
+		expect(dav_property(xml, 'getcontentlength')).toBe('0');
+
+		let get = await dav('GET', '/empty.txt');
+		expect(get.status).toBe(200);
+		expect(get.headers.get('Content-Length')).toBe('0');
+		expect(await get.text()).toBe('');
+	});
+
+	it('are created from a PUT without a body', async () => {
+		expect((await dav('PUT', '/empty.txt')).status).toBe(201);
+		expect((await env.bucket.head('empty.txt'))?.size).toBe(0);
+	});
+
+	it('can be written once created empty, like editors do', async () => {
+		await dav('PUT', '/a.txt', { body: '' });
+		expect((await dav('PUT', '/a.txt', { body: 'content' })).status).toBe(204);
+		expect(await (await dav('GET', '/a.txt')).text()).toBe('content');
+	});
+
+	it('are sent uncompressed', async () => {
+		await put_object('empty.txt', '', { httpMetadata: { contentType: 'text/plain' } });
+		let headers = { 'Accept-Encoding': 'gzip' };
+		let response = await dav('GET', '/empty.txt', { headers, vars: { COMPRESSION_ENABLED: 'true' } });
+		expect(response.headers.get('Content-Encoding')).toBeNull();
+		expect(response.headers.get('Content-Length')).toBe('0');
+	});
+});
+
+describe('PUT storage class', () => {
+	it('stores the class of X-Storage-Class and lists it in PROPFIND', async () => {
+		let headers = { 'X-Storage-Class': 'InfrequentAccess' };
+		expect((await dav('PUT', '/archive.zip', { body: 'zip', headers })).status).toBe(201);
+		expect((await env.bucket.head('archive.zip'))?.storageClass).toBe('InfrequentAccess');
+		let xml = await (await dav('PROPFIND', '/archive.zip', { headers: { Depth: '0' } })).text();
+		expect(xml).toContain('<storageclass xmlns="urn:r2-webdav">InfrequentAccess</storageclass>');
+	});
+
+	it('uses STORAGE_CLASS, or Standard, without the header', async () => {
+		await dav('PUT', '/a.zip', { body: 'zip', vars: { STORAGE_CLASS: 'InfrequentAccess' } });
+		await dav('PUT', '/b.zip', { body: 'zip' });
+		expect((await env.bucket.head('a.zip'))?.storageClass).toBe('InfrequentAccess');
+		expect((await env.bucket.head('b.zip'))?.storageClass).toBe('Standard');
+	});
+
+	it('refuses an unknown class with 400', async () => {
+		let response = await dav('PUT', '/a.zip', { body: 'zip', headers: { 'X-Storage-Class': 'Glacier' } });
+		expect(response.status).toBe(400);
+		expect(await list_keys()).toEqual([]);
+	});
+});
+
+describe('MULTIPART_PART_SIZE', () => {
+	const MiB = 1024 * 1024;
+
+	it.each(['1048576', String(6 * 1024 * MiB), 'big'])('refuses uploads with a part size of %s', async (size) => {
+		let response = await dav('PUT', '/a.txt', { body: 'a', vars: { MULTIPART_PART_SIZE: size } });
+		expect(response.status).toBe(500);
+		expect(await list_keys()).toEqual([]);
+	});
+
+	it('uploads bigger bodies in parts of that size, the last one smaller', async () => {
+		let body = new Uint8Array(11 * MiB).map((_, i) => i % 251);
+		let headers = { 'Content-Length': String(body.byteLength) };
+		let response = await dav('PUT', '/big.bin', { body, headers, vars: { MULTIPART_PART_SIZE: String(5 * MiB) } });
+		expect(response.status).toBe(201);
+		let object = await env.bucket.get('big.bin');
+		expect(object?.size).toBe(body.byteLength);
+		// The ETag of a multipart upload counts its parts
+		expect(object?.etag).toMatch(/-3$/);
+		let digest = async (data: ArrayBuffer | Uint8Array) => new Uint8Array(await crypto.subtle.digest('SHA-256', data));
+		expect(await digest(await object!.arrayBuffer())).toEqual(await digest(body));
+	});
+});
+
+describe('files and collections of the same name', () => {
+	it('refuses a PUT over a collection with 409', async () => {
+		await put_collection('docs');
+		expect((await dav('PUT', '/docs', { body: 'a' })).status).toBe(409);
+		expect((await env.bucket.head('docs'))?.customMetadata?.resourcetype).toBe('<collection />');
+	});
+
+	it('refuses a PUT over a folder implied by its members with 409', async () => {
+		await put_object('docs/a.txt', 'a');
+		expect((await dav('PUT', '/docs', { body: 'a' })).status).toBe(409);
+		expect(await list_keys()).toEqual(['docs/a.txt']);
+	});
+
+	it('refuses a MKCOL over a file, leaving the file', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('MKCOL', '/a.txt')).status).toBe(405);
+		let object = await env.bucket.get('a.txt');
+		expect(object?.customMetadata?.resourcetype).toBeUndefined();
+		expect(await object?.text()).toBe('a');
+	});
+});
+
+describe('Cache-Control', () => {
+	const vars = { CACHE_CONTROL: 'public, max-age=60' };
+
+	it('serves the one stored with the object back', async () => {
+		await put_object('a.txt', 'a', { httpMetadata: { cacheControl: 'max-age=3600' } });
+		expect((await dav('GET', '/a.txt', { vars })).headers.get('Cache-Control')).toBe('max-age=3600');
+	});
+
+	it('serves CACHE_CONTROL for objects stored without one', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('GET', '/a.txt', { vars })).headers.get('Cache-Control')).toBe('public, max-age=60');
+		expect((await dav('GET', '/a.txt')).headers.get('Cache-Control')).toBeNull();
+	});
+});
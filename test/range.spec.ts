@@ -0,0 +1,196 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, put_object, stub_bucket } from './helpers';
+
+const CONTENT = '0123456789';
+
+async function get_range(range: string, headers: Record<string, string> = {}): Promise<Response> {
+	await put_object('digits.txt', CONTENT);
+	return await dav('GET', '/digits.txt', { headers: { Range: range, ...headers } });
+}
+
+describe('ranged GET', () => {
+	it.each([
+		['bytes=2-4', '234', 'bytes 2-4/10'],
+		['bytes=7-', '789', 'bytes 7-9/10'],
+		['bytes=-3', '789', 'bytes 7-9/10'],
+		['bytes=0-0', '0', 'bytes 0-0/10'],
+	])('serves %s', async (range, body, content_range) => {
+		let response = await get_range(range);
+		expect(response.status).toBe(206);
+		expect(response.headers.get('Content-Range')).toBe(content_range);
+		expect(response.headers.get('Content-Length')).toBe(String(body.length));
+		expect(await response.text()).toBe(body);
+	});
+
+	it('serves the whole object for a suffix longer than it', async () => {
+		let response = await get_range('bytes=-100');
+		expect(response.status).toBe(206);
+		expect(response.headers.get('Content-Range')).toBe('bytes 0-9/10');
+		expect(await response.text()).toBe(CONTENT);
+	});
+
+	it('serves the first hundred bytes of a bigger object', async () => {
+		let content = 'x'.repeat(1000);
+		await put_object('big.txt', content);
+		let response = await dav('GET', '/big.txt', { headers: { Range: 'bytes=0-99' } });
+		expect(response.status).toBe(206);
+		expect(response.headers.get('Content-Range')).toBe('bytes 0-99/1000');
+		expect(response.headers.get('Content-Length')).toBe('100');
+		expect(await response.text()).toBe(content.slice(0, 100));
+	});
+
+	it('serves the whole object with 200 without a Range', async () => {
+		await put_object('digits.txt', CONTENT);
+		let response = await dav('GET', '/digits.txt');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Range')).toBeNull();
+		expect(await response.text()).toBe(CONTENT);
+	});
+});
+
+describe('unsatisfiable ranges', () => {
+	it.each(['bytes=10-', 'bytes=50-60'])('refuses %s, starting past the end, with 416', async (range) => {
+		let response = await get_range(range);
+		expect(response.status).toBe(416);
+		expect(response.headers.get('Content-Range')).toBe('bytes */10');
+	});
+
+	it('refuses a suffix range of an empty object', async () => {
+		await put_object('empty.txt');
+		let response = await dav('GET', '/empty.txt', { headers: { Range: 'bytes=-5' } });
+		expect(response.status).toBe(416);
+		expect(response.headers.get('Content-Range')).toBe('bytes */0');
+	});
+
+	it('answers the conditions before the range', async () => {
+		let response = await get_range('bytes=50-', { 'If-Match': '"other"' });
+		expect(response.status).toBe(412);
+	});
+});
+
+describe('If-Range', () => {
+	const get_if_range = (if_range: string) =>
+		dav('GET', '/digits.txt', { headers: { Range: 'bytes=2-4', 'If-Range': if_range } });
+
+	it('serves the range while the etag matches', async () => {
+		let { httpEtag } = await put_object('digits.txt', CONTENT);
+		let response = await get_if_range(httpEtag);
+		expect(response.status).toBe(206);
+		expect(await response.text()).toBe('234');
+	});
+
+	it('serves the whole object once it changed', async () => {
+		await put_object('digits.txt', CONTENT);
+		let response = await get_if_range('"outdated"');
+		expect(response.status).toBe(200);
+		expect(response.headers.get('Content-Range')).toBeNull();
+		expect(await response.text()).toBe(CONTENT);
+	});
+
+	it('compares a date to the second of Last-Modified', async () => {
+		let { uploaded } = await put_object('digits.txt', CONTENT);
+		expect((await get_if_range(uploaded.toUTCString())).status).toBe(206);
+		let earlier = new Date(uploaded.getTime() - 60 * 1000).toUTCString();
+		expect((await get_if_range(earlier)).status).toBe(200);
+	});
+
+	it('never matches a weak etag', async () => {
+		let { httpEtag } = await put_object('digits.txt', CONTENT);
+		expect((await get_if_range(`W/${httpEtag}`)).status).toBe(200);
+	});
+
+	it('matches the weak ETag sent with WEAK_ETAGS', async () => {
+		await put_object('digits.txt', CONTENT);
+		let vars = { WEAK_ETAGS: 'true' };
+		let etag = (await dav('HEAD', '/digits.txt', { vars })).headers.get('ETag')!;
+		expect(etag).toMatch(/^W\//);
+		let response = await dav('GET', '/digits.txt', { headers: { Range: 'bytes=2-4', 'If-Range': etag }, vars });
+		expect(response.status).toBe(206);
+		expect(await response.text()).toBe('234');
+	});
+});
+
+describe('multi-range GET', () => {
+	const content = Array.from({ length: 1000 }, (_, i) => String.fromCharCode(97 + (i % 26))).join('');
+	const ranges = 'bytes=0-9, 100-199, 990-';
+
+	// What the parts of the three ranges add up to, written one after another
+	function sequential(boundary: string): string {
+		let parts = [
+			[0, 9],
+			[100, 199],
+			[990, 999],
+		].map(
+			([start, end], index) =>
+				`${index === 0 ? '' : '\r\n'}--${boundary}\r\nContent-Type: text/plain\r\n` +
+				`Content-Range: bytes ${start}-${end}/1000\r\n\r\n${content.slice(start, end + 1)}`,
+		);
+		return parts.join('') + `\r\n--${boundary}--\r\n`;
+	}
+
+	async function get_ranges(vars = {}): Promise<string> {
+		await put_object('letters.txt', content, { httpMetadata: { contentType: 'text/plain' } });
+		let response = await dav('GET', '/letters.txt', { headers: { Range: ranges }, vars });
+		expect(response.status).toBe(206);
+		let boundary = response.headers.get('Content-Type')!.match(/boundary=(.+)$/)![1];
+		let body = await response.text();
+		expect(response.headers.get('Content-Length')).toBe(String(new TextEncoder().encode(body).byteLength));
+		expect(body).toBe(sequential(boundary));
+		return body.replaceAll(boundary, 'boundary');
+	}
+
+	it('assembles concurrently read parts like sequentially read ones', async () => {
+		expect(await get_ranges({ RANGE_CONCURRENCY: '3' })).toBe(await get_ranges({ RANGE_CONCURRENCY: '1' }));
+	});
+
+	it('keeps the parts in order when an earlier read finishes last', async () => {
+		// The first of the later parts takes the longest
+		let bucket = stub_bucket({
+			get: async (key: string, options?: R2GetOptions) => {
+				let range = options?.range as { offset?: number } | undefined;
+				if (range?.offset === 100) {
+					await new Promise((resolve) => setTimeout(resolve, 50));
+				}
+				return await env.bucket.get(key, options);
+			},
+		});
+		await get_ranges({ bucket, RANGE_CONCURRENCY: '3' });
+	});
+
+	it('aborts the body when the object changes between its parts', async () => {
+		await put_object('letters.txt', content);
+		let reads = 0;
+		let bucket = stub_bucket({
+			get: async (key: string, options?: R2GetOptions) => {
+				let object = await env.bucket.get(key, options);
+				if (reads++ === 0) {
+					await put_object('letters.txt', content.toUpperCase());
+				}
+				return object;
+			},
+		});
+		let response = await dav('GET', '/letters.txt', { headers: { Range: ranges }, vars: { bucket } });
+		expect(response.status).toBe(206);
+		await expect(response.text()).rejects.toThrow();
+	});
+});
+
+describe('ranged HEAD', () => {
+	it('reports the range with 206 but sends no body', async () => {
+		await put_object('digits.txt', CONTENT);
+		let response = await dav('HEAD', '/digits.txt', { headers: { Range: 'bytes=2-4' } });
+		expect(response.status).toBe(206);
+		expect(response.headers.get('Content-Range')).toBe('bytes 2-4/10');
+		expect(response.headers.get('Content-Length')).toBe('3');
+		expect(response.headers.get('Accept-Ranges')).toBe('bytes');
+		expect(await response.text()).toBe('');
+	});
+
+	it('refuses an unsatisfiable range with 416', async () => {
+		await put_object('digits.txt', CONTENT);
+		let response = await dav('HEAD', '/digits.txt', { headers: { Range: 'bytes=50-' } });
+		expect(response.status).toBe(416);
+		expect(response.headers.get('Content-Range')).toBe('bytes */10');
+	});
+});
@@ -0,0 +1,87 @@
+import { createExecutionContext, env, waitOnExecutionContext } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { RateLimit, count_request, peek_requests, request_kind } from '../src/ratelimit';
+import { dav, put_object } from './helpers';
+
+// Counts a request and waits for the count to be written
+async function count(identity: string, limit: RateLimit): Promise<number | null> {
+	let ctx = createExecutionContext();
+	let retry_after = await count_request(env.kv!, 'write', identity, limit, ctx);
+	await waitOnExecutionContext(ctx);
+	return retry_after;
+}
+
+describe('request_kind', () => {
+	it('tells reads from writes', () => {
+		expect(['GET', 'HEAD', 'OPTIONS', 'PROPFIND'].map(request_kind)).toEqual(['read', 'read', 'read', 'read']);
+		expect(['PUT', 'DELETE', 'MOVE', 'LOCK'].map(request_kind)).toEqual(['write', 'write', 'write', 'write']);
+	});
+});
+
+describe('count_request', () => {
+	const limit = { requests: 2, window: 3600 };
+
+	it('lets requests through up to the limit', async () => {
+		expect(await count('user:a', limit)).toBeNull();
+		expect(await count('user:a', limit)).toBeNull();
+		let retry_after = await count('user:a', limit);
+		expect(retry_after).toBeGreaterThan(0);
+		expect(retry_after).toBeLessThanOrEqual(limit.window);
+	});
+
+	it('counts every identity on its own', async () => {
+		await count('user:a', limit);
+		await count('user:a', limit);
+		expect(await count('user:b', limit)).toBeNull();
+	});
+
+	it('counts reads and writes apart', async () => {
+		await count('user:a', limit);
+		await count('user:a', limit);
+		let ctx = createExecutionContext();
+		expect(await count_request(env.kv!, 'read', 'user:a', limit, ctx)).toBeNull();
+		await waitOnExecutionContext(ctx);
+	});
+});
+
+describe('peek_requests', () => {
+	const limit = { requests: 1, window: 3600 };
+
+	it("doesn't count the request", async () => {
+		expect(await peek_requests(env.kv!, 'write', 'ip:192.0.2.1', limit)).toBeNull();
+		expect(await peek_requests(env.kv!, 'write', 'ip:192.0.2.1', limit)).toBeNull();
+		await count('ip:192.0.2.1', limit);
+		expect(await peek_requests(env.kv!, 'write', 'ip:192.0.2.1', limit)).toBeGreaterThan(0);
+	});
+});
+
+describe('RATE_LIMIT_WRITES', () => {
+	const vars = { kv: env.kv, RATE_LIMIT_WRITES: '2', RATE_LIMIT_WINDOW: '3600' };
+
+	it('refuses writes past the limit with 429 and Retry-After', async () => {
+		expect((await dav('PUT', '/a.txt', { body: 'a', vars })).status).toBe(201);
+		expect((await dav('PUT', '/a.txt', { body: 'b', vars })).status).toBe(204);
+		let response = await dav('PUT', '/a.txt', { body: 'c', vars });
+		expect(response.status).toBe(429);
+		expect(Number(response.headers.get('Retry-After'))).toBeGreaterThan(0);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('b');
+	});
+
+	it('leaves reads alone', async () => {
+		await put_object('a.txt', 'a');
+		for (let i = 0; i < 3; i++) {
+			expect((await dav('GET', '/a.txt', { vars })).status).toBe(200);
+		}
+	});
+
+	it('counts failed logins by client IP and then refuses it', async () => {
+		let headers = { 'CF-Connecting-IP': '192.0.2.1' };
+		for (let i = 0; i < 2; i++) {
+			expect((await dav('PUT', '/a.txt', { body: 'a', headers, vars, anonymous: true })).status).toBe(401);
+		}
+		expect((await dav('PUT', '/a.txt', { body: 'a', headers, vars, anonymous: true })).status).toBe(429);
+		// Even with the right credentials, it doesn't get to try them any more
+		expect((await dav('PUT', '/a.txt', { body: 'a', headers, vars })).status).toBe(429);
+		expect((await dav('PUT', '/a.txt', { body: 'a', vars })).status).toBe(201);
+	});
+});
@@ -0,0 +1,30 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, put_object } from './helpers';
+
+describe('READ_ONLY', () => {
+	const vars = { READ_ONLY: 'true' };
+
+	it('refuses a PUT with 405 without writing anything', async () => {
+		let response = await dav('PUT', '/a.txt', { body: 'a', vars });
+		expect(response.status).toBe(405);
+		expect(response.headers.get('Allow')).toBe('OPTIONS, PROPFIND, GET, HEAD');
+		expect(await env.bucket.head('a.txt')).toBeNull();
+	});
+
+	it('refuses every other write', async () => {
+		await put_object('a.txt', 'a');
+		for (let method of ['DELETE', 'MKCOL', 'PROPPATCH', 'COPY', 'MOVE', 'LOCK']) {
+			let response = await dav(method, '/a.txt', { headers: { Destination: '/b.txt' }, vars });
+			expect(response.status).toBe(405);
+		}
+		expect(await env.bucket.head('a.txt')).not.toBeNull();
+		expect(await env.bucket.head('b.txt')).toBeNull();
+	});
+
+	it('still serves reads', async () => {
+		await put_object('a.txt', 'a');
+		expect(await (await dav('GET', '/a.txt', { vars })).text()).toBe('a');
+		expect((await dav('PROPFIND', '/a.txt', { headers: { Depth: '0' }, vars })).status).toBe(207);
+	});
+});
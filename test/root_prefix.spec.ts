@@ -0,0 +1,35 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it } from 'vitest';
+import { dav, multistatus_hrefs, put_object } from './helpers';
+
+describe('ROOT_PREFIX', () => {
+	const vars = { ROOT_PREFIX: '/sites/www/' };
+
+	it('lists the folder as the root, with hrefs relative to it', async () => {
+		await put_object('sites/www/index.html', 'home');
+		await put_object('sites/www/css/site.css', 'css');
+		await put_object('sites/other/index.html', 'other');
+		await put_object('top.txt', 'top');
+
+		let response = await dav('PROPFIND', '/', { headers: { Depth: '1' }, vars });
+		expect(multistatus_hrefs(await response.text()).sort()).toEqual(['/', '/css/', '/index.html']);
+	});
+
+	it('serves the files of the folder by their relative path', async () => {
+		await put_object('sites/www/index.html', 'home');
+		await put_object('index.html', 'bucket root');
+		expect(await (await dav('GET', '/index.html', { vars })).text()).toBe('home');
+		expect((await dav('GET', '/sites/www/index.html', { vars })).status).toBe(404);
+	});
+
+	it('writes under the folder', async () => {
+		expect((await dav('PUT', '/a.txt', { body: 'a', vars })).status).toBe(201);
+		expect(await env.bucket.head('sites/www/a.txt')).not.toBeNull();
+		expect(await env.bucket.head('a.txt')).toBeNull();
+	});
+
+	it('changes nothing when empty', async () => {
+		await put_object('index.html', 'bucket root');
+		expect(await (await dav('GET', '/index.html', { vars: { ROOT_PREFIX: '' } })).text()).toBe('bucket root');
+	});
+});
@@ -0,0 +1,118 @@
+import { createExecutionContext, createScheduledController, env, waitOnExecutionContext } from 'cloudflare:test';
+import { describe, expect, it, vi } from 'vitest';
+import worker, { Env } from '../src/index';
+import { Upload } from '../src/upload';
+import { put_object, stub_bucket, test_env } from './helpers';
+
+// Runs the cron triggers once with the test bindings, the KV namespace included
+async function run_scheduled(vars: Partial<Env> = {}): Promise<void> {
+	let ctx = createExecutionContext();
+	await worker.scheduled(createScheduledController(), test_env({ kv: env.kv, ...vars }), ctx);
+	await waitOnExecutionContext(ctx);
+}
+
+// An upload of path that got its last chunk age seconds ago, kept in KV like put_upload does
+async function start_upload(path: string, age: number): Promise<string> {
+	let multipart = await env.bucket.createMultipartUpload(path);
+	let upload: Upload = { upload_id: multipart.uploadId, total: 10, part_size: 5, received: 5, parts: [] };
+	let metadata = { upload_id: multipart.uploadId, updated: Date.now() - age * 1000 };
+	await env.kv!.put(`upload:${path}`, JSON.stringify(upload), { metadata });
+	return multipart.uploadId;
+}
+
+describe('scheduled cleanup of uploads', () => {
+	it('aborts the uploads abandoned for longer than UPLOAD_MAX_AGE and forgets them', async () => {
+		let stale = await start_upload('stale.bin', 2 * 3600);
+		await start_upload('fresh.bin', 60);
+		let aborted: string[] = [];
+		let bucket = stub_bucket({
+			resumeMultipartUpload: (key: string, upload_id: string) => {
+				let upload = env.bucket.resumeMultipartUpload(key, upload_id);
+				let abort = async () => {
+					aborted.push(upload_id);
+					await upload.abort();
+				};
+				return { key, uploadId: upload_id, abort } as unknown as R2MultipartUpload;
+			},
+		});
+
+		await run_scheduled({ bucket, UPLOAD_MAX_AGE: '3600' });
+		expect(aborted).toEqual([stale]);
+		expect(await env.kv!.get('upload:stale.bin')).toBeNull();
+		expect(await env.kv!.get('upload:fresh.bin')).not.toBeNull();
+	});
+
+	it('forgets uploads R2 already aborted', async () => {
+		let stale = await start_upload('stale.bin', 2 * 3600);
+		await env.bucket.resumeMultipartUpload('stale.bin', stale).abort();
+		await run_scheduled({ UPLOAD_MAX_AGE: '3600' });
+		expect(await env.kv!.get('upload:stale.bin')).toBeNull();
+	});
+});
+
+describe('scheduled integrity audit', () => {
+	const sha256 = (text: string) => crypto.subtle.digest('SHA-256', new TextEncoder().encode(text));
+	// The messages logged as errors and warnings during fn, with the key they are about
+	async function logged(fn: () => Promise<void>): Promise<[string, string][]> {
+		let messages: [string, string][] = [];
+		let record = (line: string) => {
+			let { message, key } = JSON.parse(line);
+			messages.push([message, key]);
+		};
+		let error = vi.spyOn(console, 'error').mockImplementation(record);
+		let warn = vi.spyOn(console, 'warn').mockImplementation(record);
+		try {
+			await fn();
+		} finally {
+			error.mockRestore();
+			warn.mockRestore();
+		}
+		return messages;
+	}
+
+	it('flags an object whose content no longer matches its checksum', async () => {
+		await put_object('intact.txt', 'intact', { sha256: await sha256('intact') });
+		await put_object('corrupt.txt', 'stored', { sha256: await sha256('stored') });
+		// R2 itself never serves other bytes than it was given, the corruption is made up by the bucket
+		let bucket = stub_bucket({
+			get: async (key: string, options?: R2GetOptions) => {
+				let object = (await env.bucket.get(key, options))!;
+				if (key !== 'corrupt.txt') {
+					return object;
+				}
+				await object.body.cancel();
+				return { ...object, checksums: object.checksums, body: new Response('corrupted').body! } as R2ObjectBody;
+			},
+		});
+		let messages = await logged(() => run_scheduled({ bucket, INTEGRITY_AUDIT: 'true' }));
+		expect(messages).toEqual([['Checksum mismatch', 'corrupt.txt']]);
+	});
+
+	it('warns of objects without a checksum', async () => {
+		let upload = await env.bucket.createMultipartUpload('multipart.bin');
+		await upload.complete([await upload.uploadPart(1, 'part')]);
+		let messages = await logged(() => run_scheduled({ INTEGRITY_AUDIT: 'true' }));
+		expect(messages).toEqual([['Object without a checksum', 'multipart.bin']]);
+	});
+
+	it('continues where the last run stopped, and starts over once done', async () => {
+		// One more than a run checks
+		for (let i = 0; i <= 100; i++) {
+			await put_object(`${i}.txt`, String(i), { sha256: await sha256(String(i)) });
+		}
+		let gets: string[] = [];
+		let bucket = stub_bucket({
+			get: (key: string, options?: R2GetOptions) => {
+				gets.push(key);
+				return env.bucket.get(key, options);
+			},
+		});
+		await run_scheduled({ bucket, INTEGRITY_AUDIT: 'true' });
+		expect(gets).toHaveLength(100);
+		expect(await env.kv!.get('audit:cursor')).not.toBeNull();
+		await run_scheduled({ bucket, INTEGRITY_AUDIT: 'true' });
+		expect(gets).toHaveLength(101);
+		expect(new Set(gets).size).toBe(101);
+		expect(await env.kv!.get('audit:cursor')).toBeNull();
+	});
+});
@@ -0,0 +1,46 @@
+import { describe, expect, it } from 'vitest';
+import { dav, put_object } from './helpers';
+
+describe('SECURITY_HEADERS', () => {
+	const browser = { Accept: 'text/html,application/xhtml+xml,*/*;q=0.8' };
+
+	it('sends nosniff and a Content-Security-Policy with a browser GET', async () => {
+		await put_object('page.html', '<script>alert(1)</script>', { httpMetadata: { contentType: 'text/html' } });
+		let vars = { SECURITY_HEADERS: 'true', FRAME_OPTIONS: 'DENY' };
+		for (let path of ['/page.html', '/']) {
+			let response = await dav('GET', path, { headers: browser, vars });
+			expect(response.headers.get('X-Content-Type-Options')).toBe('nosniff');
+			expect(response.headers.get('Content-Security-Policy')).not.toBeNull();
+			expect(response.headers.get('X-Frame-Options')).toBe('DENY');
+		}
+	});
+
+	it('sends the CONTENT_SECURITY_POLICY configured', async () => {
+		await put_object('a.txt', 'a');
+		let vars = { SECURITY_HEADERS: 'true', CONTENT_SECURITY_POLICY: "default-src 'none'" };
+		let response = await dav('GET', '/a.txt', { headers: browser, vars });
+		expect(response.headers.get('Content-Security-Policy')).toBe("default-src 'none'");
+		expect(response.headers.get('X-Frame-Options')).toBeNull();
+	});
+
+	it('leaves WebDAV responses alone', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('PROPFIND', '/a.txt', { headers: { Depth: '0' }, vars: { SECURITY_HEADERS: 'true' } });
+		expect(response.headers.get('X-Content-Type-Options')).toBeNull();
+		expect(response.headers.get('Content-Security-Policy')).toBeNull();
+	});
+
+	it('sends them with the 401 of a browser GET without credentials', async () => {
+		let vars = { SECURITY_HEADERS: 'true' };
+		let response = await dav('GET', '/a.txt', { headers: browser, vars, anonymous: true });
+		expect(response.status).toBe(401);
+		expect(response.headers.get('X-Content-Type-Options')).toBe('nosniff');
+	});
+
+	it('sends none of them when off', async () => {
+		await put_object('a.txt', 'a');
+		let response = await dav('GET', '/a.txt', { headers: browser });
+		expect(response.headers.get('X-Content-Type-Options')).toBeNull();
+		expect(response.headers.get('Content-Security-Policy')).toBeNull();
+	});
+});
@@ -0,0 +1,47 @@
+import { describe, expect, it } from 'vitest';
+import { SSEC_HEADER, is_ssec_key } from '../src/bucket';
+import { dav } from './helpers';
+
+const KEY = '0123456789abcdef'.repeat(4);
+const OTHER_KEY = 'fedcba9876543210'.repeat(4);
+
+describe('is_ssec_key', () => {
+	it('takes 64 hex digits only', () => {
+		expect(is_ssec_key(KEY)).toBe(true);
+		expect(is_ssec_key(KEY.toUpperCase())).toBe(true);
+		expect(is_ssec_key(KEY.slice(1))).toBe(false);
+		expect(is_ssec_key('z'.repeat(64))).toBe(false);
+	});
+});
+
+describe('SSE-C', () => {
+	const put_encrypted = () => dav('PUT', '/secret.txt', { body: 'secret', headers: { [SSEC_HEADER]: KEY } });
+
+	it('reads an object back with the key it was written with', async () => {
+		expect((await put_encrypted()).status).toBe(201);
+		let response = await dav('GET', '/secret.txt', { headers: { [SSEC_HEADER]: KEY } });
+		expect(response.status).toBe(200);
+		expect(await response.text()).toBe('secret');
+	});
+
+	it("can't read it without the key, or with another one", async () => {
+		await put_encrypted();
+		let response = await dav('GET', '/secret.txt');
+		expect(response.status).toBe(403);
+		expect(await response.text()).not.toContain('secret');
+		expect((await dav('GET', '/secret.txt', { headers: { [SSEC_HEADER]: OTHER_KEY } })).status).toBe(403);
+	});
+
+	it('uses SSEC_KEY when the client sends no key', async () => {
+		let vars = { SSEC_KEY: KEY };
+		await dav('PUT', '/secret.txt', { body: 'secret', vars });
+		expect(await (await dav('GET', '/secret.txt', { vars })).text()).toBe('secret');
+		expect((await dav('GET', '/secret.txt')).status).toBe(403);
+	});
+
+	it('refuses a key that is no key with 400', async () => {
+		let response = await dav('PUT', '/secret.txt', { body: 'secret', headers: { [SSEC_HEADER]: 'password' } });
+		expect(response.status).toBe(400);
+		expect(await response.text()).not.toContain('password');
+	});
+});
@@ -0,0 +1,122 @@
+import { env } from 'cloudflare:test';
+import { describe, expect, it, vi } from 'vitest';
+import { dav, lock, put_object } from './helpers';
+
+const WEBHOOK_URL = 'https://hooks.example.com/r2';
+const vars = { WEBHOOK_URL, WEBHOOK_SECRET: 'secret' };
+
+// The requests the worker sent during fn, answered by respond in place of the receiver
+async function deliveries(fn: () => Promise<void>, respond = () => new Response(null, { status: 204 })) {
+	let requests: Request[] = [];
+	let fetch = vi.spyOn(globalThis, 'fetch').mockImplementation(async (input, init) => {
+		requests.push(new Request(input, init));
+		return respond();
+	});
+	try {
+		await fn();
+	} finally {
+		fetch.mockRestore();
+	}
+	return requests;
+}
+
+async function hmac(secret: string, body: string): Promise<string> {
+	let encoder = new TextEncoder();
+	let raw = encoder.encode(secret);
+	let key = await crypto.subtle.importKey('raw', raw, { name: 'HMAC', hash: 'SHA-256' }, false, ['sign']);
+	let signature = await crypto.subtle.sign('HMAC', key, encoder.encode(body));
+	return [...new Uint8Array(signature)].map((byte) => byte.toString(16).padStart(2, '0')).join('');
+}
+
+describe('WEBHOOK_URL', () => {
+	it('POSTs a signed event for a file written by PUT', async () => {
+		let requests = await deliveries(async () => {
+			expect((await dav('PUT', '/a.txt', { body: 'abc', vars })).status).toBe(201);
+		});
+		expect(requests).toHaveLength(1);
+		let [request] = requests;
+		expect(request.url).toBe(WEBHOOK_URL);
+		expect(request.method).toBe('POST');
+		let body = await request.text();
+		expect(request.headers.get('X-Webhook-Signature')).toBe(`sha256=${await hmac('secret', body)}`);
+
+		let { events } = JSON.parse(body);
+		let { httpEtag } = (await env.bucket.head('a.txt'))!;
+		let timestamp = expect.any(String);
+		expect(events).toEqual([{ event: 'created', bucket: 'bucket', path: 'a.txt', size: 3, etag: httpEtag, timestamp }]);
+	});
+
+	it('POSTs an event for a file deleted', async () => {
+		await put_object('a.txt', 'a');
+		let requests = await deliveries(async () => {
+			expect((await dav('DELETE', '/a.txt', { vars })).status).toBe(204);
+		});
+		let { events } = await requests[0].json<{ events: unknown[] }>();
+		let timestamp = expect.any(String);
+		expect(events).toEqual([{ event: 'deleted', bucket: 'bucket', path: 'a.txt', size: null, etag: null, timestamp }]);
+	});
+
+	it("doesn't fail the request when the delivery fails", async () => {
+		let requests = await deliveries(
+			async () => {
+				expect((await dav('PUT', '/a.txt', { body: 'a', vars })).status).toBe(201);
+			},
+			() => new Response(null, { status: 500 }),
+		);
+		expect(requests).toHaveLength(1);
+		expect(await (await env.bucket.get('a.txt'))?.text()).toBe('a');
+	});
+
+	it('sends nothing for reads', async () => {
+		await put_object('a.txt', 'a');
+		let requests = await deliveries(async () => {
+			expect((await dav('GET', '/a.txt', { vars })).status).toBe(200);
+		});
+		expect(requests).toEqual([]);
+	});
+
+	it('sends nothing for writes of properties or the empty file of a LOCK', async () => {
+		await put_object('a.txt', 'a');
+		let requests = await deliveries(async () => {
+			let body = `<D:propertyupdate xmlns:D="DAV:" xmlns:E="urn:example">
+				<D:set><D:prop><E:color>blue</E:color></D:prop></D:set>
+			</D:propertyupdate>`;
+			expect((await dav('PROPPATCH', '/a.txt', { body, vars })).status).toBe(207);
+			await lock('/b.txt', { vars });
+		});
+		expect(requests).toEqual([]);
+		expect(await env.bucket.head('b.txt')).not.toBeNull();
+	});
+
+	it('POSTs the events of writes to the secondary bucket', async () => {
+		await put_object('a.txt', 'a');
+		let requests = await deliveries(async () => {
+			let headers = { Destination: '/production/b.txt' };
+			let response = await dav('COPY', '/a.txt', { headers, vars: { ...vars, SECONDARY_PREFIX: 'production' } });
+			expect(response.status).toBe(201);
+		});
+		let { events } = await requests[0].json<{ events: { event: string; bucket: string; path: string }[] }>();
+		expect(events.map(({ event, bucket, path }) => [event, bucket, path])).toEqual([
+			['created', 'secondary_bucket', 'b.txt'],
+		]);
+	});
+
+	it('POSTs the events of a request together, in the order they happened', async () => {
+		await put_object('a.txt', 'a');
+		let requests = await deliveries(async () => {
+			expect((await dav('MOVE', '/a.txt', { headers: { Destination: '/b.txt' }, vars })).status).toBe(201);
+		});
+		expect(requests).toHaveLength(1);
+		let { events } = await requests[0].json<{ events: { event: string; path: string }[] }>();
+		expect(events.map(({ event, path }) => [event, path])).toEqual([
+			['created', 'b.txt'],
+			['deleted', 'a.txt'],
+		]);
+	});
+
+	it('only refuses writes without WEBHOOK_SECRET', async () => {
+		await put_object('a.txt', 'a');
+		expect((await dav('GET', '/a.txt', { vars: { WEBHOOK_URL } })).status).toBe(200);
+		expect((await dav('PUT', '/a.txt', { body: 'b', vars: { WEBHOOK_URL } })).status).toBe(500);
+	});
+});
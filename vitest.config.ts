@@ -0,0 +1,17 @@
+import { defineWorkersConfig } from '@cloudflare/vitest-pool-workers/config';
+
+export default defineWorkersConfig({
+	test: {
+		poolOptions: {
+			workers: {
+				wrangler: { configPath: './wrangler.toml' },
+				miniflare: {
+					compatibilityFlags: ['nodejs_compat'],
+					// The optional bindings of wrangler.toml as well, every test gets them empty
+					r2Buckets: ['bucket', 'secondary_bucket'],
+					kvNamespaces: ['kv'],
+				},
+			},
+		},
+	},
+});